@@ -0,0 +1,509 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{stdout, BufWriter, Write};
+
+use clap::{App, Arg};
+use serde_json::{json, Value};
+
+use constdb::{Bytes, CstError};
+use constdb::lib::utils::glob_match;
+use constdb::object::{Encoding, Object};
+use constdb::snapshot::{SnapshotEntry, SnapshotLoader};
+
+type FileLoader = SnapshotLoader<tokio::io::BufReader<tokio::fs::File>>;
+
+// the members or fields in a single SADD or HSET exported, as many as in an aof rewritten by redis
+const EXPORT_ITEMS_PER_CMD: usize = 64;
+
+// inspect a snapshot offline: print its header and the metadata of the node which dumped it,
+// dump the entries as json lines, and verify the checksum at the end.
+// with --diff, compare it with the snapshot of another node and print the differences instead.
+// with --resp, export the live dataset as commands that can be piped into redis or another cluster.
+pub fn main() {
+    let matches = App::new("ConstDB-snapshot")
+        .version("1.1.0")
+        .author("TanCehao tancehao93@163.com")
+        .about("inspect the snapshots of constdb")
+        .arg(Arg::with_name("file")
+            .help("the snapshot file")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("match")
+            .short("m")
+            .long("match")
+            .help("only dump the keys matching the glob-style pattern, e.g. user:*")
+            .takes_value(true))
+        .arg(Arg::with_name("verify")
+            .short("v")
+            .long("verify")
+            .help("only verify the checksum and print the header and the summary"))
+        .arg(Arg::with_name("diff")
+            .short("d")
+            .long("diff")
+            .help("compare with the snapshot of another node, exits with 1 if they differ")
+            .takes_value(true))
+        .arg(Arg::with_name("resp")
+            .short("r")
+            .long("resp")
+            .help("export the live keys as SET, HSET, SADD, INCRBY and PEXPIREAT commands in RESP")
+            .conflicts_with_all(&["verify", "diff"]))
+        .get_matches();
+    let file_name = matches.value_of("file").unwrap().to_string();
+    let pattern = matches.value_of("match").map(|x| x.as_bytes().to_vec());
+    let verify_only = matches.is_present("verify");
+    let other = matches.value_of("diff").map(|x| x.to_string());
+    let resp = matches.is_present("resp");
+
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async move {
+        let mut output = BufWriter::new(stdout());
+        let mut loader = open_loader(&file_name).await;
+        if resp {
+            let r = export(&mut loader, &mut output, pattern.as_deref()).await;
+            let _ = output.flush();
+            match r {
+                Ok((keys, expires)) => eprintln!("exported {} keys and {} expires", keys, expires),
+                Err(e) => {
+                    eprintln!("the snapshot {} is corrupted at offset {}: {}", file_name, loader.total_read(), e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        let other = match other {
+            None => {
+                let r = inspect(&mut loader, &mut output, pattern.as_deref(), verify_only).await;
+                let _ = output.flush();
+                if let Err(e) = r {
+                    eprintln!("the snapshot {} is corrupted at offset {}: {}", file_name, loader.total_read(), e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+            Some(other) => other,
+        };
+        let mut left = match load_all(&mut loader, pattern.as_deref()).await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("the snapshot {} is corrupted at offset {}: {}", file_name, loader.total_read(), e);
+                std::process::exit(2);
+            }
+        };
+        let mut right = open_loader(&other).await;
+        let mut compared = match diff(&mut left, &mut right, &mut output, pattern.as_deref()).await {
+            Ok(c) => c,
+            Err(e) => {
+                let _ = output.flush();
+                eprintln!("the snapshot {} is corrupted at offset {}: {}", other, right.total_read(), e);
+                std::process::exit(2);
+            }
+        };
+        // the objects of the left side are read again for the keys to print
+        let mut loader = open_loader(&file_name).await;
+        let r = finish_diff(left, &mut compared, &mut loader, &mut output).await;
+        let _ = output.flush();
+        match r {
+            Ok(0) => {}
+            Ok(_) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("the snapshot {} is corrupted at offset {}: {}", file_name, loader.total_read(), e);
+                std::process::exit(2);
+            }
+        }
+    });
+}
+
+async fn open_loader(file_name: &str) -> FileLoader {
+    match tokio::fs::File::open(file_name).await {
+        Ok(f) => SnapshotLoader::new(tokio::io::BufReader::new(f)),
+        Err(e) => {
+            eprintln!("unable to open {}: {}", file_name, e);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    keys: u64,
+    expires: u64,
+    deletes: u64,
+    shadows: u64,
+    replicas_added: u64,
+    replicas_removed: u64,
+}
+
+async fn inspect<T, W>(loader: &mut SnapshotLoader<T>, w: &mut W, pattern: Option<&[u8]>, verify_only: bool) -> Result<(), CstError>
+where
+    T: tokio::io::AsyncRead + Unpin,
+    W: Write,
+{
+    let mut summary = Summary::default();
+    let matched = |k: &[u8]| pattern.map(|p| glob_match(p, k)).unwrap_or(true);
+    while let Some(entry) = loader.next().await? {
+        let line = match entry {
+            SnapshotEntry::Version(v) => Some(json!({
+                "type": "version",
+                "version": v.to_string(),
+                "format": loader.format(),
+                "compression": loader.compression().name(),
+            })),
+            SnapshotEntry::Node(id, alias, addr, uuid) => Some(json!({
+                "type": "node",
+                "node_id": id,
+                "alias": alias,
+                "addr": addr,
+                "uuid": uuid,
+            })),
+            SnapshotEntry::ReplicaAdd(add_time, id, alias, addr, uuid) => {
+                summary.replicas_added += 1;
+                Some(json!({
+                    "type": "replica_add",
+                    "add_time": add_time,
+                    "node_id": id,
+                    "alias": alias,
+                    "addr": addr,
+                    "uuid_he_sent": uuid,
+                }))
+            }
+            SnapshotEntry::ReplicaDel(addr, t) => {
+                summary.replicas_removed += 1;
+                Some(json!({"type": "replica_del", "addr": addr, "del_time": t}))
+            }
+            SnapshotEntry::Data(k, v) => {
+                summary.keys += 1;
+                if verify_only || !matched(k.as_bytes()) {
+                    None
+                } else {
+                    Some(json!({"type": "data", "key": k.to_string(), "object": v.to_json()}))
+                }
+            }
+            SnapshotEntry::Shadow(k, v) => {
+                summary.shadows += 1;
+                if verify_only || !matched(k.as_bytes()) {
+                    None
+                } else {
+                    Some(json!({"type": "shadow", "key": k.to_string(), "object": v.to_json()}))
+                }
+            }
+            SnapshotEntry::Expires(k, t) => {
+                summary.expires += 1;
+                if verify_only || !matched(k.as_bytes()) {
+                    None
+                } else {
+                    Some(json!({"type": "expire", "key": k.to_string(), "expire_at": t}))
+                }
+            }
+            SnapshotEntry::Versions(id, uuid) => Some(json!({"type": "versions", "node_id": id, "uuid": uuid})),
+            SnapshotEntry::Deletes(k, t) => {
+                summary.deletes += 1;
+                if verify_only || !matched(k.as_bytes()) {
+                    None
+                } else {
+                    Some(json!({"type": "delete", "key": k.to_string(), "del_time": t}))
+                }
+            }
+        };
+        if let Some(line) = line {
+            writeln!(w, "{}", line)?;
+        }
+    }
+    // the loader has verified the checksum once it returns None
+    writeln!(w, "{}", json!({
+        "type": "summary",
+        "keys": summary.keys,
+        "expires": summary.expires,
+        "deletes": summary.deletes,
+        "shadows": summary.shadows,
+        "replicas_added": summary.replicas_added,
+        "replicas_removed": summary.replicas_removed,
+        "bytes": loader.total_read(),
+        "checksum": "ok",
+    }))?;
+    Ok(())
+}
+
+// write the live keys as commands, the tombstones are left out. the expires may come before the keys,
+// as in the incremental dumps, so they are kept until all the keys are exported, and written for the
+// keys exported. returns the number of keys and expires.
+async fn export<T, W>(loader: &mut SnapshotLoader<T>, w: &mut W, pattern: Option<&[u8]>) -> Result<(u64, u64), CstError>
+where
+    T: tokio::io::AsyncRead + Unpin,
+    W: Write,
+{
+    let matched = |k: &[u8]| pattern.map(|p| glob_match(p, k)).unwrap_or(true);
+    let mut exported = HashSet::new();
+    let mut expires = vec![];
+    while let Some(entry) = loader.next().await? {
+        match entry {
+            SnapshotEntry::Data(k, o) => {
+                if !o.alive() || !matched(k.as_bytes()) {
+                    continue;
+                }
+                if export_object(w, &k, &o)? {
+                    exported.insert(k);
+                }
+            }
+            SnapshotEntry::Expires(k, t) => {
+                if matched(k.as_bytes()) {
+                    expires.push((k, t));
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut exported_expires = 0;
+    for (k, t) in expires.iter().filter(|(k, _)| exported.contains(k)) {
+        // the expire time is a uuid, whose high bits are the milliseconds
+        let at = (t >> 22).to_string();
+        write_resp(w, &[b"PEXPIREAT", k.as_bytes(), at.as_bytes()])?;
+        exported_expires += 1;
+    }
+    Ok((exported.len() as u64, exported_expires))
+}
+
+// returns false if nothing was written as the set or dict is empty
+fn export_object<W: Write>(w: &mut W, key: &Bytes, o: &Object) -> Result<bool, CstError> {
+    let k = key.as_bytes();
+    match &o.enc {
+        Encoding::Bytes(b) => write_resp(w, &[b"SET", k, b.as_bytes()])?,
+        Encoding::Counter(c) => write_resp(w, &[b"INCRBY", k, c.get().to_string().as_bytes()])?,
+        Encoding::LWWSet(s) => {
+            let members: Vec<&Bytes> = s.iter().map(|(m, _)| m).collect();
+            for chunk in members.chunks(EXPORT_ITEMS_PER_CMD) {
+                let mut args: Vec<&[u8]> = vec![b"SADD", k];
+                args.extend(chunk.iter().map(|m| m.as_bytes()));
+                write_resp(w, &args)?;
+            }
+            return Ok(!members.is_empty());
+        }
+        Encoding::LWWDict(d) => {
+            let kvs: Vec<(&Bytes, &Bytes)> = d.iter().map(|(f, (_, v))| (f, v)).collect();
+            for chunk in kvs.chunks(EXPORT_ITEMS_PER_CMD) {
+                let mut args: Vec<&[u8]> = vec![b"HSET", k];
+                for (f, v) in chunk {
+                    args.push(f.as_bytes());
+                    args.push(v.as_bytes());
+                }
+                write_resp(w, &args)?;
+            }
+            return Ok(!kvs.is_empty());
+        }
+    }
+    Ok(true)
+}
+
+fn write_resp<W: Write>(w: &mut W, args: &[&[u8]]) -> Result<(), CstError> {
+    write!(w, "*{}\r\n", args.len())?;
+    for a in args {
+        write!(w, "${}\r\n", a.len())?;
+        w.write_all(a)?;
+        w.write_all(b"\r\n")?;
+    }
+    Ok(())
+}
+
+// everything in a snapshot except the objects, which are kept as the hashes of their json, so that
+// the snapshot of a big dataset fits in memory. the data of the other side is compared with them.
+#[derive(Default)]
+struct Loaded {
+    header: Vec<Value>,
+    data: HashMap<Bytes, u64>,
+    expires: BTreeMap<String, u64>,
+    deletes: BTreeMap<String, u64>,
+}
+
+async fn load_all(loader: &mut FileLoader, pattern: Option<&[u8]>) -> Result<Loaded, CstError> {
+    let mut loaded = Loaded::default();
+    while let Some(entry) = loader.next().await? {
+        match entry {
+            SnapshotEntry::Data(k, v) => {
+                if pattern.map(|p| glob_match(p, k.as_bytes())).unwrap_or(true) {
+                    loaded.data.insert(k, json_hash(&v.to_json()));
+                }
+            }
+            others => loaded.add(others, pattern),
+        }
+    }
+    Ok(loaded)
+}
+
+impl Loaded {
+    // the entries except the data
+    fn add(&mut self, entry: SnapshotEntry, pattern: Option<&[u8]>) {
+        let matched = |k: &Bytes| pattern.map(|p| glob_match(p, k.as_bytes())).unwrap_or(true);
+        match entry {
+            SnapshotEntry::Version(v) => self.header.push(json!({"version": v.to_string()})),
+            SnapshotEntry::Node(id, alias, addr, uuid) => {
+                self.header.push(json!({"node_id": id, "alias": alias, "addr": addr, "uuid": uuid}))
+            }
+            SnapshotEntry::Expires(k, t) => {
+                if matched(&k) {
+                    self.expires.insert(k.to_string(), t);
+                }
+            }
+            SnapshotEntry::Deletes(k, t) => {
+                if matched(&k) {
+                    self.deletes.insert(k.to_string(), t);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn json_hash(v: &Value) -> u64 {
+    let mut h = DefaultHasher::new();
+    v.to_string().hash(&mut h);
+    h.finish()
+}
+
+// the result of comparing the data of the right side with the left one
+#[derive(Default)]
+struct Compared {
+    rest: Loaded,
+    keys: u64,
+    diffs: u64,
+    // the right objects of the keys differing, which are printed with the left ones
+    differ: HashMap<Bytes, Value>,
+}
+
+// print the keys existing only on the right side, and collect the keys whose crdt states differ.
+async fn diff<W: Write>(left: &mut Loaded, right: &mut FileLoader, w: &mut W, pattern: Option<&[u8]>) -> Result<Compared, CstError> {
+    let mut compared = Compared::default();
+    while let Some(entry) = right.next().await? {
+        let (k, v) = match entry {
+            SnapshotEntry::Data(k, v) => (k, v),
+            others => {
+                compared.rest.add(others, pattern);
+                continue;
+            }
+        };
+        if !pattern.map(|p| glob_match(p, k.as_bytes())).unwrap_or(true) {
+            continue;
+        }
+        compared.keys += 1;
+        let r = v.to_json();
+        match left.data.remove(&k) {
+            None => {
+                compared.diffs += 1;
+                writeln!(w, "{}", json!({"type": "only_right", "key": k.to_string(), "object": r}))?;
+            }
+            Some(l) if l != json_hash(&r) => {
+                compared.differ.insert(k, r);
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(compared)
+}
+
+// read the left side again to print the keys whose crdt states differ and the keys existing only
+// on it, then the differing expires and tombstones. returns the number of differences.
+async fn finish_diff<W: Write>(left: Loaded, compared: &mut Compared, loader: &mut FileLoader, w: &mut W) -> Result<u64, CstError> {
+    let mut diffs = compared.diffs;
+    while let Some(entry) = loader.next().await? {
+        let (k, v) = match entry {
+            SnapshotEntry::Data(k, v) => (k, v),
+            _ => continue,
+        };
+        if let Some(r) = compared.differ.remove(&k) {
+            let l = v.to_json();
+            // the hashes could collide
+            if l != r {
+                diffs += 1;
+                writeln!(w, "{}", json!({"type": "differ", "key": k.to_string(), "differences": diff_objects(&l, &r)}))?;
+            }
+        } else if left.data.contains_key(&k) {
+            diffs += 1;
+            writeln!(w, "{}", json!({"type": "only_left", "key": k.to_string(), "object": v.to_json()}))?;
+        }
+    }
+    let right_rest = &compared.rest;
+    for (name, l, r) in [("expire", &left.expires, &right_rest.expires), ("delete", &left.deletes, &right_rest.deletes)].iter() {
+        for (k, l, r) in diff_maps(l, r) {
+            diffs += 1;
+            writeln!(w, "{}", json!({"type": format!("{}_differ", name), "key": k, "left": l, "right": r}))?;
+        }
+    }
+    writeln!(w, "{}", json!({
+        "type": "summary",
+        "left": left.header,
+        "right": right_rest.header,
+        "keys_compared": compared.keys,
+        "differences": diffs,
+    }))?;
+    Ok(diffs)
+}
+
+fn diff_maps<'a>(l: &'a BTreeMap<String, u64>, r: &'a BTreeMap<String, u64>) -> Vec<(&'a String, Option<u64>, Option<u64>)> {
+    let mut diffs = vec![];
+    for (k, lt) in l.iter() {
+        match r.get(k) {
+            Some(rt) if rt == lt => {}
+            rt => diffs.push((k, Some(*lt), rt.copied())),
+        }
+    }
+    for (k, rt) in r.iter() {
+        if !l.contains_key(k) {
+            diffs.push((k, None, Some(*rt)));
+        }
+    }
+    diffs
+}
+
+// the differing parts of two objects in json: the differing timestamps or types are shown as
+// [left, right], and the elements of the crdt internals are matched by their identities, such as
+// the node of a counter or the member of a set, only those differ are shown.
+fn diff_objects(l: &Value, r: &Value) -> Value {
+    let mut diffs = serde_json::Map::new();
+    for field in ["type", "create_time", "update_time", "delete_time"].iter() {
+        if l[field] != r[field] {
+            diffs.insert(field.to_string(), json!([l[field], r[field]]));
+        }
+    }
+    if l["type"] != r["type"] {
+        diffs.insert("value".to_string(), json!([l["value"], r["value"]]));
+        return Value::Object(diffs);
+    }
+    let (lv, rv) = (&l["value"], &r["value"]);
+    for (field, id) in [("nodes", "node"), ("members", "member"), ("fields", "field"), ("tombstones", "key")].iter() {
+        if let (Some(la), Some(ra)) = (lv[field].as_array(), rv[field].as_array()) {
+            let d = diff_elements(la, ra, id);
+            if !d.is_empty() {
+                diffs.insert(field.to_string(), Value::Array(d));
+            }
+        }
+    }
+    for field in ["sum"].iter() {
+        if lv[field] != rv[field] {
+            diffs.insert(field.to_string(), json!([lv[field], rv[field]]));
+        }
+    }
+    if lv.is_string() && lv != rv {
+        diffs.insert("value".to_string(), json!([lv, rv]));
+    }
+    Value::Object(diffs)
+}
+
+// the elements are matched by the json of their identities in one pass over each side
+fn diff_elements(l: &[Value], r: &[Value], id: &str) -> Vec<Value> {
+    let mut diffs = vec![];
+    let rs: HashMap<String, &Value> = r.iter().map(|re| (re[id].to_string(), re)).collect();
+    let mut ls = HashSet::with_capacity(l.len());
+    for le in l.iter() {
+        let lid = le[id].to_string();
+        match rs.get(&lid) {
+            Some(re) if *re == le => {}
+            re => diffs.push(json!({id: le[id], "left": le, "right": re})),
+        }
+        ls.insert(lid);
+    }
+    for re in r.iter() {
+        if !ls.contains(&re[id].to_string()) {
+            diffs.push(json!({id: re[id], "left": Value::Null, "right": re}));
+        }
+    }
+    diffs
+}
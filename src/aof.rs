@@ -0,0 +1,240 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+use crate::conf::AppendFsync;
+use crate::CstError;
+use crate::replica::backlog::{decode_command, encode_bytes, encode_command};
+use crate::resp::Message;
+
+const ENTRY_HEADER_SIZE: usize = 20; // uuid(8) + nodeid(8) + body length(4)
+
+// a write command that was executed, either by our clients or by a replica at `addr`.
+// `addr` is empty for those executed by our clients.
+#[derive(Debug, Clone)]
+pub struct AofEntry {
+    pub uuid: u64,
+    pub nodeid: u64,
+    pub addr: String,
+    pub cmd_name: &'static str,
+    pub args: Vec<Message>,
+}
+
+// The append only file. Every time a snapshot is dumped in a child process, the file is rotated to
+// `<file_name>.<seq>`, and the rotated ones are removed once the snapshot is dumped successfully.
+// Replaying a command more than once is harmless as all our datatypes are CRDTs, so the rotated
+// files and the current one are all replayed after loading the snapshot, no matter which of them
+// the snapshot contains already.
+pub struct Aof {
+    file_name: String,
+    fsync: AppendFsync,
+    writer: BufWriter<File>,
+    size: u64,
+    next_seq: u64,
+    unsynced: bool,
+}
+
+impl Aof {
+    pub fn open(file_name: &str, fsync: AppendFsync) -> Result<Self, CstError> {
+        let file = OpenOptions::new().create(true).append(true).open(file_name)?;
+        let size = file.metadata()?.len();
+        let next_seq = Self::rotated_files(file_name)?.last().map(|(seq, _)| *seq + 1).unwrap_or(1);
+        Ok(Aof{
+            file_name: file_name.to_string(),
+            fsync,
+            writer: BufWriter::new(file),
+            size,
+            next_seq,
+            unsynced: false,
+        })
+    }
+
+    pub fn append(&mut self, nodeid: u64, uuid: u64, addr: &str, cmd_name: &str, args: &[Message]) -> Result<(), CstError> {
+        let mut body = Vec::with_capacity(64);
+        encode_bytes(&mut body, addr.as_bytes());
+        encode_command(&mut body, cmd_name, args);
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_SIZE + body.len());
+        entry.extend_from_slice(&uuid.to_le_bytes());
+        entry.extend_from_slice(&nodeid.to_le_bytes());
+        entry.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&body);
+        self.writer.write_all(&entry)?;
+        self.size += entry.len() as u64;
+        self.unsynced = true;
+        if self.fsync == AppendFsync::Always {
+            self.writer.flush()?;
+            self.writer.get_ref().sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    // called by the cron of server. the buffered entries are written into the file every time,
+    // and the file is synced every second if the policy is `everysec`.
+    pub fn cron(&mut self, every_second: bool) -> Result<(), CstError> {
+        if !self.unsynced {
+            return Ok(());
+        }
+        self.writer.flush()?;
+        if self.fsync == AppendFsync::EverySec && every_second {
+            // the main thread must not be blocked by fsync
+            let file = self.writer.get_ref().try_clone()?;
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = file.sync_data() {
+                    error!("Failed to sync the aof because {}", e);
+                }
+            });
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // move the current file aside and start a new one, returns the sequence of the rotated file.
+    pub fn rotate(&mut self) -> Result<u64, CstError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        let seq = self.next_seq;
+        std::fs::rename(&self.file_name, format!("{}.{}", self.file_name, seq))?;
+        let file = OpenOptions::new().create(true).append(true).open(&self.file_name)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        self.next_seq += 1;
+        self.unsynced = false;
+        Ok(seq)
+    }
+
+    // remove the rotated files up to seq, as they are contained in a snapshot now.
+    pub fn remove_rotated(&mut self, seq: u64) -> Result<(), CstError> {
+        for (s, path) in Self::rotated_files(&self.file_name)? {
+            if s <= seq {
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // the rotated files and their sequences, in the order they were written
+    fn rotated_files(file_name: &str) -> Result<Vec<(u64, String)>, CstError> {
+        let path = std::path::Path::new(file_name);
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d.to_path_buf(),
+            _ => std::path::PathBuf::from("."),
+        };
+        let prefix = format!("{}.", path.file_name().and_then(|x| x.to_str()).unwrap_or_default());
+        let mut files = vec![];
+        for entry in std::fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            let name = match name.to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            if let Some(seq) = name.strip_prefix(&prefix).and_then(|x| x.parse::<u64>().ok()) {
+                files.push((seq, format!("{}.{}", file_name, seq)));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    // the files to replay at startup, the rotated ones come first.
+    pub fn files(file_name: &str) -> Result<Vec<String>, CstError> {
+        let mut files: Vec<String> = Self::rotated_files(file_name)?.into_iter().map(|(_, f)| f).collect();
+        files.push(file_name.to_string());
+        Ok(files)
+    }
+
+    // read all the entries in the file. a partially written entry at the tail is truncated.
+    pub fn replay<F: FnMut(AofEntry)>(file_name: &str, mut f: F) -> Result<u64, CstError> {
+        let file = match OpenOptions::new().read(true).write(true).open(file_name) {
+            Ok(f) => f,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let total = file.metadata()?.len();
+        let mut reader = BufReader::new(&file);
+        let mut offset = 0u64;
+        let mut cnt = 0;
+        let mut header = [0u8; ENTRY_HEADER_SIZE];
+        while offset + ENTRY_HEADER_SIZE as u64 <= total {
+            reader.read_exact(&mut header)?;
+            let uuid = u64::from_le_bytes([header[0], header[1], header[2], header[3], header[4], header[5], header[6], header[7]]);
+            let nodeid = u64::from_le_bytes([header[8], header[9], header[10], header[11], header[12], header[13], header[14], header[15]]);
+            let body_size = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as u64;
+            if offset + ENTRY_HEADER_SIZE as u64 + body_size > total {
+                break;
+            }
+            let mut body = vec![0u8; body_size as usize];
+            reader.read_exact(&mut body)?;
+            if body.len() < 4 {
+                return Err(CstError::InvalidAof(offset));
+            }
+            let addr_len = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as usize;
+            if body.len() < 4 + addr_len {
+                return Err(CstError::InvalidAof(offset));
+            }
+            let addr = String::from_utf8(body[4..4 + addr_len].to_vec()).map_err(|_| CstError::InvalidAof(offset))?;
+            let (cmd_name, args) = decode_command(&body[4 + addr_len..], offset).map_err(|_| CstError::InvalidAof(offset))?;
+            f(AofEntry{uuid, nodeid, addr, cmd_name, args});
+            offset += ENTRY_HEADER_SIZE as u64 + body_size;
+            cnt += 1;
+        }
+        if offset < total {
+            warn!("Truncating the aof {} from {} to {} bytes", file_name, total, offset);
+            file.set_len(offset)?;
+        }
+        Ok(cnt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::aof::Aof;
+    use crate::conf::AppendFsync;
+    use crate::resp::Message;
+
+    #[test]
+    fn test_aof() {
+        let dir = std::env::temp_dir().join(format!("constdb_aof_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file_name = dir.join("appendonly.aof").to_str().unwrap().to_string();
+        let mut aof = Aof::open(&file_name, AppendFsync::Always).unwrap();
+        aof.append(1, 100, "", "set", &[Message::BulkString("k".into()), Message::BulkString("v".into())]).unwrap();
+        let seq = aof.rotate().unwrap();
+        aof.append(2, 200, "127.0.0.1:9002", "incr", &[Message::BulkString("c".into())]).unwrap();
+        aof.append(2, 300, "127.0.0.1:9002", "sadd", &[Message::BulkString("s".into()), Message::BulkString("m".into())]).unwrap();
+        drop(aof);
+        // a partially written entry
+        std::fs::OpenOptions::new().append(true).open(&file_name).unwrap().write_all(&[1, 2, 3]).unwrap();
+
+        let files = Aof::files(&file_name).unwrap();
+        assert_eq!(files, vec![format!("{}.{}", file_name, seq), file_name.clone()]);
+        let mut entries = vec![];
+        for f in files.iter() {
+            Aof::replay(f, |e| entries.push(e)).unwrap();
+        }
+        let uuids: Vec<u64> = entries.iter().map(|e| e.uuid).collect();
+        assert_eq!(uuids, vec![100, 200, 300]);
+        assert_eq!(entries[0].addr, "");
+        assert_eq!(entries[0].cmd_name, "set");
+        assert_eq!(entries[1].nodeid, 2);
+        assert_eq!(entries[1].addr, "127.0.0.1:9002");
+        assert_eq!(entries[2].cmd_name, "sadd");
+        assert_eq!(entries[2].args.len(), 2);
+
+        // the junk at the tail was truncated, new entries follow the good ones
+        let mut aof = Aof::open(&file_name, AppendFsync::No).unwrap();
+        aof.append(1, 400, "", "set", &[Message::BulkString("k".into()), Message::BulkString("v2".into())]).unwrap();
+        aof.remove_rotated(seq).unwrap();
+        drop(aof);
+        assert_eq!(Aof::files(&file_name).unwrap(), vec![file_name.clone()]);
+        let mut uuids = vec![];
+        Aof::replay(&file_name, |e| uuids.push(e.uuid)).unwrap();
+        assert_eq!(uuids, vec![200, 300, 400]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,148 @@
+use std::io::{ErrorKind, Write};
+
+use crate::{CstError, now_mil};
+
+// the low bits of a uuid are a counter, the high ones are milliseconds
+pub const UUID_COUNTER_BITS: u64 = 22;
+// the uuids are reserved in the clock file this many milliseconds in advance, so that it is written rarely
+const CLOCK_RESERVE_MS: u64 = 5000;
+// the uuids of our replicas ahead of our wall clock by more than it are not followed
+pub const MAX_CLOCK_DRIFT_MS: u64 = 60_000;
+
+// A hybrid logical clock which generates the uuids. The milliseconds in them follow the wall clock
+// as long as it moves forward, otherwise the counter grows. The uuids received from our replicas
+// move it forward too, so the uuids never go backward, and a write is always ordered after those
+// it may have seen even if our wall clock runs behind.
+#[derive(Debug)]
+pub struct HybridClock {
+    uuid: u64,
+    // no uuid beyond it is handed out before it is saved in the file, so that we start after it
+    // next time even if the wall clock has been stepped backward meanwhile.
+    reserved: u64,
+    file: Option<String>,
+}
+
+impl HybridClock {
+    pub fn new(uuid: u64) -> Self {
+        HybridClock{
+            uuid,
+            reserved: u64::MAX,
+            file: None,
+        }
+    }
+
+    // start after the uuid saved in the file, in which the uuids are reserved from now on.
+    pub fn persist(&mut self, file: &str) -> Result<(), CstError> {
+        match std::fs::read_to_string(file) {
+            Ok(s) => {
+                let saved = s.trim().parse::<u64>().map_err(|_| std::io::Error::new(ErrorKind::InvalidData, format!("invalid uuid {:?} in {}", s, file)))?;
+                self.restore(saved);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.file = Some(file.to_string());
+        self.reserve()
+    }
+
+    pub fn current(&self) -> u64 {
+        self.uuid
+    }
+
+    pub fn next(&mut self, is_write: bool) -> u64 {
+        self.tick(now_mil(), is_write)
+    }
+
+    fn tick(&mut self, now: u64, is_write: bool) -> u64 {
+        let now = now << UUID_COUNTER_BITS;
+        if now > self.uuid {
+            self.uuid = now;
+        } else if is_write {
+            self.uuid += 1;
+        }
+        self.check_reserved();
+        self.uuid
+    }
+
+    // a uuid we generated before, found in our snapshot or aof
+    pub fn restore(&mut self, uuid: u64) {
+        if uuid > self.uuid {
+            self.uuid = uuid;
+            self.check_reserved();
+        }
+    }
+
+    // a uuid received from a replica, it's ignored and false is returned if it's too far ahead of our wall clock.
+    pub fn observe(&mut self, uuid: u64) -> bool {
+        if uuid <= self.uuid {
+            return true;
+        }
+        if uuid >> UUID_COUNTER_BITS > now_mil() + MAX_CLOCK_DRIFT_MS {
+            return false;
+        }
+        self.uuid = uuid;
+        self.check_reserved();
+        true
+    }
+
+    fn check_reserved(&mut self) {
+        if self.uuid >= self.reserved {
+            if let Err(e) = self.reserve() {
+                // we don't retry on every uuid, the next reservation is after another CLOCK_RESERVE_MS
+                error!("Failed to reserve the uuids in the clock file because {}", e);
+                self.reserved = self.uuid + (CLOCK_RESERVE_MS << UUID_COUNTER_BITS);
+            }
+        }
+    }
+
+    fn reserve(&mut self) -> Result<(), CstError> {
+        let file = match &self.file {
+            None => return Ok(()),
+            Some(f) => f,
+        };
+        let reserved = self.uuid + (CLOCK_RESERVE_MS << UUID_COUNTER_BITS);
+        let tmp = format!("{}.tmp", file);
+        let mut f = std::fs::File::create(&tmp)?;
+        f.write_all(reserved.to_string().as_bytes())?;
+        f.sync_data()?;
+        std::fs::rename(&tmp, file)?;
+        self.reserved = reserved;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::{HybridClock, MAX_CLOCK_DRIFT_MS, UUID_COUNTER_BITS};
+    use crate::now_mil;
+
+    #[test]
+    fn test_hybrid_clock() {
+        let mut c = HybridClock::new(1);
+        let t = 1000 << UUID_COUNTER_BITS;
+        assert_eq!(c.tick(1000, true), t);
+        assert_eq!(c.tick(1000, true), t + 1);
+        assert_eq!(c.tick(1000, false), t + 1);
+        // the wall clock is stepped backward
+        assert_eq!(c.tick(900, true), t + 2);
+        assert_eq!(c.tick(1001, true), 1001 << UUID_COUNTER_BITS);
+
+        // follow a replica whose clock is ahead, but not too far
+        let now = now_mil();
+        let his = (now + 1000) << UUID_COUNTER_BITS;
+        assert!(c.observe(his));
+        assert_eq!(c.next(true), his + 1);
+        assert!(!c.observe((now + MAX_CLOCK_DRIFT_MS + 1000) << UUID_COUNTER_BITS));
+        assert_eq!(c.next(true), his + 2);
+
+        // the uuids reserved in the file are never handed out again
+        let file = std::env::temp_dir().join(format!("constdb_clock_test_{}", std::process::id())).to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&file);
+        c.persist(&file).unwrap();
+        let last = c.next(true);
+        let mut restarted = HybridClock::new(1);
+        restarted.persist(&file).unwrap();
+        assert!(restarted.next(true) > last);
+        let _ = std::fs::remove_file(&file);
+    }
+}
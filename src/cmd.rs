@@ -5,12 +5,15 @@ use bitflags::_core::cmp::max;
 use bitflags::_core::fmt::{Debug, Formatter};
 
 use crate::{Bytes, CstError};
-use crate::type_counter::{decr_command, delcnt_command, incr_command};
+use crate::type_counter::{decr_command, delcnt_command, delcounter_command, incr_command, incrby_command};
 use crate::lib::utils::bytes2i64;
+use crate::crdt::timestamp::Timestamp;
+use crate::clock::UUID_COUNTER_BITS;
 use crate::link::Client;
+use crate::rdb::RdbImport;
 use crate::type_hash::{deldict_command, hdel_command, hget_command, hgetall_command, hset_command};
 use crate::type_set::{delset_command, sadd_command, smembers_command, spop_command, srem_command};
-use crate::object::{Encoding, Object};
+use crate::object::Encoding;
 use crate::replica::{meet_command, replicas_command, sync_command};
 use crate::resp::{Message, new_msg_ok};
 use crate::stats::info_command;
@@ -40,6 +43,14 @@ impl Cmd {
         COMMANDS.get(name.to_ascii_lowercase().as_slice()).map(|c| Cmd{args, command: c}).ok_or(CstError::UnknownCmd(String::from(Bytes::from(name))))
     }
 
+    pub fn name(&self) -> &'static str {
+        self.command.name
+    }
+
+    pub fn args(&self) -> &[Message] {
+        &self.args
+    }
+
     pub fn exec(&self, client: Option<&mut Client>, server: &mut Server) -> Result<Message, CstError> {
         server.metrics.incr_cmd_processed();
         if self.command.flags & COMMAND_REPL_ONLY > 0 {
@@ -56,6 +67,9 @@ impl Cmd {
     pub fn exec_detail(&self, server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, repl: bool) -> Result<Message, CstError> {
         let r = (self.command.handler)(server, client, nodeid, uuid, self.args.clone());
         debug!("Executed command {}, nodeid={}, uuid={}, repl={}, result={:?}", self, nodeid, uuid, repl, r);
+        if r.is_ok() && (self.command.flags & COMMAND_WRITE) > 0 {
+            server.dirty += 1;
+        }
         if !r.is_err() && repl {
             server.replicate_cmd(uuid, self.command.name, self.args.clone());
         }
@@ -63,6 +77,11 @@ impl Cmd {
     }
 }
 
+// the static name of a command, used when a command is restored from somewhere else than a connection.
+pub fn command_name(name: &[u8]) -> Option<&'static str> {
+    COMMANDS.get(name.to_ascii_lowercase().as_slice()).map(|c| c.name)
+}
+
 type CommandHandler = fn(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError>;
 
 pub struct Command {
@@ -100,9 +119,17 @@ lazy_static!{
         new_command!(command_table, "meet", meet_command, COMMAND_CTRL);
         new_command!(command_table, "client", client_command, COMMAND_CTRL);
 
+        // persistence
+        new_command!(command_table, "save", save_command, COMMAND_CTRL);
+        new_command!(command_table, "bgsave", bgsave_command, COMMAND_CTRL);
+        new_command!(command_table, "lastsave", lastsave_command, COMMAND_READONLY);
+        new_command!(command_table, "bgrewriteaof", bgrewriteaof_command, COMMAND_CTRL);
+        new_command!(command_table, "importrdb", importrdb_command, COMMAND_CTRL);
+
         //stats
         new_command!(command_table, "repllog", repllog_command, COMMAND_READONLY);
         new_command!(command_table, "info", info_command, COMMAND_READONLY);
+        new_command!(command_table, "debug", debug_command, COMMAND_READONLY);
 
         // common commands
         new_command!(command_table, "get", get_command, COMMAND_READONLY);
@@ -110,11 +137,14 @@ lazy_static!{
         new_command!(command_table, "desc", desc_command, COMMAND_READONLY);
         new_command!(command_table, "del", del_command, COMMAND_WRITE | COMMAND_NO_REPLICATE);
         new_command!(command_table, "delbytes", delbytes_command, COMMAND_WRITE | COMMAND_REPL_ONLY);
+        new_command!(command_table, "pexpireat", pexpireat_command, COMMAND_WRITE | COMMAND_REPL_ONLY);
 
         // counter
         new_command!(command_table, "incr", incr_command, COMMAND_WRITE);
         new_command!(command_table, "decr", decr_command, COMMAND_WRITE);
+        new_command!(command_table, "incrby", incrby_command, COMMAND_WRITE);
         new_command!(command_table, "delcnt", delcnt_command, COMMAND_WRITE | COMMAND_REPL_ONLY);
+        new_command!(command_table, "delcounter", delcounter_command, COMMAND_WRITE | COMMAND_REPL_ONLY);
 
 
         // set
@@ -164,13 +194,63 @@ pub fn node_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
     }
 }
 
+pub fn save_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, _uuid: u64, _args: Vec<Message>) -> Result<Message, CstError> {
+    if server.bgsave_in_progress() {
+        return Ok(Message::Error("Background save already in progress".into()));
+    }
+    server.save()?;
+    Ok(new_msg_ok())
+}
+
+pub fn bgsave_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, _uuid: u64, _args: Vec<Message>) -> Result<Message, CstError> {
+    if server.bgsave_in_progress() {
+        return Ok(Message::Error("Background save already in progress".into()));
+    }
+    server.bgsave()?;
+    Ok(Message::String("Background saving started".into()))
+}
+
+// the aof is rewritten by dumping a snapshot, after which the aof only holds the following commands.
+pub fn bgrewriteaof_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, _uuid: u64, _args: Vec<Message>) -> Result<Message, CstError> {
+    if server.aof.is_none() {
+        return Ok(Message::Error("the aof is not enabled".into()));
+    }
+    if server.bgsave_in_progress() {
+        return Ok(Message::Error("Background save already in progress".into()));
+    }
+    server.bgsave()?;
+    Ok(Message::String("Background append only file rewriting started".into()))
+}
+
+// importrdb <file> [counters <pattern>]
+// the keys of the rdb are applied in the background as our own writes, those matching the pattern
+// and holding integers are added to counters.
+pub fn importrdb_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, _uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let mut args = args.into_iter();
+    let file_name = args.next_string()?;
+    let counters = match args.next_bytes() {
+        Err(_) => None,
+        Ok(o) if o.as_bytes().eq_ignore_ascii_case(b"counters") => Some(args.next_bytes()?.as_bytes().to_vec()),
+        Ok(o) => return Err(CstError::UnknownSubCmd(o.to_string(), "importrdb".to_string())),
+    };
+    if server.rdb_import.is_some() {
+        return Ok(Message::Error("Import of rdb already in progress".into()));
+    }
+    server.rdb_import = Some(RdbImport::start(&file_name, counters)?);
+    Ok(Message::String("Import of rdb started".into()))
+}
+
+pub fn lastsave_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, _uuid: u64, _args: Vec<Message>) -> Result<Message, CstError> {
+    Ok(Message::Integer(server.latest_dump_time as i64))
+}
+
 pub fn get_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
     info!("thread id: {:?}", std::thread::current().id());
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     match server.db.query(&key_name, uuid) {
         Some(o) => {
-            if o.create_time < o.delete_time {
+            if !o.alive() {
                 return Ok(Message::Nil);
             }
             match &o.enc {
@@ -183,20 +263,14 @@ pub fn get_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u
     }
 }
 
-pub fn set_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn set_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     let value = args.next_bytes()?;
     // let o = server.db.entry(key_name).or_insert(Object::new(Encoding::Bytes(value.clone()), uuid, 0));
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::Bytes(value.clone()), uuid, 0);
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
-    if o.update_time > uuid {
+    let o = server.db.query_or_create(&key_name, t, Encoding::Bytes(value.clone()), client.is_none())?;
+    if o.update_time > t {
         return Ok(Message::Integer(0));
     }
     match o.enc {
@@ -204,7 +278,7 @@ pub fn set_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u
         _ => return Err(CstError::InvalidType),
     }
     o.enc = Encoding::Bytes(value);
-    o.updated_at(uuid);
+    o.updated_at(t);
     Ok(new_msg_ok())
 }
 
@@ -218,7 +292,8 @@ pub fn desc_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
 }
 
 // del command can be sent only by the client, not the replicas.
-pub fn del_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn del_command(server: &mut Server, _client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let mut deleted = 0;
     let mut replicates = vec![];
@@ -227,16 +302,17 @@ pub fn del_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u
         None => {},
         Some(v) => {
             debug!("deleting object, ct: {}, dt: {}, mt: {}", v.create_time, v.delete_time, v.update_time);
+            let alive = v.alive();
             match &mut v.enc {
                 // as for counter and bytes, we don't allow deletion before some later modifications exist already.
                 // since we are sure that the `del` command is sent by our clients, not replicas, this policy doesn't ruin our eventual consistency.
                 Encoding::Counter(g) => {
-                    if v.update_time <= uuid { // v.ct and v.dt must be less than uuid
-                        if v.create_time < v.delete_time {
+                    if v.update_time <= t { // v.ct and v.dt must be less than t
+                        if !alive {
                             // already deleted, and has no following modifications since that deletion
                         } else {
-                            v.delete_time = uuid;
-                            v.update_time = uuid;
+                            v.delete_time = t;
+                            v.update_time = t;
                             deleted = 1;
                             let mut d = HashMap::new();
                             for (nodeid, (value, _)) in g.iter() {
@@ -244,45 +320,44 @@ pub fn del_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u
                             }
                             let mut args = Vec::with_capacity(d.len() * 2 + 1);
                             args.push(Message::BulkString(key_name.into()));
-                            for (nodeid, value) in d {
-                                g.change(nodeid, -value, uuid);
-                                args.push(Message::Integer(nodeid as i64));
+                            for (n, value) in d {
+                                args.push(Message::Integer(n as i64));
                                 args.push(Message::Integer(-value));
                             }
-                            replicates.push(("delcnt", args));
+                            g.change(nodeid, -g.get(), uuid);
+                            replicates.push(("delcounter", args));
                         }
                     }
                 }
                 Encoding::Bytes(_) => {
-                    if v.update_time <= uuid { // v.ct and v.dt must be less than uuid
-                        if v.create_time < v.delete_time {  // already deleted
+                    if v.update_time <= t { // v.ct and v.dt must be less than t
+                        if !alive {  // already deleted
 
                         } else {
-                            v.delete_time = uuid;
-                            v.update_time = uuid;
+                            v.delete_time = t;
+                            v.update_time = t;
+                            v.enc = Encoding::Bytes("".into());
                             deleted = 1;
                             replicates.push(("delbytes", vec![Message::BulkString(key_name.into())]));
                         }
                     }
                 }
                 Encoding::LWWSet(s) => {
-                    let members: Vec<Bytes> = s.iter_all().map(|(x, _)| x.clone()).collect();
-                    let _ = s.remove_members(members.as_slice(), uuid);
-                    if v.create_time >= v.delete_time && uuid > v.create_time {  // exist before and now deleted
+                    s.remove_before(t);
+                    if alive && t > v.create_time {  // exist before and now deleted
                         deleted = 1;
                     }
-                    v.delete_time = max(v.delete_time, uuid);
-                    v.update_time = max(v.update_time, uuid);
+                    v.delete_time = max(v.delete_time, t);
+                    v.update_time = max(v.update_time, t);
                     replicates.push(("delset", vec![Message::BulkString(key_name.into())]));
                 }
                 Encoding::LWWDict(d) => {
-                    let fields: Vec<Bytes> = d.iter_all().map(|(b, _, _)| b.clone()).collect();
-                    let _ = d.del_fields(fields.as_slice(), uuid);
-                    if v.create_time >= v.delete_time && uuid > v.create_time { // exist before and now deleted
+                    d.remove_before(t);
+                    if alive && t > v.create_time { // exist before and now deleted
                         deleted = 1;
                     }
-                    v.delete_time = max(v.delete_time, uuid);
-                    v.update_time = max(v.update_time, uuid);
+                    v.delete_time = max(v.delete_time, t);
+                    v.update_time = max(v.update_time, t);
                     replicates.push(("deldict", vec![Message::BulkString(key_name.into())]));
                 }
             }
@@ -295,24 +370,32 @@ pub fn del_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u
     Ok(Message::Integer(deleted))
 }
 
-pub fn delbytes_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn delbytes_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::Bytes("".into()), uuid, 0));
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::Bytes("".into()), uuid, 0);
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::Bytes("".into()), client.is_none())?;
     match o.enc {
         Encoding::Bytes(_) => {},
         _ => return Err(CstError::InvalidType),
     }
-    o.delete_time = max(o.delete_time, uuid);
-    o.update_time = max(o.update_time, uuid);
+    // the value is dropped with it, unless it's set again later
+    if o.update_time < t {
+        o.enc = Encoding::Bytes("".into());
+    }
+    o.delete_time = max(o.delete_time, t);
+    o.update_time = max(o.update_time, t);
+    Ok(Message::None)
+}
+
+// the ttl of a key imported from a rdb file, which every node sets, so that they expire it at the same time.
+pub fn pexpireat_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, _uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let mut args = args.into_iter();
+    let key_name = args.next_bytes()?;
+    let at = args.next_u64()?;
+    // the expire time is a uuid, like the ones loaded from the snapshots
+    server.db.expire_at(&key_name, at << UUID_COUNTER_BITS);
     Ok(Message::None)
 }
 
@@ -333,6 +416,24 @@ pub fn repllog_command(server: &mut Server, _client: Option<&mut Client>, _nodei
     }
 }
 
+// the digests are of the data clients see, which are the same on every node once the replication catches up.
+pub fn debug_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let mut args = args.into_iter();
+    let sub_command = args.next_string()?;
+    let hex = |d: u64| Message::BulkString(format!("{:016x}", d).into());
+    match sub_command.to_ascii_lowercase().as_str() {
+        "digest" => Ok(hex(server.db.digest(uuid))),
+        "digest-value" => {
+            let mut digests = vec![];
+            while let Ok(key) = args.next_bytes() {
+                digests.push(hex(server.db.key_digest(&key, uuid)));
+            }
+            Ok(Message::Array(digests))
+        }
+        others => Err(CstError::UnknownSubCmd(others.to_string(), "DEBUG".to_string())),
+    }
+}
+
 pub fn client_command(_server: &mut Server, client: Option<&mut Client>, _nodeid: u64, _uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
     let mut args = args.into_iter();
     let sub_command = args.next_string()?;
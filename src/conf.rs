@@ -2,6 +2,8 @@ use std::env::current_dir;
 
 use clap::App;
 
+use crate::snapshot::Compression;
+
 lazy_static!{
     pub static ref GLOBAL_CONF: Config = parse_args();
     pub static ref CONF_PATH: String = get_conf_path();
@@ -21,6 +23,40 @@ pub struct Config {
     pub tcp_backlog: u32,
     pub replica_heartbeat_frequency: u32,
     pub replica_gossip_frequency: u32,
+    pub repl_backlog_size: u64,
+    pub repl_backlog_dir: String,
+    pub repl_backlog_disk_size: u64,
+    pub repl_backlog_segment_size: u64,
+    pub save: Vec<(u64, u64)>, // (seconds, changes)
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: AppendFsync,
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+    pub snapshot_mode: SnapshotMode,
+    pub snapshot_chunk_ms: u64,
+    pub snapshot_compression: Compression,
+    // load the snapshots of our replicas directly from the connections, rather than saving them first
+    pub repl_diskless_load: bool,
+    // milliseconds the snapshots of the replicas may be merged for in every round of the main loop, in total
+    pub repl_merge_chunk_ms: u64,
+    // seconds between the comparisons of our data with every replica's, 0 disables them
+    pub anti_entropy_interval: u64,
+}
+
+// how a snapshot is dumped in the background. `Fork` dumps it in a child process, while `Incremental`
+// serializes the data in the main thread in chunks of snapshot_chunk_ms, and writes it in another thread.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SnapshotMode {
+    Fork,
+    Incremental,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
 }
 
 #[derive(Deserialize)]
@@ -36,6 +72,22 @@ struct OriginConfig {
     tcp_backlog: Option<u32>,
     replica_heartbeat_frequency: Option<u32>,
     replica_gossip_frequency: Option<u32>,
+    repl_backlog_size: Option<u64>,
+    repl_backlog_dir: Option<String>,
+    repl_backlog_disk_size: Option<u64>,
+    repl_backlog_segment_size: Option<u64>,
+    save: Option<String>,
+    appendonly: Option<bool>,
+    appendfilename: Option<String>,
+    appendfsync: Option<String>,
+    auto_aof_rewrite_percentage: Option<u64>,
+    auto_aof_rewrite_min_size: Option<u64>,
+    snapshot_mode: Option<String>,
+    snapshot_chunk_ms: Option<u64>,
+    snapshot_compression: Option<String>,
+    repl_diskless_load: Option<bool>,
+    repl_merge_chunk_ms: Option<u64>,
+    anti_entropy_interval: Option<u64>,
 }
 
 fn get_conf_path() -> String {
@@ -81,8 +133,61 @@ fn parse_args() -> Config {
                     replica_heartbeat_frequency: oc.replica_heartbeat_frequency.unwrap_or(4),
                     replica_gossip_frequency: oc.replica_gossip_frequency.unwrap_or(15),
                     threads: oc.threads.unwrap_or(4),
+                    repl_backlog_size: oc.repl_backlog_size.unwrap_or(1024000),
+                    repl_backlog_dir: oc.repl_backlog_dir.unwrap_or_default(),
+                    repl_backlog_disk_size: oc.repl_backlog_disk_size.unwrap_or(1 << 30),
+                    repl_backlog_segment_size: oc.repl_backlog_segment_size.unwrap_or(64 << 20),
+                    save: parse_save_points(oc.save.as_deref().unwrap_or("3600 1 300 100 60 10000")),
+                    appendonly: oc.appendonly.unwrap_or_default(),
+                    appendfilename: oc.appendfilename.unwrap_or_else(|| "appendonly.aof".to_string()),
+                    appendfsync: match oc.appendfsync.as_deref().unwrap_or("everysec") {
+                        "always" => AppendFsync::Always,
+                        "everysec" => AppendFsync::EverySec,
+                        "no" => AppendFsync::No,
+                        o => {
+                            println!("invalid appendfsync `{}`, it should be one of always, everysec and no", o);
+                            std::process::exit(-1);
+                        }
+                    },
+                    auto_aof_rewrite_percentage: oc.auto_aof_rewrite_percentage.unwrap_or(100),
+                    auto_aof_rewrite_min_size: oc.auto_aof_rewrite_min_size.unwrap_or(64 << 20),
+                    snapshot_mode: match oc.snapshot_mode.as_deref().unwrap_or("fork") {
+                        "fork" => SnapshotMode::Fork,
+                        "incremental" => SnapshotMode::Incremental,
+                        o => {
+                            println!("invalid snapshot_mode `{}`, it should be either fork or incremental", o);
+                            std::process::exit(-1);
+                        }
+                    },
+                    snapshot_chunk_ms: oc.snapshot_chunk_ms.unwrap_or(10),
+                    snapshot_compression: match Compression::from_name(oc.snapshot_compression.as_deref().unwrap_or("none")) {
+                        Some(c) => c,
+                        None => {
+                            println!("invalid snapshot_compression `{}`, it should be either none or lz4", oc.snapshot_compression.unwrap_or_default());
+                            std::process::exit(-1);
+                        }
+                    },
+                    repl_diskless_load: oc.repl_diskless_load.unwrap_or_default(),
+                    repl_merge_chunk_ms: oc.repl_merge_chunk_ms.unwrap_or(5),
+                    anti_entropy_interval: oc.anti_entropy_interval.unwrap_or(300),
                 }
             },
         }
     }
-}
\ No newline at end of file
+}
+// parse save points like "900 1 300 10", which means saving after 900 seconds if at least 1 change
+// was made, or after 300 seconds if at least 10 changes were made. An empty string disables it.
+fn parse_save_points(s: &str) -> Vec<(u64, u64)> {
+    let nums: Vec<u64> = match s.split_whitespace().map(|x| x.parse::<u64>()).collect() {
+        Ok(nums) => nums,
+        Err(e) => {
+            println!("invalid save config `{}`, {}", s, e);
+            std::process::exit(-1);
+        }
+    };
+    if nums.chunks(2).any(|x| x.len() != 2) {
+        println!("invalid save config `{}`, it should be pairs of seconds and changes", s);
+        std::process::exit(-1);
+    }
+    nums.chunks(2).map(|x| (x[0], x[1])).collect()
+}
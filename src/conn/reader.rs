@@ -1,7 +1,9 @@
 use std::cmp::min;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use tokio::fs::File;
-use tokio::io::{AsyncWriteExt, ErrorKind};
+use tokio::io::{AsyncRead, AsyncWriteExt, ErrorKind};
 use tokio::net::tcp::OwnedReadHalf;
 
 use crate::conn::buf_read::ReadBuf;
@@ -120,3 +122,123 @@ impl Reader {
         Ok(())
     }
 }
+
+// the next `remaining` bytes of a connection, those already in the buffer of the reader come first.
+// a snapshot is loaded from it while being received, then the reader is given back.
+#[derive(Debug)]
+pub struct SnapshotStream {
+    reader: Reader,
+    remaining: usize,
+}
+
+impl SnapshotStream {
+    pub fn new(reader: Reader, size: usize) -> Self {
+        Self{
+            reader,
+            remaining: size,
+        }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    pub fn into_inner(self) -> Reader {
+        self.reader
+    }
+}
+
+impl AsyncRead for SnapshotStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut tokio::io::ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if this.remaining == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        let buffered = this.reader.read_buf.buf_reader();
+        if !buffered.is_empty() {
+            let n = min(min(buffered.len(), this.remaining), buf.remaining());
+            buf.put_slice(&buffered[..n]);
+            this.reader.read_buf.forward(n);
+            this.remaining -= n;
+            return Poll::Ready(Ok(()));
+        }
+        let conn = match this.reader.conn.as_mut() {
+            Some(c) => c,
+            None => return Poll::Ready(Err(std::io::Error::new(ErrorKind::NotConnected, "no connection"))),
+        };
+        let mut tmp = [0u8; 16 * 1024];
+        let n = min(min(tmp.len(), this.remaining), buf.remaining());
+        let mut rb = tokio::io::ReadBuf::new(&mut tmp[..n]);
+        match Pin::new(conn).poll_read(cx, &mut rb) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Ready(Ok(())) if rb.filled().is_empty() => Poll::Ready(Err(ErrorKind::UnexpectedEof.into())),
+            Poll::Ready(Ok(())) => {
+                this.remaining -= rb.filled().len();
+                buf.put_slice(rb.filled());
+                Poll::Ready(Ok(()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use crate::Bytes;
+    use crate::conn::reader::{Reader, SnapshotStream};
+    use crate::crdt::timestamp::Timestamp;
+    use crate::object::{Encoding, Object};
+    use crate::resp::Message;
+    use crate::snapshot::{SNAPSHOT_FLAG_DATAS, SNAPSHOT_FLAG_NODE, SnapshotEntry, SnapshotLoader, SnapshotWriter};
+
+    #[test]
+    fn test_snapshot_stream() {
+        let mut w = SnapshotWriter::new(1024, vec![]);
+        w.write_head().unwrap().write_section(SNAPSHOT_FLAG_NODE, 1).unwrap();
+        w.write_item(|w| {
+            let _ = w.write_integer(1)?.write_integer(1)?.write_bytes(b"a")?.write_integer(3)?.write_bytes(b"a:1")?.write_integer(100)?;
+            Ok(())
+        }).unwrap();
+        w.write_section(SNAPSHOT_FLAG_DATAS, 2000).unwrap();
+        for i in 0..2000 {
+            let o = Object::new(Encoding::Bytes(Bytes::from(format!("value-{}", i))), Timestamp::new(10, 1), Timestamp::default());
+            w.write_item(|w| w.write_entry(format!("key-{}", i).as_bytes(), &o)).unwrap();
+        }
+        w.write_checksum().unwrap();
+        w.flush().unwrap();
+        let snapshot = std::mem::take(w.get_mut());
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let size = snapshot.len();
+            tokio::spawn(async move {
+                let (mut c, _) = listener.accept().await.unwrap();
+                // the reply of sync, the snapshot and a command following it arrive together
+                let mut data = format!(":{}\r\n", size).into_bytes();
+                data.extend_from_slice(&snapshot);
+                data.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+                c.write_all(&data).await.unwrap();
+            });
+            let (r, _w) = tokio::net::TcpStream::connect(addr).await.unwrap().into_split();
+            let mut reader = Reader::new(addr.to_string(), Some(r));
+            assert_eq!(reader.next_msg().await.unwrap(), Message::Integer(size as i64));
+
+            let mut loader = SnapshotLoader::new(SnapshotStream::new(reader, size));
+            let mut keys = 0;
+            while let Some(e) = loader.next().await.unwrap() {
+                if let SnapshotEntry::Data(_, _) = e {
+                    keys += 1;
+                }
+            }
+            assert_eq!(keys, 2000);
+            let stream = loader.into_inner();
+            assert_eq!(stream.remaining(), 0);
+            let mut reader = stream.into_inner();
+            assert_eq!(reader.next_msg().await.unwrap(), Message::Array(vec![Message::BulkString("PING".into())]));
+        });
+    }
+}
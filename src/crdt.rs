@@ -1,3 +1,4 @@
 pub mod vclock;
 pub mod lwwhash;
-pub mod list;
\ No newline at end of file
+pub mod list;
+pub mod timestamp;
@@ -7,16 +7,17 @@ use crate::snapshot::{SnapshotWriter, SnapshotLoader};
 use std::collections::hash_map::Iter;
 use std::cmp::max;
 use tokio::io::AsyncRead;
+use crate::crdt::timestamp::Timestamp;
 
 #[derive(Debug, Clone)]
-pub struct LWWHash<K, V> {
+pub struct LWWHash<K, V, T = Timestamp> {
     pub size: i32,
-    pub add: HashMap<K, (u64, V)>, // key => (add_time, value)
-    pub del: HashMap<K, u64>,      // key => del_time
+    pub add: HashMap<K, (T, V)>, // key => (add_time, value)
+    pub del: HashMap<K, T>,      // key => del_time
 }
 
-impl<K, V> LWWHash<K, V>
-    where K: Eq + Hash + Clone
+impl<K, V, T> LWWHash<K, V, T>
+    where K: Eq + Hash + Clone, T: Ord + Copy
 {
 
     pub fn empty() -> Self {
@@ -51,7 +52,7 @@ impl<K, V> LWWHash<K, V>
         }
     }
 
-    pub fn remove_time(&self, k: &K) -> Option<u64> {
+    pub fn remove_time(&self, k: &K) -> Option<T> {
         match (self.add.get(k), self.del.get(k)) {
             (_, None) => None,
             (None, Some(t)) => Some(*t),
@@ -84,7 +85,8 @@ impl<K, V> LWWHash<K, V>
         }
     }
 
-    pub fn set(&mut self, k: K, v: V, t: u64) -> bool {
+    // a key is either in add or in del, in which it's of the time it was added or removed lately.
+    pub fn set(&mut self, k: K, v: V, t: T) -> bool {
         if let Some(v) = self.del.get(&k) {
             if *v > t {
                 return false;
@@ -100,13 +102,13 @@ impl<K, V> LWWHash<K, V>
             None => {
                 let _ = self.del.remove(&k);
                 self.add.insert(k, (t, v));
+                self.size += 1;
             }
         }
-        self.size += 1;
         true
     }
 
-    pub fn rem(&mut self, k: &K, t: u64) -> bool {
+    pub fn rem(&mut self, k: &K, t: T) -> bool {
         if let Some((v, _)) = self.add.get(k) {
             if *v > t {
                 return false;
@@ -120,12 +122,94 @@ impl<K, V> LWWHash<K, V>
             }
             None => {
                 self.del.insert(k.clone(), t);
-                let _ = self.add.remove(k);
+                if self.add.remove(k).is_some() {
+                    self.size -= 1;
+                }
             }
         }
-        self.size -= 1;
         true
     }
+
+    // the join of two states, every key is of the latest one of its adds and removes in both,
+    // so the result is the same no matter in which order the states are merged, or how many times.
+    pub fn merge(&mut self, other: Self) {
+        for (k, (t, v)) in other.add {
+            self.set(k, v, t);
+        }
+        for (k, t) in other.del {
+            self.rem(&k, t);
+        }
+    }
+
+    // remove the keys added or removed before t, at which the whole structure was removed. The older tombstones
+    // are moved to t too, otherwise they would depend on whether the removal of the key or the structure came first.
+    pub fn remove_before(&mut self, t: T) {
+        let keys: Vec<K> = self.add.iter().filter(|(_, (at, _))| *at < t).map(|(k, _)| k.clone())
+            .chain(self.del.iter().filter(|(_, dt)| **dt < t).map(|(k, _)| k.clone()))
+            .collect();
+        for k in keys.iter() {
+            self.rem(k, t);
+        }
+    }
+}
+
+fn tombstones_json(del: &HashMap<Bytes, Timestamp>) -> Vec<serde_json::Value> {
+    let mut tombstones: Vec<(&Bytes, &Timestamp)> = del.iter().collect();
+    tombstones.sort_by(|(k1, t1), (k2, t2)| (t1, k1.as_bytes()).cmp(&(t2, k2.as_bytes())));
+    tombstones.into_iter().map(|(k, t)| serde_json::json!({"key": k.to_string(), "del_time": t})).collect()
+}
+
+impl<K, V> LWWHash<K, V>
+    where K: Eq + Hash + Clone, V: Clone
+{
+    // the keys added or removed after the uuid, which is all a replica having the rest needs to catch up.
+    pub fn delta_since(&self, uuid: u64) -> Self {
+        let add: HashMap<K, (Timestamp, V)> = self.add.iter().filter(|(_, (t, _))| t.uuid > uuid).map(|(k, v)| (k.clone(), v.clone())).collect();
+        let del: HashMap<K, Timestamp> = self.del.iter().filter(|(_, t)| t.uuid > uuid).map(|(k, t)| (k.clone(), *t)).collect();
+        Self{
+            size: add.len() as i32,
+            add,
+            del,
+        }
+    }
+}
+
+// the node ids in the timestamps are saved apart from the rest of the structure, after the object,
+// so that the snapshots are still readable by the older versions. Only those of nonzero are saved.
+impl<V> LWWHash<Bytes, V> {
+    pub fn save_nodes<W: Write>(&self, dst: &mut SnapshotWriter<W>) -> Result<(), CstError> {
+        let adds: Vec<(&Bytes, u64)> = self.add.iter().filter(|(_, (t, _))| t.node_id != 0).map(|(k, (t, _))| (k, t.node_id)).collect();
+        let dels: Vec<(&Bytes, u64)> = self.del.iter().filter(|(_, t)| t.node_id != 0).map(|(k, t)| (k, t.node_id)).collect();
+        for nodes in [adds, dels].iter() {
+            dst.write_integer(nodes.len() as i64)?;
+            for (k, node_id) in nodes {
+                dst.write_integer(k.len() as i64)?;
+                dst.write_bytes(k.as_bytes())?;
+                dst.write_integer(*node_id as i64)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load_nodes<T: AsyncRead + Unpin>(&mut self, src: &mut SnapshotLoader<T>) -> Result<(), CstError> {
+        for is_add in [true, false].iter() {
+            let cnt = src.read_len().await?;
+            for _ in 0..cnt {
+                let kl = src.read_len().await?;
+                let k: Bytes = src.read_bytes(kl).await?.into();
+                let node_id = src.read_integer().await? as u64;
+                let t = if *is_add {
+                    self.add.get_mut(&k).map(|(t, _)| t)
+                } else {
+                    self.del.get_mut(&k)
+                };
+                if let Some(t) = t {
+                    t.node_id = node_id;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 pub type Dict = LWWHash<Bytes, Bytes>;
@@ -145,53 +229,56 @@ impl Dict {
         }
     }
 
-    pub fn set_field(&mut self, field: Bytes, value: Bytes, uuid: u64) -> bool {
-        self.set(field, value, uuid)
+    pub fn set_field(&mut self, field: Bytes, value: Bytes, t: Timestamp) -> bool {
+        self.set(field, value, t)
     }
 
-    pub fn set_fields(&mut self, kvs: Vec<(Bytes, Bytes)>, uuid: u64) -> u32 {
+    pub fn set_fields(&mut self, kvs: Vec<(Bytes, Bytes)>, t: Timestamp) -> u32 {
         let mut cnt = 0;
         for (k, v) in kvs.into_iter().next() {
-            if self.set_field(k, v, uuid) {
+            if self.set_field(k, v, t) {
                 cnt += 1;
             }
         }
         cnt
     }
 
-    pub fn del_field(&mut self, field: &Bytes, uuid: u64) -> bool {
-        self.rem(field, uuid)
+    pub fn del_field(&mut self, field: &Bytes, t: Timestamp) -> bool {
+        self.rem(field, t)
     }
 
-    pub fn del_fields(&mut self, fields: &[Bytes], uuid: u64) -> u32 {
+    pub fn del_fields(&mut self, fields: &[Bytes], t: Timestamp) -> u32 {
         let mut s = 0;
         for field in fields {
-            if self.del_field(field, uuid) {
+            if self.del_field(field, t) {
                 s += 1;
             }
         }
         s
     }
 
-    pub fn merge(&mut self, other: Self) {
-        for (k, (t, v)) in other.iter() {
-            self.set(k.clone(), v.clone(), t);
-        }
-        unimplemented!()
-    }
-
     pub fn describe(&self) -> Message {
-        let a: Vec<Message> = self.add.iter().map(|(k, (v, vv))| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(*v as i64), Message::BulkString(vv.clone())])).collect();
-        let d: Vec<Message> = self.del.iter().map(|(k, v)| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(*v as i64)])).collect();
+        let a: Vec<Message> = self.add.iter().map(|(k, (v, vv))| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(v.uuid as i64), Message::Integer(v.node_id as i64), Message::BulkString(vv.clone())])).collect();
+        let d: Vec<Message> = self.del.iter().map(|(k, v)| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(v.uuid as i64), Message::Integer(v.node_id as i64)])).collect();
         Message::Array(vec![Message::Array(a), Message::Array(d)])
     }
 
+    // the fields and the tombstones, sorted by the time they were added or removed
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut fields: Vec<(&Bytes, &(Timestamp, Bytes))> = self.add.iter().collect();
+        fields.sort_by(|(k1, (t1, _)), (k2, (t2, _))| (t1, k1.as_bytes()).cmp(&(t2, k2.as_bytes())));
+        let fields: Vec<serde_json::Value> = fields.into_iter()
+            .map(|(k, (t, v))| serde_json::json!({"field": k.to_string(), "value": v.to_string(), "add_time": t}))
+            .collect();
+        serde_json::json!({"fields": fields, "tombstones": tombstones_json(&self.del)})
+    }
+
     pub fn save_snapshot<W: Write>(&self, dst: &mut SnapshotWriter<W>) -> Result<(), CstError> {
         dst.write_integer(self.add.len() as i64)?;
         for (k, (t, v)) in self.add.iter() {
             dst.write_integer(k.len() as i64)?;
             dst.write_bytes(k.as_bytes())?;
-            dst.write_integer(*t as i64)?;
+            dst.write_integer(t.uuid as i64)?;
             dst.write_integer(v.len() as i64)?;
             dst.write_bytes(v.as_bytes())?;
         }
@@ -199,27 +286,27 @@ impl Dict {
         for (k, t) in self.del.iter() {
             dst.write_integer(k.len() as i64)?;
             dst.write_bytes(k.as_bytes())?;
-            dst.write_integer(*t as i64)?;
+            dst.write_integer(t.uuid as i64)?;
         }
         Ok(())
     }
 
     pub async fn load_snapshot<T: AsyncRead + Unpin>(src: &mut SnapshotLoader<T>) -> Result<Self, CstError> {
         let mut s = Self::empty();
-        let add_cnt = src.read_integer().await? as usize;
+        let add_cnt = src.read_len().await?;
         for _ in 0..add_cnt {
-            let kl = src.read_integer().await? as usize;
+            let kl = src.read_len().await?;
             let k: Bytes = src.read_bytes(kl).await?.into();
-            let t = src.read_integer().await? as u64;
-            let vl = src.read_integer().await? as usize;
+            let t = Timestamp::new(src.read_integer().await? as u64, 0);
+            let vl = src.read_len().await?;
             let v: Bytes = src.read_bytes(vl).await?.into();
             let _ = s.set(k, v, t);
         }
-        let del_cnt = src.read_integer().await? as usize;
+        let del_cnt = src.read_len().await?;
         for _ in 0..del_cnt {
-            let bl = src.read_integer().await? as usize;
+            let bl = src.read_len().await?;
             let k: Bytes = src.read_bytes(bl).await?.into();
-            let t = src.read_integer().await? as u64;
+            let t = Timestamp::new(src.read_integer().await? as u64, 0);
             let _ = s.rem(&k, t);
         }
         Ok(s)
@@ -227,12 +314,12 @@ impl Dict {
 }
 
 pub struct DictIter<'a> {
-    i: Iter<'a, Bytes, (u64, Bytes)>,
+    i: Iter<'a, Bytes, (Timestamp, Bytes)>,
     h: &'a Dict,
 }
 
 impl<'a> Iterator for DictIter<'a> {
-    type Item = (&'a Bytes, (u64, &'a Bytes));
+    type Item = (&'a Bytes, (Timestamp, &'a Bytes));
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some((k, (t, v))) = self.i.next() {
@@ -248,12 +335,12 @@ impl<'a> Iterator for DictIter<'a> {
 }
 
 pub struct DictAllIter<'a> {
-    a: Iter<'a, Bytes, (u64, Bytes)>,
-    d: Iter<'a, Bytes, u64>,
+    a: Iter<'a, Bytes, (Timestamp, Bytes)>,
+    d: Iter<'a, Bytes, Timestamp>,
 }
 
 impl<'a> Iterator for DictAllIter<'a> {
-    type Item = (&'a Bytes, u64, bool);
+    type Item = (&'a Bytes, Timestamp, bool);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.a.next().map(|(b, (u, _))| (b, *u, true)).or(self.d.next().map(|(b, u)| (b, *u, false)))
@@ -263,14 +350,14 @@ impl<'a> Iterator for DictAllIter<'a> {
 pub type Set = LWWHash<Bytes, ()>;
 
 impl Set {
-    pub fn add_member(&mut self, member: Bytes, uuid: u64) -> bool {
-        self.set(member, (), uuid)
+    pub fn add_member(&mut self, member: Bytes, t: Timestamp) -> bool {
+        self.set(member, (), t)
     }
 
-    pub fn add_members(&mut self, members: &[Bytes], uuid: u64) -> u64 {
+    pub fn add_members(&mut self, members: &[Bytes], t: Timestamp) -> u64 {
         let mut s = 0;
         for member in members {
-            if self.add_member(member.clone(), uuid) {
+            if self.add_member(member.clone(), t) {
                 s += 1;
             }
         }
@@ -278,14 +365,14 @@ impl Set {
     }
 
     #[inline]
-    pub fn remove_member(&mut self, member: &Bytes, uuid: u64) -> bool {
-        self.rem(member, uuid)
+    pub fn remove_member(&mut self, member: &Bytes, t: Timestamp) -> bool {
+        self.rem(member, t)
     }
 
-    pub fn remove_members(&mut self, members: &[Bytes], uuid: u64) -> u64 {
+    pub fn remove_members(&mut self, members: &[Bytes], t: Timestamp) -> u64 {
         let mut s = 0;
         for member in members {
-            if self.remove_member(member, uuid) {
+            if self.remove_member(member, t) {
                 s += 1;
             }
         }
@@ -311,15 +398,19 @@ impl Set {
     }
 
     pub fn describe(&self) -> Message {
-        let a: Vec<Message> = self.add.iter().map(|(k, (v, _))| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(*v as i64)])).collect();
-        let d: Vec<Message> = self.del.iter().map(|(k, v)| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(*v as i64)])).collect();
+        let a: Vec<Message> = self.add.iter().map(|(k, (v, _))| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(v.uuid as i64), Message::Integer(v.node_id as i64)])).collect();
+        let d: Vec<Message> = self.del.iter().map(|(k, v)| Message::Array(vec![Message::BulkString(k.clone()), Message::Integer(v.uuid as i64), Message::Integer(v.node_id as i64)])).collect();
         Message::Array(vec![Message::Array(a), Message::Array(d)])
     }
 
-    pub fn merge(&mut self, other: Self) {
-        for (k, v) in other.iter() {
-            self.set(k.clone(), (), v);
-        }
+    // the members and the tombstones, sorted by the time they were added or removed
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut members: Vec<(&Bytes, &Timestamp)> = self.add.iter().map(|(k, (t, _))| (k, t)).collect();
+        members.sort_by(|(k1, t1), (k2, t2)| (t1, k1.as_bytes()).cmp(&(t2, k2.as_bytes())));
+        let members: Vec<serde_json::Value> = members.into_iter()
+            .map(|(k, t)| serde_json::json!({"member": k.to_string(), "add_time": t}))
+            .collect();
+        serde_json::json!({"members": members, "tombstones": tombstones_json(&self.del)})
     }
 
     pub fn save_snapshot<W: Write>(&self, dst: &mut SnapshotWriter<W>) -> Result<(), CstError> {
@@ -327,31 +418,31 @@ impl Set {
         for (k, (t, _)) in self.add.iter() {
             dst.write_integer(k.len() as i64)?;
             dst.write_bytes(k.as_bytes())?;
-            dst.write_integer(*t as i64)?;
+            dst.write_integer(t.uuid as i64)?;
         }
         dst.write_integer(self.del.len() as i64)?;
         for (k, t) in self.del.iter() {
             dst.write_integer(k.len() as i64)?;
             dst.write_bytes(k.as_bytes())?;
-            dst.write_integer(*t as i64)?;
+            dst.write_integer(t.uuid as i64)?;
         }
         Ok(())
     }
 
     pub async fn load_snapshot<T: AsyncRead + Unpin>(src: &mut SnapshotLoader<T>) -> Result<Self, CstError> {
         let mut s = Self::empty();
-        let add_cnt = src.read_integer().await? as usize;
+        let add_cnt = src.read_len().await?;
         for _ in 0..add_cnt {
-            let bl = src.read_integer().await? as usize;
+            let bl = src.read_len().await?;
             let k: Bytes = src.read_bytes(bl).await?.into();
-            let t = src.read_integer().await? as u64;
+            let t = Timestamp::new(src.read_integer().await? as u64, 0);
             let _ = s.add_member(k, t);
         }
-        let del_cnt = src.read_integer().await? as usize;
+        let del_cnt = src.read_len().await?;
         for _ in 0..del_cnt {
-            let bl = src.read_integer().await? as usize;
+            let bl = src.read_len().await?;
             let k: Bytes = src.read_bytes(bl).await?.into();
-            let t = src.read_integer().await? as u64;
+            let t = Timestamp::new(src.read_integer().await? as u64, 0);
             let _ = s.remove_member(&k, t);
         }
         Ok(s)
@@ -359,12 +450,12 @@ impl Set {
 }
 
 pub struct SetIter<'a> {
-    i: Iter<'a, Bytes, (u64, ())>,
+    i: Iter<'a, Bytes, (Timestamp, ())>,
     s: &'a Set,
 }
 
 impl<'a> Iterator for SetIter<'a> {
-    type Item = (&'a Bytes, u64);
+    type Item = (&'a Bytes, Timestamp);
 
     fn next(&mut self) -> Option<Self::Item> {
         while let Some((k, (t, _))) = self.i.next() {
@@ -380,12 +471,12 @@ impl<'a> Iterator for SetIter<'a> {
 }
 
 pub struct SetAllIter<'a> {
-    a: Iter<'a, Bytes, (u64, ())>,
-    d: Iter<'a, Bytes, u64>,
+    a: Iter<'a, Bytes, (Timestamp, ())>,
+    d: Iter<'a, Bytes, Timestamp>,
 }
 
 impl<'a> Iterator for SetAllIter<'a> {
-    type Item = (&'a Bytes, u64);
+    type Item = (&'a Bytes, Timestamp);
 
     fn next(&mut self) -> Option<Self::Item> {
         self.a.next().map(|(b, (u, _))| (b, *u)).or(self.d.next().map(|(b, u)| (b, *u)))
@@ -0,0 +1,26 @@
+use std::fmt::{Display, Formatter};
+
+// The time of a write in the crdts. Two nodes may generate the same uuid in the same millisecond,
+// so the uuids are ordered by the ids of the nodes which generated them if they are equal. It makes
+// the order a total one, and all the replicas resolve the conflicts in the same way no matter in
+// which order the writes arrive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct Timestamp {
+    pub uuid: u64,
+    pub node_id: u64,
+}
+
+impl Timestamp {
+    pub fn new(uuid: u64, node_id: u64) -> Self {
+        Timestamp{
+            uuid,
+            node_id,
+        }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}@{}", self.uuid, self.node_id)
+    }
+}
@@ -1,7 +1,8 @@
 use crate::Bytes;
 
-type VClock<T> = MiniMap<T>;
+pub type VClock<T> = MiniMap<T>;
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct MiniMap<T> {
     values: Vec<(u64, T)>
 }
@@ -35,6 +36,29 @@ impl<T> MiniMap<T> {
             Err(i) => self.values.insert(i, (k, v))
         }
     }
+
+    pub fn iter(&self) -> impl Iterator<Item=&(u64, T)> {
+        self.values.iter()
+    }
+}
+
+// the uuids of the nodes' writes we've seen, which only grow
+impl VClock<u64> {
+    pub fn observe(&mut self, k: u64, v: u64) {
+        if self.get(&k).map(|x| *x < v).unwrap_or(true) {
+            self.set(k, v);
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (k, v) in other.iter() {
+            self.observe(*k, *v);
+        }
+    }
+
+    pub fn uuid_of(&self, k: u64) -> u64 {
+        self.get(&k).cloned().unwrap_or_default()
+    }
 }
 
 type MultiValue = VClock<Bytes>;
@@ -1,45 +1,134 @@
-use std::collections::{HashMap, LinkedList};
+use std::cmp::max;
+use std::collections::{HashMap, HashSet, LinkedList};
 use std::io::Write;
+use std::time::Instant;
+
+use crc64::crc64;
+use indexmap::IndexMap;
 
 use crate::{Bytes, CstError};
 use crate::object::{Encoding, Object};
-use crate::snapshot::{SNAPSHOT_FLAG_DATAS, SNAPSHOT_FLAG_DELETES, SNAPSHOT_FLAG_EXPIRES, SnapshotWriter};
+use crate::crdt::timestamp::Timestamp;
+use crate::replica::merkle::MerkleTree;
+use crate::snapshot::{SNAPSHOT_FLAG_DATAS, SNAPSHOT_FLAG_DELETES, SNAPSHOT_FLAG_EXPIRES, SNAPSHOT_FLAG_SHADOWS, SnapshotWriter};
 
 const DB_INITIAL_SIZE: usize = 8096;
 
 pub struct DB {
-    data: HashMap<Bytes, Object>,
+    // the keys are never removed, so that their indexes are stable while the data is dumped incrementally
+    data: IndexMap<Bytes, Object>,
     expires: HashMap<Bytes, u64>, // key -> timestamp
     deletes: HashMap<Bytes, u64>,
     garbages: LinkedList<(Bytes, Option<Bytes>, u64)>, // (key, field/member, uuid)
+    // the objects of the keys written as another type somewhere else at the same time, one per type.
+    // the one written lately is in data, and the others are kept here and still replicated into, so that
+    // the replicas converge no matter in which order they receive the writes. The update times are used
+    // rather than the create times, as an object may be created by any of its writes which comes first.
+    shadows: HashMap<Bytes, Vec<Object>>,
+    // the number of the writes and merges of a type other than the key's
+    pub type_conflicts: u64,
+    // the latest tombstone collected, the deltas since an earlier time would miss it
+    collected_until: u64,
+    dumping: Option<IncrementalDump>,
+}
+
+// the progress of dumping the data incrementally. The objects are copied before they are modified
+// for the first time since the dump began, so that the snapshot is of the time when it began. The keys
+// added after it began are at the indexes from `end`, which are not dumped.
+struct IncrementalDump {
+    next: usize,
+    end: usize,
+    origins: HashMap<Bytes, Object>,
 }
 
 impl DB {
     pub fn empty() -> Self {
         Self{
-            data: HashMap::with_capacity(DB_INITIAL_SIZE),
+            data: IndexMap::with_capacity(DB_INITIAL_SIZE),
             expires: HashMap::new(),
             deletes: HashMap::new(),
             garbages: LinkedList::default(),
+            shadows: HashMap::new(),
+            type_conflicts: 0,
+            collected_until: 0,
+            dumping: None,
         }
     }
 
     pub fn add(&mut self, key: Bytes, value: Object) {
+        self.preserve(&key);
         self.data.insert(key, value);
     }
 
     pub fn merge_entry(&mut self, key: Bytes, value: Object) {
+        self.preserve(&key);
         match self.data.get_mut(&key) {
             None => {
                 self.data.insert(key, value);
+                return;
             },
             Some(o) => {
-                let en = value.enc.name();
-                if o.merge(value).is_err() {
-                    error!("Failed to merge key {} because there is a type conflict!, my type={}, other type={}", key.to_string(), o.enc.name(), en);
+                if o.enc.same_type(&value.enc) {
+                    let _ = o.merge(value);
+                } else {
+                    let _ = self.shadow(&key, value.enc.clone()).merge(value);
                 }
             },
         }
+        self.resolve(&key);
+    }
+
+    // the object of the key of the type of enc, which is created at t with enc if it's missing.
+    // if the key is of another type, it's an error to our clients, while the writes of our replicas
+    // go to the shadow of that type.
+    pub fn query_or_create(&mut self, key: &Bytes, t: Timestamp, enc: Encoding, replicated: bool) -> Result<&mut Object, CstError> {
+        match self.query(key, t.uuid).map(|o| o.enc.same_type(&enc)) {
+            None => {
+                self.data.insert(key.clone(), Object::new(enc, t, Timestamp::default()));
+                return Ok(self.data.get_mut(key).unwrap());
+            }
+            Some(true) => return Ok(self.data.get_mut(key).unwrap()),
+            Some(false) => {}
+        }
+        if !replicated {
+            return Err(CstError::InvalidType);
+        }
+        let o = self.shadow(key, enc);
+        if o.create_time == Timestamp::default() {
+            o.create_time = t;
+        }
+        Ok(o)
+    }
+
+    // the shadow of the key of the type of enc, an empty one is added if it's missing.
+    fn shadow(&mut self, key: &Bytes, enc: Encoding) -> &mut Object {
+        self.type_conflicts += 1;
+        let shadows = self.shadows.entry(key.clone()).or_default();
+        match shadows.iter().position(|o| o.enc.same_type(&enc)) {
+            Some(i) => &mut shadows[i],
+            None => {
+                let current = self.data.get(key).map(|o| o.enc.name()).unwrap_or("none");
+                warn!("The key {} is written as {} somewhere else while it's {} here", key.to_string(), enc.name(), current);
+                shadows.push(Object::new(enc, Timestamp::default(), Timestamp::default()));
+                shadows.last_mut().unwrap()
+            }
+        }
+    }
+
+    // the object written lately becomes the one of the key, the others are its shadows.
+    fn resolve(&mut self, key: &Bytes) {
+        let shadows = match self.shadows.get_mut(key) {
+            None => return,
+            Some(s) => s,
+        };
+        let o = match self.data.get_mut(key) {
+            None => return,
+            Some(o) => o,
+        };
+        let latest = shadows.iter_mut().max_by_key(|s| s.update_time).unwrap();
+        if latest.update_time > o.update_time {
+            std::mem::swap(latest, o);
+        }
     }
 
     // TODO
@@ -51,14 +140,20 @@ impl DB {
     // While if it's in data, but also in expires, and its expire time is smaller than t,
     // We insert it into deletes and also return it to the caller.
     pub fn query(&mut self, key: &Bytes, t: u64) -> Option<&mut Object> {
+        self.preserve(key);
+        if !self.shadows.is_empty() {
+            self.resolve(key);
+        }
         let o = match self.data.get_mut(key) {
             None => return None,
             Some(o) => o,
         };
         if let Some(expire_time) = self.expires.get(key) {
             if o.alive() && o.created_before(*expire_time) && *expire_time <= t {
-                o.delete_time = *expire_time;
-                o.updated_at(*expire_time);
+                // every node expires it at the same time, so the deletion is of none of them
+                let t = Timestamp::new(*expire_time, 0);
+                o.delete_time = t;
+                o.updated_at(t);
                 let _ = self.deletes.insert(key.clone(), *expire_time);
             }
         }
@@ -84,6 +179,7 @@ impl DB {
             if t > tombstone {
                 break;
             }
+            self.collected_until = max(self.collected_until, t);
             match field {
                 None => match self.deletes.get(&key) {
                     None => {},
@@ -94,18 +190,20 @@ impl DB {
                     }
                 }
                 Some(f) => {
+                    // the object is modified in place
+                    self.preserve(&key);
                     if let Some(v) = self.data.get_mut(&key) {
                         match &mut v.enc {
                             Encoding::LWWDict(dict) => {
                                 if let Some(rt) = dict.remove_time(&f) {
-                                    if rt < t {
+                                    if rt.uuid < t {
                                         dict.remove_actually(&f);
                                     }
                                 }
                             }
                             Encoding::LWWSet(set) => {
                                 if let Some(rt) = set.remove_time(&f) {
-                                    if rt < t {
+                                    if rt.uuid < t {
                                         set.remove_actually(&f);
                                     }
                                 }
@@ -120,37 +218,291 @@ impl DB {
 
     // FIXME
     pub fn dump<W: Write>(&self, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
-        let _ = w.write_byte(SNAPSHOT_FLAG_DATAS)?.write_integer(self.data.len() as i64)?;
+        let _ = w.write_section(SNAPSHOT_FLAG_DATAS, self.data.len())?;
         for (k, v) in self.data.iter() {
-            w.write_entry(k.as_bytes(), v)?;
+            w.write_item(|w| w.write_entry(k.as_bytes(), v))?;
+        }
+        self.dump_tombstones(w)
+    }
+
+    // the expires, the deletes and the shadows, which are small enough to be dumped at once.
+    pub fn dump_tombstones<W: Write>(&self, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
+        let _ = w.write_section(SNAPSHOT_FLAG_SHADOWS, self.shadows.values().map(|s| s.len()).sum())?;
+        for (k, shadows) in self.shadows.iter() {
+            for o in shadows.iter() {
+                w.write_item(|w| w.write_entry(k.as_bytes(), o))?;
+            }
         }
-        let _ = w.write_byte(SNAPSHOT_FLAG_EXPIRES)?.write_integer(self.expires.len() as i64)?;
+        let _ = w.write_section(SNAPSHOT_FLAG_EXPIRES, self.expires.len())?;
         for (k, v) in self.expires.iter() {
-            w.write_integer(k.len() as i64)?.write_bytes(k.as_bytes())?.write_integer(*v as i64)?;
+            w.write_item(|w| {
+                let _ = w.write_integer(k.len() as i64)?.write_bytes(k.as_bytes())?.write_integer(*v as i64)?;
+                Ok(())
+            })?;
         }
-        let _ = w.write_byte(SNAPSHOT_FLAG_DELETES)?.write_integer(self.deletes.len() as i64)?;
+        let _ = w.write_section(SNAPSHOT_FLAG_DELETES, self.deletes.len())?;
         for (k, v) in self.deletes.iter() {
-            w.write_integer(k.len() as i64)?.write_bytes(k.as_bytes())?.write_integer(*v as i64)?;
+            w.write_item(|w| {
+                let _ = w.write_integer(k.len() as i64)?.write_bytes(k.as_bytes())?.write_integer(*v as i64)?;
+                Ok(())
+            })?;
         }
         Ok(())
     }
 }
 
+/*
+ *  dumping what's changed since some time, for the replicas having the rest
+ */
+impl DB {
+    // none of the tombstones since the uuid is collected, so a delta since it is complete
+    pub fn delta_available(&self, uuid: u64) -> bool {
+        uuid >= self.collected_until
+    }
+
+    // the tombstones collected before we restarted are unknown, so are the deltas since an earlier time
+    pub fn forget_collected(&mut self, uuid: u64) {
+        self.collected_until = max(self.collected_until, uuid);
+    }
+
+    // the keys existing now are scanned for the objects written after the uuid.
+    pub fn begin_delta_dump(&self, uuid: u64) -> DeltaDump {
+        DeltaDump{
+            since: uuid,
+            next: 0,
+            end: self.data.len(),
+        }
+    }
+
+    // scan the keys until the deadline, the objects written after the uuid are dumped as a section, each of which
+    // only has the parts written since then. the objects written during the dump are dumped as they are then,
+    // which is fine as the writes after the dump began are sent to the replica after it anyway.
+    // returns true if all the keys have been scanned.
+    pub fn dump_delta_incrementally<W: Write>(&self, d: &mut DeltaDump, w: &mut SnapshotWriter<W>, deadline: Instant) -> Result<bool, CstError> {
+        let mut deltas = vec![];
+        while d.next < d.end {
+            let (k, o) = self.data.get_index(d.next).unwrap();
+            d.next += 1;
+            if let Some(delta) = o.delta_since(d.since) {
+                deltas.push((k, delta));
+            }
+            // checking the time is not cheap, so we do it every 64 keys
+            if d.next & 63 == 0 && Instant::now() >= deadline {
+                break;
+            }
+        }
+        if !deltas.is_empty() {
+            let _ = w.write_section(SNAPSHOT_FLAG_DATAS, deltas.len())?;
+            for (k, delta) in deltas.iter() {
+                w.write_item(|w| w.write_entry(k.as_bytes(), delta))?;
+            }
+        }
+        Ok(d.next >= d.end)
+    }
+
+    // the shadows, the expires and the tombstones of the delta, which are small enough to be dumped at once.
+    pub fn dump_delta_tombstones<W: Write>(&self, d: &DeltaDump, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
+        let uuid = d.since;
+        let shadows: Vec<(&Bytes, Object)> = self.shadows.iter()
+            .flat_map(|(k, s)| s.iter().filter_map(move |o| o.delta_since(uuid).map(|d| (k, d))))
+            .collect();
+        let _ = w.write_section(SNAPSHOT_FLAG_SHADOWS, shadows.len())?;
+        for (k, d) in shadows.iter() {
+            w.write_item(|w| w.write_entry(k.as_bytes(), d))?;
+        }
+        let _ = w.write_section(SNAPSHOT_FLAG_EXPIRES, self.expires.len())?;
+        for (k, v) in self.expires.iter() {
+            w.write_item(|w| {
+                let _ = w.write_integer(k.len() as i64)?.write_bytes(k.as_bytes())?.write_integer(*v as i64)?;
+                Ok(())
+            })?;
+        }
+        let deletes: Vec<(&Bytes, &u64)> = self.deletes.iter().filter(|(_, t)| **t > uuid).collect();
+        let _ = w.write_section(SNAPSHOT_FLAG_DELETES, deletes.len())?;
+        for (k, v) in deletes {
+            w.write_item(|w| {
+                let _ = w.write_integer(k.len() as i64)?.write_bytes(k.as_bytes())?.write_integer(*v as i64)?;
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// the progress of dumping what's written since a uuid, by the indexes of the keys which are stable
+pub struct DeltaDump {
+    since: u64,
+    next: usize,
+    end: usize,
+}
+
+impl DeltaDump {
+    pub fn since(&self) -> u64 {
+        self.since
+    }
+}
+
+/*
+ *  comparing the data with a replica's
+ */
+impl DB {
+    // the digests of the objects alive, except those written after the horizon which may be on the way to the replica
+    pub fn merkle_tree(&self, horizon: u64) -> MerkleTree {
+        let mut tree = MerkleTree::default();
+        for (k, o) in self.data.iter() {
+            if o.alive() && o.update_time.uuid <= horizon {
+                tree.add(k.as_bytes(), o.digest());
+            }
+        }
+        tree.seal();
+        tree
+    }
+
+    // the objects in the buckets, including the dead ones whose deletions the replica may have missed
+    pub fn bucket_objects(&self, buckets: &[usize]) -> Vec<(&Bytes, &Object)> {
+        let buckets: HashSet<usize> = buckets.iter().cloned().collect();
+        self.data.iter().filter(|(k, _)| buckets.contains(&MerkleTree::bucket(k.as_bytes()))).collect()
+    }
+
+    // the digest of the data as clients see it at t, the nodes having the same writes have the same one no matter
+    // in which order the keys are stored. The expired keys and the deleted ones are left out, 0 if there's none.
+    pub fn digest(&self, t: u64) -> u64 {
+        self.data.iter().fold(0u64, |h, (k, o)| h.wrapping_add(self.object_digest(k, o, t)))
+    }
+
+    pub fn key_digest(&self, key: &Bytes, t: u64) -> u64 {
+        match self.data.get(key) {
+            None => 0,
+            Some(o) => self.object_digest(key, o, t),
+        }
+    }
+
+    // the object is seen as `query` returns it, but nothing is changed: the latest of the shadows
+    // isn't swapped in, and the expired one isn't deleted.
+    fn object_digest(&self, key: &Bytes, o: &Object, t: u64) -> u64 {
+        let o = match self.shadows.get(key).and_then(|s| s.iter().max_by_key(|s| s.update_time)) {
+            Some(latest) if latest.update_time > o.update_time => latest,
+            _ => o,
+        };
+        let expired = match self.expires.get(key) {
+            Some(expire_time) => o.created_before(*expire_time) && *expire_time <= t,
+            None => false,
+        };
+        if o.alive() && !expired {
+            crc64(o.digest(), key.as_bytes())
+        } else {
+            0
+        }
+    }
+
+    // merge the object of a replica which differs from ours, returns whether ours is changed by it
+    pub fn repair(&mut self, key: Bytes, value: Object) -> bool {
+        let before = self.data.get(&key).map(|o| o.digest());
+        self.merge_entry(key.clone(), value);
+        self.data.get(&key).map(|o| o.digest()) != before
+    }
+}
+
+/*
+ *  dumping the data incrementally in the main thread
+ */
+impl DB {
+    // write the header of the data section, the keys existing now are to be dumped.
+    pub fn begin_incremental_dump<W: Write>(&mut self, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
+        let _ = w.write_section(SNAPSHOT_FLAG_DATAS, self.data.len())?;
+        self.dumping = Some(IncrementalDump{
+            next: 0,
+            end: self.data.len(),
+            origins: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    // dump the keys until the deadline, returns true if all of them have been dumped.
+    pub fn dump_incrementally<W: Write>(&mut self, w: &mut SnapshotWriter<W>, deadline: Instant) -> Result<bool, CstError> {
+        let d = match &mut self.dumping {
+            None => return Ok(true),
+            Some(d) => d,
+        };
+        while d.next < d.end {
+            let (key, o) = self.data.get_index(d.next).unwrap();
+            d.next += 1;
+            match d.origins.remove(key) {
+                Some(o) => w.write_item(|w| w.write_entry(key.as_bytes(), &o))?,
+                None => w.write_item(|w| w.write_entry(key.as_bytes(), o))?,
+            }
+            // checking the time is not cheap, so we do it every 64 keys
+            if d.next % 64 == 0 && Instant::now() >= deadline {
+                return Ok(false);
+            }
+        }
+        self.dumping = None;
+        Ok(true)
+    }
+
+    pub fn abort_incremental_dump(&mut self) {
+        self.dumping = None;
+    }
+
+    // copy the object if it's going to be dumped but not dumped yet
+    #[inline]
+    fn preserve(&mut self, key: &Bytes) {
+        if let Some(d) = &mut self.dumping {
+            if let Some((i, _, o)) = self.data.get_full(key) {
+                if i >= d.next && i < d.end && !d.origins.contains_key(key) {
+                    d.origins.insert(key.clone(), o.clone());
+                }
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::Bytes;
     use crate::db::DB;
     use crate::object::{Encoding, Object};
+    use crate::crdt::timestamp::Timestamp;
 
     #[test]
     fn test_db() {
         let mut db = DB::empty();
-        let (t1, t2, t3, t4, t5) = (1, 2, 3, 4, 5);
+        let (t1, t2, t3, t4) = (1, 2, 3, 4);
         let (k, v) = (Bytes::from("k1"), Bytes::from("v1"));
-        db.add(k.clone(), Object::new(Encoding::Bytes(v.clone()), t2, 0));
-        db.expire_at(&k, t2);
-        assert!(db.query(&k, t1).is_some());
-        assert!(db.query(&k, t2).is_some());
-        //assert!(db.query(&k, t3).is_none());
+        db.add(k.clone(), Object::new(Encoding::Bytes(v.clone()), Timestamp::new(t2, 1), Timestamp::default()));
+        db.expire_at(&k, t3);
+        assert!(db.query(&k, t1).unwrap().alive());
+        assert!(db.query(&k, t2).unwrap().alive());
+        // an expired key is still there as a deleted one
+        assert!(!db.query(&k, t3).unwrap().alive());
+        assert!(!db.query(&k, t4).unwrap().alive());
+    }
+
+    #[test]
+    fn test_digest() {
+        let (mut a, mut b) = (DB::empty(), DB::empty());
+        assert_eq!(a.digest(0), 0);
+        let keys: Vec<Bytes> = (0..100).map(|i| Bytes::from(format!("k{}", i))).collect();
+        for (i, k) in keys.iter().enumerate() {
+            let o = Object::new(Encoding::Bytes(k.clone()), Timestamp::new(i as u64 + 1, 1), Timestamp::default());
+            a.add(k.clone(), o);
+            b.add(keys[99 - i].clone(), Object::new(Encoding::Bytes(keys[99 - i].clone()), Timestamp::new(100 - i as u64, 1), Timestamp::default()));
+        }
+        assert_eq!(a.digest(200), b.digest(200));
+        assert_eq!(a.key_digest(&keys[0], 200), b.key_digest(&keys[0], 200));
+        assert_ne!(a.key_digest(&keys[0], 200), a.key_digest(&keys[1], 200));
+
+        // an expired key is as a missing one
+        let missing = Bytes::from("missing");
+        assert_eq!(a.key_digest(&missing, 200), 0);
+        let (before, k0) = (a.digest(200), a.key_digest(&keys[0], 200));
+        a.expire_at(&keys[0], 150);
+        assert_eq!(a.key_digest(&keys[0], 100), k0);
+        assert_eq!(a.key_digest(&keys[0], 200), 0);
+        assert_eq!(a.digest(200), before.wrapping_sub(k0));
+        assert_ne!(a.digest(200), b.digest(200));
+        // the digest doesn't delete the expired key
+        assert!(a.deletes.is_empty());
+        assert!(a.query(&keys[0], 100).unwrap().alive());
     }
 }
\ No newline at end of file
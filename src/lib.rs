@@ -47,6 +47,9 @@ pub mod cmd;
 pub mod conf;
 pub mod conn;
 pub mod snapshot;
+pub mod aof;
+pub mod clock;
+pub mod rdb;
 pub mod db;
 pub mod type_set;
 pub mod type_hash;
@@ -156,6 +159,10 @@ pub enum CstError {
     InvalidRequestMsg(String),
     #[fail(display = "invalid data in snapshot at offset {}", _0)]
     InvalidSnapshot(usize),
+    #[fail(display = "the snapshot of version {} is not supported", _0)]
+    IncompatibleSnapshot(String),
+    #[fail(display = "the replica {} speaks the replication protocol of version {} which is not supported", _0, _1)]
+    IncompatibleReplica(String, u64),
     #[fail(display = "the connection with {} is broken", _0)]
     ConnBroken(String),
     #[fail(display = "io error {}", _0)]
@@ -172,6 +179,16 @@ pub enum CstError {
     ReplicaNodeAlreadyExist,
     #[fail(display = "invalid checksum of snapshot")]
     InvalidSnapshotChecksum,
+    #[fail(display = "invalid data in repl backlog at offset {}", _0)]
+    InvalidBacklog(u64),
+    #[fail(display = "invalid data in aof at offset {}", _0)]
+    InvalidAof(u64),
+    #[fail(display = "invalid data in rdb at offset {}", _0)]
+    InvalidRdb(u64),
+    #[fail(display = "the rdb {} is not supported", _0)]
+    IncompatibleRdb(String),
+    #[fail(display = "invalid checksum of rdb")]
+    InvalidRdbChecksum,
 }
 
 impl From<Error> for CstError {
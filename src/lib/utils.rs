@@ -58,4 +58,44 @@ pub fn merge_sorted_vec(a: &mut VecDeque<u64>, b: &mut VecDeque<u64>) -> VecDequ
         new_value.push_back(v);
     }
     new_value
-}
\ No newline at end of file
+}
+
+// glob-style matching, supporting `*`, `?`, `[abc]`, `[^a-z]` and `\` escaping
+pub fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.first() {
+        None => s.is_empty(),
+        Some(b'*') => (0..=s.len()).any(|i| glob_match(&pattern[1..], &s[i..])),
+        Some(b'?') => !s.is_empty() && glob_match(&pattern[1..], &s[1..]),
+        Some(b'[') => {
+            let c = match s.first() {
+                None => return false,
+                Some(c) => *c,
+            };
+            let mut i = 1;
+            let negative = pattern.get(i) == Some(&b'^');
+            if negative {
+                i += 1;
+            }
+            let mut found = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    i += 1;
+                    found |= pattern[i] == c;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+                    found |= pattern[i] <= c && c <= pattern[i + 2];
+                    i += 2;
+                } else {
+                    found |= pattern[i] == c;
+                }
+                i += 1;
+            }
+            if i >= pattern.len() {
+                // no closing bracket, match it literally
+                return c == b'[' && glob_match(&pattern[1..], &s[1..]);
+            }
+            found != negative && glob_match(&pattern[i + 1..], &s[1..])
+        }
+        Some(b'\\') if pattern.len() > 1 => s.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &s[1..]),
+        Some(c) => s.first() == Some(c) && glob_match(&pattern[1..], &s[1..]),
+    }
+}
@@ -7,12 +7,14 @@ use crate::crdt::lwwhash::{Set, Dict};
 use crate::resp::Message;
 use crate::snapshot::{SnapshotLoader, SnapshotWriter};
 use tokio::io::AsyncRead;
+use crate::crdt::timestamp::Timestamp;
+use crc64::crc64;
 
 #[derive(Debug, Clone)]
 pub struct Object {
-    pub create_time: u64,
-    pub update_time: u64,
-    pub delete_time: u64,
+    pub create_time: Timestamp,
+    pub update_time: Timestamp,
+    pub delete_time: Timestamp,
     pub enc: Encoding,
 }
 
@@ -22,70 +24,116 @@ const OBJECT_ENC_DICT: u8 = 4;
 const OBJECT_ENC_SET: u8 = 5;
 
 impl Object {
-    pub fn new(enc: Encoding, ct: u64, dt: u64) -> Self {
+    pub fn new(enc: Encoding, ct: Timestamp, dt: Timestamp) -> Self {
         Object{
             create_time: ct,
-            update_time: 0,
+            update_time: Timestamp::default(),
             delete_time: dt,
             enc,
         }
     }
 
     #[inline]
-    pub fn updated_at(&mut self, uuid: u64) {
-        if self.update_time < uuid {
-            self.update_time = uuid;
+    pub fn updated_at(&mut self, t: Timestamp) {
+        if self.update_time < t {
+            self.update_time = t;
         }
         if self.create_time < self.delete_time {
-            if uuid < self.create_time {
+            if t < self.create_time {
 
-            } else if self.create_time <= uuid && uuid < self.delete_time {
+            } else if self.create_time <= t && t < self.delete_time {
 
-            } else {  // uuid >= self.delete_time
-                self.create_time = uuid; // created again
+            } else {  // t >= self.delete_time
+                self.create_time = t; // created again
             }
         }
     }
 
+    // it's alive if it's created again or updated after it was deleted lately. the update may be known only
+    // after a merge, in which the create time is not, as it's of the first update following the deletion.
+    // it's created at the time of its deletion if the deletion is the first write we receive of it.
     #[inline]
     pub fn alive(&self) -> bool {
-        self.create_time >= self.delete_time
+        self.delete_time == Timestamp::default() || self.create_time > self.delete_time || self.update_time > self.delete_time
     }
 
     #[inline]
-    pub fn created_before(&self, t: u64) -> bool {
-        self.create_time < t
+    pub fn created_before(&self, uuid: u64) -> bool {
+        self.create_time.uuid < uuid
     }
 
-    // apply the data in another object into the current one.
+    // apply the data in another object into the current one. It's the join of the two states, so that
+    // the result is the same no matter in which order the objects are merged, or how many times.
     // if an object was once of an encoding, and be deleted(softly) later, it still has that encoding.
     // that says, we avoid type conflicts even the user believes an older entry has been deleted.
     pub fn merge(&mut self, other: Object) -> Result<(), ()> {
-        let (my_ct, my_dt) = (self.create_time, self.delete_time);
-        let (his_ct, his_dt) = (other.create_time, other.delete_time);
-        let (my_ut, his_ut) = (self.update_time, other.update_time);
+        let (his_ct, his_ut, his_dt) = (other.create_time, other.update_time, other.delete_time);
         match (&mut self.enc, other.enc) {
             (Encoding::Counter(c), Encoding::Counter(oc)) => c.merge(*oc),
             (Encoding::Bytes(b), Encoding::Bytes(ob)) => {
-                // TODO
-                if my_ct < his_ct {
-                    b.0 = ob.0.clone();
+                // the value is of the latest update. A deletion is an update too, which doesn't change the value,
+                // but the value is not visible until it's set again then.
+                if (his_ut, ob.as_bytes()) > (self.update_time, b.as_bytes()) {
+                    *b = ob;
                 }
-                self.create_time = max(my_ct, his_ct);
-                self.delete_time = max(my_dt, his_dt);
-                self.update_time = max(my_ut, his_ut);
             }
             (Encoding::LWWDict(d), Encoding::LWWDict(od)) => d.merge(*od),
             (Encoding::LWWSet(s), Encoding::LWWSet(os)) => s.merge(*os),
             _ => return Err(())
         }
+        self.create_time = max(self.create_time, his_ct);
+        self.update_time = max(self.update_time, his_ut);
+        self.delete_time = max(self.delete_time, his_dt);
+        // the elements added before the whole object was deleted are deleted with it
+        match &mut self.enc {
+            Encoding::LWWDict(d) => d.remove_before(self.delete_time),
+            Encoding::LWWSet(s) => s.remove_before(self.delete_time),
+            Encoding::Counter(_) | Encoding::Bytes(_) => {}
+        }
         Ok(())
     }
 
+    // the part of the object written after the uuid, or None if it's not written since then.
+    // merging it into the object a replica had at the uuid gives what we have now.
+    pub fn delta_since(&self, uuid: u64) -> Option<Object> {
+        if max(self.create_time, max(self.update_time, self.delete_time)).uuid <= uuid {
+            return None;
+        }
+        let enc = match &self.enc {
+            Encoding::Counter(c) => Encoding::from(c.delta_since(uuid)),
+            Encoding::Bytes(b) => Encoding::from(b.clone()),
+            Encoding::LWWSet(s) => Encoding::from(s.delta_since(uuid)),
+            Encoding::LWWDict(d) => Encoding::from(d.delta_since(uuid)),
+        };
+        Some(Object{
+            create_time: self.create_time,
+            update_time: self.update_time,
+            delete_time: self.delete_time,
+            enc,
+        })
+    }
+
+    // the same on the replicas having the same writes of the object, in whatever order they're merged.
+    // the tombstones of the members are left out, as they're collected at different times on the replicas.
+    // so is the create time, which is of the first write a replica receives after the deletion.
+    pub fn digest(&self) -> u64 {
+        let hash = |xs: &[u64]| xs.iter().fold(0, |h, x| crc64(h, &x.to_le_bytes()));
+        let enc = match &self.enc {
+            Encoding::Bytes(b) => crc64(0, b.as_bytes()),
+            Encoding::Counter(c) => c.iter().fold(0u64, |h, (node_id, (v, t))| h.wrapping_add(hash(&[node_id, v as u64, t]))),
+            Encoding::LWWSet(s) => s.iter().fold(0u64, |h, (m, t)| h.wrapping_add(crc64(hash(&[t.uuid, t.node_id]), m.as_bytes()))),
+            Encoding::LWWDict(d) => d.iter().fold(0u64, |h, (k, (t, v))| {
+                h.wrapping_add(crc64(crc64(hash(&[t.uuid, t.node_id, k.len() as u64]), k.as_bytes()), v.as_bytes()))
+            }),
+        };
+        let (ut, dt) = (self.update_time, self.delete_time);
+        hash(&[self.alive() as u64, ut.uuid, ut.node_id, dt.uuid, dt.node_id, enc])
+    }
+
     pub fn save_snapshot<W: Write>(&self, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
-        w.write_integer(self.create_time as i64)?;
-        w.write_integer(self.update_time as i64)?;
-        w.write_integer(self.delete_time as i64)?;
+        w.write_integer(self.create_time.uuid as i64)?;
+        w.write_integer(self.update_time.uuid as i64)?;
+        w.write_integer(self.delete_time.uuid as i64)?;
         match &self.enc {
             Encoding::Counter(i) => {
                 w.write_byte(OBJECT_ENC_COUNTER)?;
@@ -93,7 +141,7 @@ impl Object {
             }
             Encoding::Bytes(b) => {
                 w.write_byte(OBJECT_ENC_BYTES)?;
-                let _ = w.write_bytes(b.as_bytes())?;
+                let _ = w.write_integer(b.len() as i64)?.write_bytes(b.as_bytes())?;
                 Ok(())
             }
             Encoding::LWWSet(s) => {
@@ -112,8 +160,8 @@ impl Object {
         let enc = match r.read_byte().await? {
             OBJECT_ENC_COUNTER => Encoding::from(Counter::load_snapshot(r).await?),
             OBJECT_ENC_BYTES => {
-                let s = r.read_integer().await?;
-                let d = r.read_bytes(s as usize).await?;
+                let s = r.read_len().await?;
+                let d = r.read_bytes(s).await?;
                 Encoding::from(Bytes::from(d))
             }
             OBJECT_ENC_SET => Encoding::from(Set::load_snapshot(r).await?),
@@ -121,13 +169,37 @@ impl Object {
             _ => return Err(CstError::InvalidType),
         };
         Ok(Object{
-            create_time: ct,
-            update_time: mt,
-            delete_time: dt,
+            create_time: Timestamp::new(ct, 0),
+            update_time: Timestamp::new(mt, 0),
+            delete_time: Timestamp::new(dt, 0),
             enc
         })
     }
 
+    // the node ids of the timestamps, which follow the object in a snapshot only if it's of format 2 or later,
+    // the older versions skip them as the fields they don't know.
+    pub fn save_nodes<W: Write>(&self, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
+        w.write_integer(self.create_time.node_id as i64)?;
+        w.write_integer(self.update_time.node_id as i64)?;
+        w.write_integer(self.delete_time.node_id as i64)?;
+        match &self.enc {
+            Encoding::LWWSet(s) => s.save_nodes(w),
+            Encoding::LWWDict(d) => d.save_nodes(w),
+            Encoding::Counter(_) | Encoding::Bytes(_) => Ok(()),
+        }
+    }
+
+    pub async fn load_nodes<T: AsyncRead + Unpin>(&mut self, r: &mut SnapshotLoader<T>) -> Result<(), CstError> {
+        self.create_time.node_id = r.read_integer().await? as u64;
+        self.update_time.node_id = r.read_integer().await? as u64;
+        self.delete_time.node_id = r.read_integer().await? as u64;
+        match &mut self.enc {
+            Encoding::LWWSet(s) => s.load_nodes(r).await,
+            Encoding::LWWDict(d) => d.load_nodes(r).await,
+            Encoding::Counter(_) | Encoding::Bytes(_) => Ok(()),
+        }
+    }
+
     pub fn describe(&self) -> Message {
         let (t, m) = match &self.enc {
             Encoding::Counter(g) => ("counter", g.describe()),
@@ -143,6 +215,23 @@ impl Object {
             m
         ])
     }
+
+    // the whole object including the crdt internals, which is used for inspecting snapshots
+    pub fn to_json(&self) -> serde_json::Value {
+        let (t, v) = match &self.enc {
+            Encoding::Counter(g) => ("counter", g.to_json()),
+            Encoding::Bytes(s) => ("bytes", serde_json::Value::String(s.to_string())),
+            Encoding::LWWSet(t) => ("lwwset", t.to_json()),
+            Encoding::LWWDict(t) => ("lwwdict", t.to_json()),
+        };
+        serde_json::json!({
+            "type": t,
+            "create_time": self.create_time,
+            "update_time": self.update_time,
+            "delete_time": self.delete_time,
+            "value": v,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -163,6 +252,10 @@ impl Encoding {
         }
     }
 
+    pub fn same_type(&self, other: &Encoding) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other)
+    }
+
     pub fn as_counter(&self) -> Result<&Counter, CstError> {
         match self {
             Encoding::Counter(c) => Ok(c),
@@ -228,4 +321,265 @@ impl From<Dict> for Encoding {
     fn from(c: Dict) -> Self {
         Encoding::LWWDict(Box::new(c))
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use std::cmp::max;
+
+    use crate::Bytes;
+    use crate::crdt::lwwhash::{Dict, Set};
+    use crate::crdt::timestamp::Timestamp;
+    use crate::db::DB;
+    use crate::object::{Encoding, Object};
+    use crate::snapshot::{SnapshotLoader, SnapshotWriter};
+    use crate::type_counter::Counter;
+    use tokio::macros::support::thread_rng_n;
+
+    fn ts(uuid: u64, node_id: u64) -> Timestamp {
+        Timestamp::new(uuid, node_id)
+    }
+
+    fn deleted(mut o: Object, t: Timestamp) -> Object {
+        match &mut o.enc {
+            Encoding::LWWSet(s) => s.remove_before(t),
+            Encoding::LWWDict(d) => d.remove_before(t),
+            _ => {}
+        }
+        o.delete_time = t;
+        o.update_time = t;
+        o
+    }
+
+    fn merged(objects: &[&Object]) -> serde_json::Value {
+        let mut o = objects[0].clone();
+        for other in objects[1..].iter() {
+            o.merge((*other).clone()).unwrap();
+        }
+        o.to_json()
+    }
+
+    // the merge of the replicas is the same in every order and grouping, and merging one again changes nothing
+    fn check_join(replicas: &[Object; 3]) -> Object {
+        let [a, b, c] = replicas;
+        let expected = merged(&[a, b, c]);
+        for order in [[a, c, b], [b, a, c], [b, c, a], [c, a, b], [c, b, a]].iter() {
+            assert_eq!(merged(order), expected);
+        }
+        let mut bc = b.clone();
+        bc.merge(c.clone()).unwrap();
+        assert_eq!(merged(&[a, &bc]), expected);
+        let mut all = a.clone();
+        for o in [b, c, a, b, c].iter() {
+            all.merge((*o).clone()).unwrap();
+        }
+        assert_eq!(all.to_json(), expected);
+        all
+    }
+
+    #[test]
+    fn test_merge_bytes() {
+        let a = Object::new(Encoding::from(Bytes::from("a")), ts(10, 1), Timestamp::default());
+        let mut b = a.clone();
+        b.enc = Encoding::from(Bytes::from("b"));
+        b.updated_at(ts(11, 2));
+        let c = deleted(a.clone(), ts(12, 3));
+        let o = check_join(&[a.clone(), b.clone(), c.clone()]);
+        assert!(!o.alive());
+        assert_eq!(o.update_time, ts(12, 3));
+
+        // set after the deletion which was unknown
+        let mut d = b.clone();
+        d.enc = Encoding::from(Bytes::from("d"));
+        d.updated_at(ts(13, 1));
+        let o = check_join(&[a, c, d]);
+        assert!(o.alive());
+        match o.enc {
+            Encoding::Bytes(b) => assert_eq!(b, Bytes::from("d")),
+            _ => panic!("should be bytes"),
+        }
+    }
+
+    #[test]
+    fn test_merge_counter() {
+        let mut counters = vec![];
+        for (node, value, uuid) in [(1, 5, 10), (2, 3, 11), (1, 7, 12)].iter() {
+            let mut c = Counter::default();
+            c.change(*node, *value, *uuid);
+            counters.push(Object::new(Encoding::from(c), ts(10, 1), Timestamp::default()));
+        }
+        let o = check_join(&[counters[0].clone(), counters[1].clone(), counters[2].clone()]);
+        assert_eq!(o.enc.as_counter().unwrap().get(), 10);
+    }
+
+    #[test]
+    fn test_merge_set() {
+        let mut s = Set::empty();
+        s.add_members(&["m1".into(), "m2".into()], ts(10, 1));
+        let a = Object::new(Encoding::from(s), ts(10, 1), Timestamp::default());
+        let mut b = a.clone();
+        b.enc.as_mut_set().unwrap().remove_member(&"m1".into(), ts(11, 2));
+        b.enc.as_mut_set().unwrap().add_member("m3".into(), ts(11, 2));
+        b.updated_at(ts(11, 2));
+        let mut c = deleted(a.clone(), ts(12, 3));
+        c.enc.as_mut_set().unwrap().add_member("m4".into(), ts(13, 3));
+        c.updated_at(ts(13, 3));
+        let o = check_join(&[a, b, c]);
+        assert!(o.alive());
+        let members: Vec<&Bytes> = o.enc.as_set().unwrap().iter().map(|(m, _)| m).collect();
+        assert_eq!(members, vec![&Bytes::from("m4")]);
+        assert_eq!(o.enc.as_set().unwrap().size(), 1);
+    }
+
+    #[test]
+    fn test_merge_dict() {
+        let mut d = Dict::empty();
+        d.set_field("f1".into(), "v1".into(), ts(10, 1));
+        d.set_field("f2".into(), "v2".into(), ts(10, 1));
+        let a = Object::new(Encoding::from(d), ts(10, 1), Timestamp::default());
+        let mut b = a.clone();
+        b.enc.as_mut_dict().unwrap().set_field("f1".into(), "b".into(), ts(11, 2));
+        b.updated_at(ts(11, 2));
+        let mut c = a.clone();
+        c.enc.as_mut_dict().unwrap().set_field("f1".into(), "c".into(), ts(11, 3));
+        c.enc.as_mut_dict().unwrap().del_field(&"f2".into(), ts(11, 3));
+        c.updated_at(ts(11, 3));
+        let o = check_join(&[a.clone(), b, c]);
+        let d = o.enc.as_dict().unwrap();
+        assert_eq!(d.get(&"f1".into()), Some(&Bytes::from("c")));
+        assert_eq!(d.get(&"f2".into()), None);
+
+        // a dict met in the snapshot of another cluster
+        let mut db = DB::empty();
+        db.merge_entry("d".into(), a.clone());
+        db.merge_entry("d".into(), deleted(a, ts(12, 2)));
+        let o = db.query(&"d".into(), 12 << 22).unwrap();
+        assert!(!o.alive());
+        assert_eq!(o.enc.as_dict().unwrap().iter().count(), 0);
+    }
+
+    const NODES: usize = 3;
+
+    // a write made by a node on its own replica
+    fn random_write(o: &mut Object, t: Timestamp) -> String {
+        let member = Bytes::from(format!("m{}", thread_rng_n(4)));
+        let value = Bytes::from(format!("v{}", thread_rng_n(4)));
+        let op = thread_rng_n(3);
+        if op == 2 && thread_rng_n(2) == 0 {
+            *o = deleted(o.clone(), t);
+            return format!("del@{}", t);
+        }
+        match &mut o.enc {
+            Encoding::Counter(c) => {
+                let delta = thread_rng_n(10) as i64 - 5;
+                c.change(t.node_id, delta, t.uuid);
+                o.updated_at(t);
+                return format!("incrby {}@{}", delta, t);
+            }
+            Encoding::Bytes(_) => o.enc = Encoding::from(value.clone()),
+            Encoding::LWWSet(s) => if op == 0 {
+                s.add_member(member.clone(), t);
+            } else {
+                s.remove_member(&member, t);
+            },
+            Encoding::LWWDict(d) => if op == 0 {
+                d.set_field(member.clone(), value.clone(), t);
+            } else {
+                d.del_field(&member, t);
+            },
+        }
+        o.updated_at(t);
+        format!("{} {} {}@{}", op, member.to_string(), value.to_string(), t)
+    }
+
+    // the replicas of the nodes after a random history of writes made by them and merges between them.
+    // the uuid of a node's write is greater than those of the writes it has seen, but the nodes which
+    // haven't seen each other's writes may make them at the same uuid.
+    fn random_replicas(origin: &Object) -> ([Object; NODES], Vec<String>) {
+        let mut replicas = [origin.clone(), origin.clone(), origin.clone()];
+        let mut clocks = [origin.update_time.uuid; NODES];
+        let mut history = vec![];
+        for _ in 0..thread_rng_n(40) {
+            let node = thread_rng_n(NODES as u32) as usize;
+            if thread_rng_n(3) == 0 {
+                let from = thread_rng_n(NODES as u32) as usize;
+                let other = replicas[from].clone();
+                replicas[node].merge(other).unwrap();
+                clocks[node] = max(clocks[node], clocks[from]);
+                history.push(format!("node {} merges node {}", node + 1, from + 1));
+            } else {
+                clocks[node] += 1;
+                let t = ts(clocks[node], node as u64 + 1);
+                history.push(format!("node {} {}", node + 1, random_write(&mut replicas[node], t)));
+            }
+        }
+        (replicas, history)
+    }
+
+    fn reload(o: &Object) -> Object {
+        let mut w = SnapshotWriter::new(1024, vec![]);
+        w.write_entry(b"k", o).unwrap();
+        w.flush().unwrap();
+        let buf = w.get_mut().clone();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut r = SnapshotLoader::new(buf.as_slice());
+            r.read_entry(Some(buf.len())).await.unwrap().1
+        })
+    }
+
+    #[test]
+    fn test_random_merges() {
+        let t = ts(10, 1);
+        let origins = vec![
+            Object::new(Encoding::from(Counter::default()), t, Timestamp::default()),
+            Object::new(Encoding::from(Bytes::from("v")), t, Timestamp::default()),
+            Object::new(Encoding::from(Set::empty()), t, Timestamp::default()),
+            Object::new(Encoding::from(Dict::empty()), t, Timestamp::default()),
+        ];
+        for _ in 0..100 {
+            for origin in origins.iter() {
+                let mut origin = origin.clone();
+                origin.update_time = t;
+                let (replicas, history) = random_replicas(&origin);
+                let joined = std::panic::catch_unwind(|| check_join(&replicas))
+                    .unwrap_or_else(|_| panic!("the replicas don't converge after {:?}", history));
+                for o in replicas.iter().chain(std::iter::once(&joined)) {
+                    assert_eq!(reload(o).to_json(), o.to_json(), "{:?}", history);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_random_deltas() {
+        let t = ts(10, 1);
+        let origins = vec![
+            Object::new(Encoding::from(Counter::default()), t, Timestamp::default()),
+            Object::new(Encoding::from(Bytes::from("v")), t, Timestamp::default()),
+            Object::new(Encoding::from(Set::empty()), t, Timestamp::default()),
+            Object::new(Encoding::from(Dict::empty()), t, Timestamp::default()),
+        ];
+        for _ in 0..100 {
+            for origin in origins.iter() {
+                let (replicas, mut history) = random_replicas(origin);
+                // node 1 writes more after the other nodes have its replica at the uuid
+                let old = &replicas[0];
+                let since = max(old.create_time, max(old.update_time, old.delete_time)).uuid;
+                let mut o = old.clone();
+                for i in 0..thread_rng_n(10) {
+                    history.push(format!("node 1 {}", random_write(&mut o, ts(since + 1 + i as u64, 1))));
+                }
+                let mut others = replicas[1].clone();
+                others.merge(replicas[2].clone()).unwrap();
+                let mut expected = others.clone();
+                expected.merge(old.clone()).unwrap();
+                let mut delta = expected.clone();
+                expected.merge(o.clone()).unwrap();
+                if let Some(d) = o.delta_since(since) {
+                    delta.merge(d).unwrap();
+                }
+                assert_eq!(delta.to_json(), expected.to_json(), "{:?}", history);
+            }
+        }
+    }
+}
@@ -0,0 +1,721 @@
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, TryRecvError};
+
+use crc64::Crc64;
+
+use crate::CstError;
+
+// A reader of the RDB files dumped by Redis, so that a dataset can be moved into ConstDB.
+// Only the types having a counterpart here are decoded, that is strings, sets and hashes,
+// lists and sorted sets are parsed and skipped, and anything else fails the import.
+
+const RDB_MAX_VERSION: u32 = 12;
+
+const RDB_OPCODE_SLOT_INFO: u8 = 0xF4;
+const RDB_OPCODE_FUNCTION2: u8 = 0xF5;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_FREQ: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+
+const RDB_ENC_INT8: u64 = 0;
+const RDB_ENC_INT16: u64 = 1;
+const RDB_ENC_INT32: u64 = 2;
+const RDB_ENC_LZF: u64 = 3;
+
+// the longest string Redis accepts by default(proto-max-bulk-len), a longer one means a corrupted length
+const RDB_MAX_STRING_LEN: u64 = 512 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum RdbValue {
+    String(Vec<u8>),
+    Set(Vec<Vec<u8>>),
+    Hash(Vec<(Vec<u8>, Vec<u8>)>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RdbItem {
+    // a key we can import, with its expire time in milliseconds if it has one
+    Entry(Vec<u8>, RdbValue, Option<u64>),
+    // a key of a type that ConstDB doesn't have, or in a database other than 0
+    Skipped(Vec<u8>, &'static str),
+}
+
+pub struct RdbReader<R: Read> {
+    io: R,
+    checksum: Crc64,
+    offset: u64,
+    version: u32,
+    db: u64,
+    finished: bool,
+    // the size of the file if it's known, no length read could go beyond it
+    size: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    pub fn new(io: R) -> Result<Self, CstError> {
+        let mut r = RdbReader{
+            io,
+            checksum: Crc64::new(),
+            offset: 0,
+            version: 0,
+            db: 0,
+            finished: false,
+            size: u64::MAX,
+        };
+        let head = r.read_exact(9)?;
+        if &head[..5] != b"REDIS" {
+            return Err(CstError::InvalidRdb(0));
+        }
+        r.version = std::str::from_utf8(&head[5..]).ok().and_then(|v| v.parse().ok()).ok_or(CstError::InvalidRdb(5))?;
+        if r.version == 0 || r.version > RDB_MAX_VERSION {
+            return Err(CstError::IncompatibleRdb(r.version.to_string()));
+        }
+        Ok(r)
+    }
+
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    // the next key in the file, None once the EOF opcode and the checksum are read
+    pub fn next_item(&mut self) -> Result<Option<RdbItem>, CstError> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut expire_ms = None;
+        loop {
+            let opcode = self.read_u8()?;
+            match opcode {
+                RDB_OPCODE_EOF => {
+                    self.read_checksum()?;
+                    self.finished = true;
+                    return Ok(None);
+                }
+                RDB_OPCODE_SELECTDB => self.db = self.read_len()?,
+                RDB_OPCODE_RESIZEDB => {
+                    let _ = self.read_len()?;
+                    let _ = self.read_len()?;
+                }
+                RDB_OPCODE_AUX => {
+                    let _ = self.read_string()?;
+                    let _ = self.read_string()?;
+                }
+                RDB_OPCODE_EXPIRETIME_MS => expire_ms = Some(self.read_u64_le()?),
+                RDB_OPCODE_EXPIRETIME => {
+                    let b = self.read_exact(4)?;
+                    expire_ms = Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64 * 1000);
+                }
+                RDB_OPCODE_IDLE => {
+                    let _ = self.read_len()?;
+                }
+                RDB_OPCODE_FREQ => {
+                    let _ = self.read_u8()?;
+                }
+                RDB_OPCODE_FUNCTION2 => {
+                    let _ = self.read_string()?;
+                }
+                RDB_OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        let _ = self.read_len()?;
+                    }
+                }
+                RDB_OPCODE_MODULE_AUX => return Err(CstError::IncompatibleRdb("module aux data".to_string())),
+                t => {
+                    let key = self.read_string()?;
+                    let item = match self.read_value(t)? {
+                        Some(_) if self.db != 0 => RdbItem::Skipped(key, "db"),
+                        Some(v) => RdbItem::Entry(key, v, expire_ms),
+                        None => RdbItem::Skipped(key, type_name(t)),
+                    };
+                    return Ok(Some(item));
+                }
+            }
+        }
+    }
+
+    // the value of type `t`, or None if it is a list or a sorted set
+    fn read_value(&mut self, t: u8) -> Result<Option<RdbValue>, CstError> {
+        let offset = self.offset;
+        let v = match t {
+            RDB_TYPE_STRING => RdbValue::String(self.read_string()?),
+            RDB_TYPE_SET => {
+                let n = self.read_len()?;
+                let mut members = Vec::with_capacity(n.min(1024) as usize);
+                for _ in 0..n {
+                    members.push(self.read_string()?);
+                }
+                RdbValue::Set(members)
+            }
+            RDB_TYPE_HASH => {
+                let n = self.read_len()?;
+                let mut kvs = Vec::with_capacity(n.min(1024) as usize);
+                for _ in 0..n {
+                    kvs.push((self.read_string()?, self.read_string()?));
+                }
+                RdbValue::Hash(kvs)
+            }
+            RDB_TYPE_SET_INTSET => RdbValue::Set(intset_entries(&self.read_string()?).ok_or(CstError::InvalidRdb(offset))?),
+            RDB_TYPE_SET_LISTPACK => RdbValue::Set(listpack_entries(&self.read_string()?).ok_or(CstError::InvalidRdb(offset))?),
+            RDB_TYPE_HASH_ZIPMAP => RdbValue::Hash(zipmap_entries(&self.read_string()?).ok_or(CstError::InvalidRdb(offset))?),
+            RDB_TYPE_HASH_ZIPLIST => {
+                let entries = ziplist_entries(&self.read_string()?).ok_or(CstError::InvalidRdb(offset))?;
+                RdbValue::Hash(pairs(entries).ok_or(CstError::InvalidRdb(offset))?)
+            }
+            RDB_TYPE_HASH_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?).ok_or(CstError::InvalidRdb(offset))?;
+                RdbValue::Hash(pairs(entries).ok_or(CstError::InvalidRdb(offset))?)
+            }
+            RDB_TYPE_LIST => {
+                for _ in 0..self.read_len()? {
+                    let _ = self.read_string()?;
+                }
+                return Ok(None);
+            }
+            RDB_TYPE_ZSET => {
+                for _ in 0..self.read_len()? {
+                    let _ = self.read_string()?;
+                    // the score is a string of at most 255 bytes, or one of 253, 254, 255 for nan, +inf and -inf
+                    let l = self.read_u8()?;
+                    if l < 253 {
+                        let _ = self.read_exact(l as usize)?;
+                    }
+                }
+                return Ok(None);
+            }
+            RDB_TYPE_ZSET_2 => {
+                for _ in 0..self.read_len()? {
+                    let _ = self.read_string()?;
+                    let _ = self.read_exact(8)?;
+                }
+                return Ok(None);
+            }
+            RDB_TYPE_LIST_ZIPLIST | RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let _ = self.read_string()?;
+                return Ok(None);
+            }
+            RDB_TYPE_LIST_QUICKLIST => {
+                for _ in 0..self.read_len()? {
+                    let _ = self.read_string()?;
+                }
+                return Ok(None);
+            }
+            RDB_TYPE_LIST_QUICKLIST_2 => {
+                for _ in 0..self.read_len()? {
+                    let _container = self.read_len()?;
+                    let _ = self.read_string()?;
+                }
+                return Ok(None);
+            }
+            t => return Err(CstError::IncompatibleRdb(format!("value type {}", t))),
+        };
+        Ok(Some(v))
+    }
+
+    fn read_checksum(&mut self) -> Result<(), CstError> {
+        if self.version < 5 {
+            return Ok(());
+        }
+        let expected = self.checksum.get();
+        let mut b = [0u8; 8];
+        self.io.read_exact(&mut b)?;
+        let checksum = u64::from_le_bytes(b);
+        // the checksum is zero if rdbchecksum is disabled in Redis
+        if checksum != 0 && checksum != expected {
+            return Err(CstError::InvalidRdbChecksum);
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, n: usize) -> Result<Vec<u8>, CstError> {
+        if n as u64 > self.size.saturating_sub(self.offset) {
+            return Err(CstError::InvalidRdb(self.offset));
+        }
+        let mut buf = vec![0u8; n];
+        self.io.read_exact(&mut buf)?;
+        self.checksum.write_all(&buf)?;
+        self.offset += n as u64;
+        Ok(buf)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, CstError> {
+        Ok(self.read_exact(1)?[0])
+    }
+
+    fn read_u64_le(&mut self) -> Result<u64, CstError> {
+        let b = self.read_exact(8)?;
+        Ok(u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+    }
+
+    // a length, or the type of a specially encoded string if the second item is true
+    fn read_len_encoded(&mut self) -> Result<(u64, bool), CstError> {
+        let offset = self.offset;
+        let b = self.read_u8()?;
+        match b >> 6 {
+            0 => Ok(((b & 0x3f) as u64, false)),
+            1 => Ok(((((b & 0x3f) as u64) << 8) | self.read_u8()? as u64, false)),
+            2 if b == 0x80 => {
+                let b = self.read_exact(4)?;
+                Ok((u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64, false))
+            }
+            2 if b == 0x81 => {
+                let b = self.read_exact(8)?;
+                Ok((u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]), false))
+            }
+            3 => Ok(((b & 0x3f) as u64, true)),
+            _ => Err(CstError::InvalidRdb(offset)),
+        }
+    }
+
+    fn read_len(&mut self) -> Result<u64, CstError> {
+        let offset = self.offset;
+        match self.read_len_encoded()? {
+            (l, false) => Ok(l),
+            _ => Err(CstError::InvalidRdb(offset)),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, CstError> {
+        let offset = self.offset;
+        let (l, encoded) = self.read_len_encoded()?;
+        if !encoded {
+            if l > RDB_MAX_STRING_LEN {
+                return Err(CstError::InvalidRdb(offset));
+            }
+            return self.read_exact(l as usize);
+        }
+        match l {
+            RDB_ENC_INT8 => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            RDB_ENC_INT16 => {
+                let b = self.read_exact(2)?;
+                Ok(i16::from_le_bytes([b[0], b[1]]).to_string().into_bytes())
+            }
+            RDB_ENC_INT32 => {
+                let b = self.read_exact(4)?;
+                Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]).to_string().into_bytes())
+            }
+            RDB_ENC_LZF => {
+                let compressed_len = self.read_len()?;
+                let len = self.read_len()?;
+                if compressed_len > RDB_MAX_STRING_LEN || len > RDB_MAX_STRING_LEN {
+                    return Err(CstError::InvalidRdb(offset));
+                }
+                let compressed = self.read_exact(compressed_len as usize)?;
+                lzf_decompress(&compressed, len as usize).ok_or(CstError::InvalidRdb(offset))
+            }
+            _ => Err(CstError::InvalidRdb(offset)),
+        }
+    }
+}
+
+fn type_name(t: u8) -> &'static str {
+    match t {
+        RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 | RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => "zset",
+        _ => "list",
+    }
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let run = input.get(i..i + ctrl + 1)?;
+            out.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            let mut l = ctrl >> 5;
+            if l == 7 {
+                l += *input.get(i)? as usize;
+                i += 1;
+            }
+            let back = ((ctrl & 0x1f) << 8) + *input.get(i)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(back)?;
+            // the ranges may overlap, so bytes are copied one by one
+            for k in 0..l + 2 {
+                let b = out[start + k];
+                out.push(b);
+            }
+        }
+        if out.len() > len {
+            return None;
+        }
+    }
+    if out.len() != len {
+        return None;
+    }
+    Some(out)
+}
+
+fn pairs(entries: Vec<Vec<u8>>) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    if !entries.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut iter = entries.into_iter();
+    let mut kvs = vec![];
+    while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+        kvs.push((k, v));
+    }
+    Some(kvs)
+}
+
+fn le_int(b: &[u8]) -> i64 {
+    let mut buf = [0u8; 8];
+    buf[..b.len()].copy_from_slice(b);
+    // sign extend the narrower integers
+    let shift = 64 - 8 * b.len() as u32;
+    (i64::from_le_bytes(buf) << shift) >> shift
+}
+
+fn intset_entries(s: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let width = u32::from_le_bytes(s.get(0..4)?.try_into().ok()?) as usize;
+    let n = u32::from_le_bytes(s.get(4..8)?.try_into().ok()?) as usize;
+    if width != 2 && width != 4 && width != 8 {
+        return None;
+    }
+    let body = s.get(8..8 + width.checked_mul(n)?)?;
+    Some(body.chunks(width).map(|c| le_int(c).to_string().into_bytes()).collect())
+}
+
+fn ziplist_entries(zl: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut entries = vec![];
+    let mut pos = 10;
+    loop {
+        if *zl.get(pos)? == 0xFF {
+            return Some(entries);
+        }
+        pos += if zl[pos] == 0xFE { 5 } else { 1 };
+        let enc = *zl.get(pos)?;
+        let (header, len) = match enc >> 6 {
+            0 => (1, (enc & 0x3f) as usize),
+            1 => (2, (((enc & 0x3f) as usize) << 8) | *zl.get(pos + 1)? as usize),
+            2 => (5, u32::from_be_bytes(zl.get(pos + 1..pos + 5)?.try_into().ok()?) as usize),
+            _ => {
+                let size = match enc {
+                    0xC0 => 2,
+                    0xD0 => 4,
+                    0xE0 => 8,
+                    0xF0 => 3,
+                    0xFE => 1,
+                    0xF1..=0xFD => 0,
+                    _ => return None,
+                };
+                let v = if size == 0 { (enc & 0x0f) as i64 - 1 } else { le_int(zl.get(pos + 1..pos + 1 + size)?) };
+                entries.push(v.to_string().into_bytes());
+                pos += 1 + size;
+                continue;
+            }
+        };
+        entries.push(zl.get(pos + header..pos + header + len)?.to_vec());
+        pos += header + len;
+    }
+}
+
+fn listpack_entries(lp: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut entries = vec![];
+    let mut pos = 6;
+    loop {
+        let b = *lp.get(pos)?;
+        if b == 0xFF {
+            return Some(entries);
+        }
+        let (entry, size) = if b & 0x80 == 0 {
+            ((b & 0x7f).to_string().into_bytes(), 1)
+        } else if b & 0xC0 == 0x80 {
+            let l = (b & 0x3f) as usize;
+            (lp.get(pos + 1..pos + 1 + l)?.to_vec(), 1 + l)
+        } else if b & 0xE0 == 0xC0 {
+            let v = (((b & 0x1f) as i64) << 8) | *lp.get(pos + 1)? as i64;
+            let v = if v >= 1 << 12 { v - (1 << 13) } else { v };
+            (v.to_string().into_bytes(), 2)
+        } else if b & 0xF0 == 0xE0 {
+            let l = (((b & 0x0f) as usize) << 8) | *lp.get(pos + 1)? as usize;
+            (lp.get(pos + 2..pos + 2 + l)?.to_vec(), 2 + l)
+        } else {
+            let size = match b {
+                0xF0 => {
+                    let l = u32::from_le_bytes(lp.get(pos + 1..pos + 5)?.try_into().ok()?) as usize;
+                    entries.push(lp.get(pos + 5..pos + 5 + l)?.to_vec());
+                    pos += backlen_size(5 + l) + 5 + l;
+                    continue;
+                }
+                0xF1 => 2,
+                0xF2 => 3,
+                0xF3 => 4,
+                0xF4 => 8,
+                _ => return None,
+            };
+            (le_int(lp.get(pos + 1..pos + 1 + size)?).to_string().into_bytes(), 1 + size)
+        };
+        entries.push(entry);
+        pos += size + backlen_size(size);
+    }
+}
+
+// the number of bytes the length of a listpack entry takes at its tail
+fn backlen_size(l: usize) -> usize {
+    match l {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+fn zipmap_entries(zm: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    fn zipmap_len(zm: &[u8], pos: &mut usize) -> Option<Option<usize>> {
+        let b = *zm.get(*pos)?;
+        match b {
+            255 => Some(None),
+            254 => {
+                let l = u32::from_le_bytes(zm.get(*pos + 1..*pos + 5)?.try_into().ok()?) as usize;
+                *pos += 5;
+                Some(Some(l))
+            }
+            _ => {
+                *pos += 1;
+                Some(Some(b as usize))
+            }
+        }
+    }
+    let mut kvs = vec![];
+    let mut pos = 1;
+    while let Some(kl) = zipmap_len(zm, &mut pos)? {
+        let k = zm.get(pos..pos + kl)?.to_vec();
+        pos += kl;
+        let vl = zipmap_len(zm, &mut pos)??;
+        let free = *zm.get(pos)? as usize;
+        let v = zm.get(pos + 1..pos + 1 + vl)?.to_vec();
+        pos += 1 + vl + free;
+        kvs.push((k, v));
+    }
+    Some(kvs)
+}
+
+// an import in progress, the file is parsed in a thread and the keys are applied by the cron of
+// the server, a few of them every time, so the clients are not blocked for long.
+pub struct RdbImport {
+    pub file_name: String,
+    // the keys matching it and holding integers are imported as counters
+    pub counters: Option<Vec<u8>>,
+    pub stats: RdbImportStats,
+    rx: Receiver<Result<RdbItem, CstError>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RdbImportStats {
+    pub imported: u64,
+    pub skipped: u64,
+    pub expired: u64,
+    pub ttl_applied: u64,
+    pub failed: u64,
+}
+
+impl RdbImport {
+    pub fn start(file_name: &str, counters: Option<Vec<u8>>) -> Result<Self, CstError> {
+        let f = File::open(file_name)?;
+        let size = f.metadata()?.len();
+        let mut reader = RdbReader::new(BufReader::new(f))?.with_size(size);
+        let (tx, rx) = sync_channel(1024);
+        std::thread::spawn(move || {
+            loop {
+                let r = reader.next_item();
+                let end = !matches!(r, Ok(Some(_)));
+                let r = match r {
+                    Ok(Some(item)) => Ok(item),
+                    Ok(None) => break,
+                    Err(e) => Err(e),
+                };
+                if tx.send(r).is_err() || end {
+                    break;
+                }
+            }
+        });
+        Ok(RdbImport{
+            file_name: file_name.to_string(),
+            counters,
+            stats: Default::default(),
+            rx,
+        })
+    }
+
+    // the next parsed item, Ok(None) if none is ready and Err(None) when the file is done
+    pub fn try_next(&mut self) -> Result<Option<RdbItem>, Option<CstError>> {
+        match self.rx.try_recv() {
+            Ok(Ok(item)) => Ok(Some(item)),
+            Ok(Err(e)) => Err(Some(e)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Write};
+
+    use crc64::Crc64;
+
+    use crate::CstError;
+    use crate::rdb::{RdbItem, RdbReader, RdbValue};
+
+    fn string(buf: &mut Vec<u8>, s: &[u8]) {
+        buf.push(s.len() as u8);
+        buf.extend_from_slice(s);
+    }
+
+    #[test]
+    fn test_rdb_reader() {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.push(0xFA);
+        string(&mut rdb, b"redis-ver");
+        string(&mut rdb, b"7.0.0");
+        rdb.extend_from_slice(&[0xFE, 0, 0xFB, 5, 1]);
+        // a plain string with an expire time
+        rdb.push(0xFC);
+        rdb.extend_from_slice(&1_000u64.to_le_bytes());
+        rdb.push(0);
+        string(&mut rdb, b"s");
+        string(&mut rdb, b"v");
+        // an integer encoded string
+        rdb.push(0);
+        string(&mut rdb, b"cnt");
+        rdb.extend_from_slice(&[0xC1, 0x39, 0x30]);
+        // a LZF compressed string of ten 'a'
+        rdb.push(0);
+        string(&mut rdb, b"lzf");
+        rdb.extend_from_slice(&[0xC3, 5, 10, 0, b'a', 0xE0, 0, 0]);
+        // a set as an intset
+        rdb.push(11);
+        string(&mut rdb, b"is");
+        let mut intset = vec![2, 0, 0, 0, 2, 0, 0, 0];
+        intset.extend_from_slice(&(-3i16).to_le_bytes());
+        intset.extend_from_slice(&7i16.to_le_bytes());
+        string(&mut rdb, &intset);
+        // a hash as a listpack
+        rdb.push(16);
+        string(&mut rdb, b"h");
+        let mut lp = vec![0; 6];
+        lp.extend_from_slice(&[0x82, b'f', b'1', 3, 0x05, 1]);
+        lp.extend_from_slice(&[0x82, b'f', b'2', 3, 0xC0 | 0x1f, 0xff, 2]);
+        lp.push(0xFF);
+        string(&mut rdb, &lp);
+        // a hash as a ziplist
+        rdb.push(13);
+        string(&mut rdb, b"hz");
+        let mut zl = vec![0; 10];
+        zl.extend_from_slice(&[0, 0x01, b'x', 3, 0xF3]);
+        zl.push(0xFF);
+        string(&mut rdb, &zl);
+        // a list is skipped
+        rdb.push(1);
+        string(&mut rdb, b"l");
+        rdb.push(1);
+        string(&mut rdb, b"e");
+        // so are the keys in the other databases
+        rdb.extend_from_slice(&[0xFE, 1, 2]);
+        string(&mut rdb, b"s1");
+        rdb.push(1);
+        string(&mut rdb, b"m");
+        rdb.push(0xFF);
+        let mut c = Crc64::new();
+        c.write_all(&rdb).unwrap();
+        rdb.extend_from_slice(&c.get().to_le_bytes());
+
+        let mut r = RdbReader::new(Cursor::new(rdb.clone())).unwrap();
+        assert_eq!(r.version(), 11);
+        let mut items = vec![];
+        while let Some(i) = r.next_item().unwrap() {
+            items.push(i);
+        }
+        assert_eq!(items, vec![
+            RdbItem::Entry(b"s".to_vec(), RdbValue::String(b"v".to_vec()), Some(1000)),
+            RdbItem::Entry(b"cnt".to_vec(), RdbValue::String(b"12345".to_vec()), None),
+            RdbItem::Entry(b"lzf".to_vec(), RdbValue::String(b"aaaaaaaaaa".to_vec()), None),
+            RdbItem::Entry(b"is".to_vec(), RdbValue::Set(vec![b"-3".to_vec(), b"7".to_vec()]), None),
+            RdbItem::Entry(b"h".to_vec(), RdbValue::Hash(vec![(b"f1".to_vec(), b"5".to_vec()), (b"f2".to_vec(), b"-1".to_vec())]), None),
+            RdbItem::Entry(b"hz".to_vec(), RdbValue::Hash(vec![(b"x".to_vec(), b"2".to_vec())]), None),
+            RdbItem::Skipped(b"l".to_vec(), "list"),
+            RdbItem::Skipped(b"s1".to_vec(), "db"),
+        ]);
+
+        // a corrupted byte fails the checksum
+        let l = rdb.len();
+        rdb[l - 10] ^= 1;
+        let mut r = RdbReader::new(Cursor::new(rdb)).unwrap();
+        let mut err = None;
+        loop {
+            match r.next_item() {
+                Ok(Some(_)) => continue,
+                Ok(None) => break,
+                Err(e) => {
+                    err = Some(e);
+                    break;
+                }
+            }
+        }
+        assert!(err.is_some());
+    }
+
+    #[test]
+    fn test_rdb_corrupted_lengths() {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.extend_from_slice(&[0xFE, 0, 0]);
+        string(&mut rdb, b"s");
+        // a length of 2^64-1 in the 64 bits form
+        rdb.push(0x81);
+        rdb.extend_from_slice(&[0xff; 8]);
+        let mut r = RdbReader::new(Cursor::new(rdb.clone())).unwrap();
+        assert!(matches!(r.next_item(), Err(CstError::InvalidRdb(_))));
+
+        // a length under the cap, but beyond the end of the file
+        let l = rdb.len();
+        rdb.truncate(l - 9);
+        rdb.push(0x80);
+        rdb.extend_from_slice(&(1u32 << 20).to_be_bytes());
+        let size = rdb.len() as u64;
+        let mut r = RdbReader::new(Cursor::new(rdb.clone())).unwrap().with_size(size);
+        assert!(matches!(r.next_item(), Err(CstError::InvalidRdb(_))));
+
+        // a lzf string claiming to be huge
+        rdb.truncate(l - 9);
+        rdb.extend_from_slice(&[0xC3, 0x81]);
+        rdb.extend_from_slice(&u64::MAX.to_be_bytes());
+        rdb.push(1);
+        let mut r = RdbReader::new(Cursor::new(rdb)).unwrap();
+        assert!(matches!(r.next_item(), Err(CstError::InvalidRdb(_))));
+    }
+}
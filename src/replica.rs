@@ -1,7 +1,11 @@
 pub mod replica;
 pub mod pull;
 pub mod push;
+pub mod backlog;
+pub mod merkle;
 
+use std::cmp::min;
+use std::collections::HashMap;
 use std::net::SocketAddr;
 
 use crate::cmd::NextArg;
@@ -10,7 +14,9 @@ use crate::CstError;
 use crate::link::{Client, SharedLink};
 use crate::resp::Message;
 use crate::server::Server;
-use crate::replica::replica::{Replica, ReplicaStat};
+use crate::replica::replica::{Replica, ReplicaPosition, ReplicaStat, load_positions};
+
+pub const REPLICATION_META_FILE: &str = "replication.meta";
 
 
 pub fn sync_command(server: &mut Server,client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
@@ -21,11 +27,19 @@ pub fn sync_command(server: &mut Server,client: Option<&mut Client>, _nodeid: u6
     let nodeid = args.next_u64()?;
     let his_alias = args.next_string()?;
     let uuid_i_sent = args.next_u64()?;
-    let mut replica = Replica::new(addr.clone(), server.node_id, server.config.node_alias.clone(),format!("{}:{}", server.config.ip, server.config.port));
+    let mut replica = Replica::new(addr.clone(), server.node_id, server.config.node_alias.clone(), format!("{}:{}", server.config.ip, server.config.port), server.config.repl_diskless_load);
+    // continue pulling from where we stopped if we've replicated with him before
+    if let Some(m) = server.replicas.get_replica(&addr) {
+        replica.meta.uuid_he_sent = m.uuid_he_sent;
+        replica.meta.uuid_he_acked = m.uuid_he_acked;
+    }
     replica.meta.he.id = nodeid;
     replica.meta.he.alias = his_alias;
     replica.meta.he.addr = addr.clone();
     replica.meta.uuid_i_sent = uuid_i_sent;
+    // the snapshot format, the compressions and the protocol he speaks, he's refused before being added
+    // if we can't replicate with him
+    replica.meta.read_sync_capabilities(&mut args)?;
     let conn = std::mem::replace(&mut client.conn, Conn::new(None, addr.clone()));
     replica.stat = ReplicaStat::Handshake(conn, true);
     replica.events = Some(server.events.new_consumer());
@@ -53,7 +67,7 @@ pub fn meet_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
     let mut args = args.into_iter();
     match args.next_string()?.parse::<SocketAddr>().map(|x| x.to_string()) {
         Ok(addr) => {
-            let mut r = Replica::new(addr.clone(), server.node_id, server.config.node_alias.clone(), server.addr.clone());
+            let mut r = Replica::new(addr.clone(), server.node_id, server.config.node_alias.clone(), server.addr.clone(), server.config.repl_diskless_load);
             r.meta.uuid_i_sent = server.get_repl_last_uuid();
             r.events = Some(server.events.new_consumer());
 
@@ -74,6 +88,45 @@ pub fn meet_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
     }
 }
 
+// reconnect to the replicas we were communicating with before we restarted, continuing
+// from the replication positions we saved. Only the data in the snapshot and the aof survived the
+// restart, so we pull from each replica no further than the position `pulled` recovered from them.
+pub fn restore_replicas(server: &mut Server, snapshot_positions: Vec<ReplicaPosition>, pulled: HashMap<String, u64>) {
+    let mut positions = match load_positions(REPLICATION_META_FILE) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Failed to load the replication positions from {} because {}", REPLICATION_META_FILE, e);
+            vec![]
+        }
+    };
+    for sp in snapshot_positions {
+        if !positions.iter().any(|p| p.addr == sp.addr) {
+            positions.push(sp);
+        }
+    }
+    for mut p in positions {
+        let uuid = pulled.get(&p.addr).copied().unwrap_or_default();
+        p.uuid_he_sent = min(p.uuid_he_sent, uuid);
+        p.uuid_he_acked = min(p.uuid_he_acked, uuid);
+        info!("Restoring the replica at {}, uuid_he_sent={}, uuid_i_sent={}", p.addr, p.uuid_he_sent, p.uuid_i_sent);
+        let mut r = Replica::new(p.addr.clone(), server.node_id, server.config.node_alias.clone(), server.addr.clone(), server.config.repl_diskless_load);
+        r.meta.he.id = p.id;
+        r.meta.he.alias = p.alias;
+        r.meta.uuid_he_sent = p.uuid_he_sent;
+        r.meta.uuid_he_acked = p.uuid_he_acked;
+        r.meta.uuid_i_sent = p.uuid_i_sent;
+        r.meta.uuid_i_acked = p.uuid_i_acked;
+        r.events = Some(server.events.new_consumer());
+        if server.replicas.add_replica(p.addr, r.meta.clone(), p.add_time) {
+            let mut sl = SharedLink::from(r);
+            let client_chan = server.client_chan.clone();
+            tokio::spawn(async move {
+                sl.prepare(client_chan).await;
+            });
+        }
+    }
+}
+
 pub fn forget_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
     let mut args = args.into_iter();
     let addr_to_forget = args.next_string()?;
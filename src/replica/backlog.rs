@@ -0,0 +1,481 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::cmd::command_name;
+use crate::conf::AppendFsync;
+use crate::CstError;
+use crate::resp::Message;
+
+const SEGMENT_FILE_PREFIX: &str = "backlog.";
+const ENTRY_HEADER_SIZE: usize = 12; // uuid(8) + body length(4)
+
+const MSG_NONE: u8 = 0;
+const MSG_NIL: u8 = 1;
+const MSG_STRING: u8 = 2;
+const MSG_INTEGER: u8 = 3;
+const MSG_ERROR: u8 = 4;
+const MSG_BULK: u8 = 5;
+const MSG_ARRAY: u8 = 6;
+
+pub type BacklogEntry = (u64, &'static str, Vec<Message>);
+
+// The commands evicted from the in-memory repl_log are spilled into segment files here,
+// so that a replica who has been disconnected for a long time can still catch up with us
+// instead of starting over with a full snapshot. The oldest segment is dropped when the
+// total size exceeds the limit. The entries are buffered and written by the cron of server,
+// and synced as the aof is, by the policy of `appendfsync`.
+pub struct DiskBacklog {
+    dir: PathBuf,
+    segment_size_limit: u64,
+    size_limit: u64,
+    size: u64,
+    fsync: AppendFsync,
+    unsynced: bool,
+    segments: VecDeque<Segment>,
+}
+
+struct Segment {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    // the entries from this offset are not written into the file yet, they're read from pending then
+    flushed: u64,
+    pending: Vec<u8>,
+    index: Vec<(u64, u64)>, // (uuid, offset)
+}
+
+impl Segment {
+    fn create(dir: &Path, first_uuid: u64) -> Result<Self, CstError> {
+        let path = dir.join(format!("{}{}", SEGMENT_FILE_PREFIX, first_uuid));
+        let file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        Ok(Segment{
+            path,
+            file,
+            size: 0,
+            flushed: 0,
+            pending: Vec::new(),
+            index: Vec::new(),
+        })
+    }
+
+    // reopen a segment written by a previous process and rebuild its index.
+    // a partially written entry at the tail is truncated.
+    fn load(path: PathBuf) -> Result<Self, CstError> {
+        let file = OpenOptions::new().read(true).append(true).open(&path)?;
+        let total = file.metadata()?.len();
+        let mut index = Vec::new();
+        let mut offset = 0u64;
+        let mut header = [0u8; ENTRY_HEADER_SIZE];
+        while offset + ENTRY_HEADER_SIZE as u64 <= total {
+            file.read_exact_at(&mut header, offset)?;
+            let uuid = u64::from_le_bytes([header[0], header[1], header[2], header[3], header[4], header[5], header[6], header[7]]);
+            let body_size = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as u64;
+            if offset + ENTRY_HEADER_SIZE as u64 + body_size > total {
+                break;
+            }
+            index.push((uuid, offset));
+            offset += ENTRY_HEADER_SIZE as u64 + body_size;
+        }
+        if offset < total {
+            warn!("Truncating the backlog segment {:?} from {} to {} bytes", path, total, offset);
+            file.set_len(offset)?;
+        }
+        Ok(Segment{
+            path,
+            file,
+            size: offset,
+            flushed: offset,
+            pending: Vec::new(),
+            index,
+        })
+    }
+
+    fn first_uuid(&self) -> Option<u64> {
+        self.index.first().map(|(u, _)| *u)
+    }
+
+    fn last_uuid(&self) -> Option<u64> {
+        self.index.last().map(|(u, _)| *u)
+    }
+
+    fn position(&self, uuid: u64) -> Result<usize, usize> {
+        self.index.binary_search_by(|(u, _)| u.cmp(&uuid))
+    }
+
+    fn read_at(&self, pos: usize) -> Result<BacklogEntry, CstError> {
+        let (uuid, offset) = self.index[pos];
+        if offset >= self.flushed {
+            let entry = &self.pending[(offset - self.flushed) as usize..];
+            let body_size = u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as usize;
+            let (cmd_name, args) = decode_command(&entry[ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + body_size], offset)?;
+            return Ok((uuid, cmd_name, args));
+        }
+        let mut header = [0u8; ENTRY_HEADER_SIZE];
+        self.file.read_exact_at(&mut header, offset)?;
+        let mut body = vec![0u8; u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize];
+        self.file.read_exact_at(&mut body, offset + ENTRY_HEADER_SIZE as u64)?;
+        let (cmd_name, args) = decode_command(&body, offset)?;
+        Ok((uuid, cmd_name, args))
+    }
+
+    fn flush(&mut self) -> Result<(), CstError> {
+        if !self.pending.is_empty() {
+            self.file.write_all(&self.pending)?;
+            self.pending.clear();
+            self.flushed = self.size;
+        }
+        Ok(())
+    }
+}
+
+impl DiskBacklog {
+    pub fn open(dir: &str, segment_size_limit: u64, size_limit: u64, fsync: AppendFsync) -> Result<Self, CstError> {
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)?;
+        // segments left by our previous process are restored, so that the replicas
+        // can continue from where they stopped after we restart.
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Some(first_uuid) = name.strip_prefix(SEGMENT_FILE_PREFIX).and_then(|x| x.parse::<u64>().ok()) {
+                found.push((first_uuid, entry.path()));
+            }
+        }
+        found.sort();
+        let mut segments = VecDeque::with_capacity(found.len());
+        let mut size = 0;
+        for (_, path) in found {
+            let seg = Segment::load(path)?;
+            if seg.index.is_empty() {
+                std::fs::remove_file(&seg.path)?;
+                continue;
+            }
+            size += seg.size;
+            segments.push_back(seg);
+        }
+        if !segments.is_empty() {
+            info!("Restored {} repl backlog segments of {} bytes from {:?}", segments.len(), size, dir);
+        }
+        Ok(DiskBacklog{
+            dir,
+            segment_size_limit,
+            size_limit,
+            size,
+            fsync,
+            unsynced: false,
+            segments,
+        })
+    }
+
+    // append a command evicted from the repl_log. If some segments are dropped to keep the total
+    // size under the limit, the last uuid dropped is returned.
+    pub fn append(&mut self, uuid: u64, cmd_name: &'static str, args: &[Message]) -> Result<Option<u64>, CstError> {
+        let mut body = Vec::with_capacity(64);
+        encode_command(&mut body, cmd_name, args);
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_SIZE + body.len());
+        entry.extend_from_slice(&uuid.to_le_bytes());
+        entry.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&body);
+
+        let need_new_segment = match self.segments.back() {
+            None => true,
+            Some(s) => s.size + entry.len() as u64 > self.segment_size_limit && !s.index.is_empty(),
+        };
+        if need_new_segment {
+            // the full segment is never written again, so it's done with before the next one
+            if let Some(seg) = self.segments.back_mut() {
+                seg.flush()?;
+                if self.fsync == AppendFsync::EverySec {
+                    sync_in_background(&seg.file)?;
+                }
+            }
+            self.segments.push_back(Segment::create(&self.dir, uuid)?);
+        }
+        let seg = self.segments.back_mut().unwrap();
+        seg.pending.extend_from_slice(&entry);
+        seg.index.push((uuid, seg.size));
+        seg.size += entry.len() as u64;
+        self.size += entry.len() as u64;
+        self.unsynced = true;
+        if self.fsync == AppendFsync::Always {
+            seg.flush()?;
+            seg.file.sync_data()?;
+            self.unsynced = false;
+        }
+
+        let mut dropped = None;
+        while self.size > self.size_limit && self.segments.len() > 1 {
+            let seg = self.segments.pop_front().unwrap();
+            self.size -= seg.size;
+            dropped = seg.last_uuid();
+            if let Err(e) = std::fs::remove_file(&seg.path) {
+                error!("Failed to remove the backlog segment {:?} because {}", seg.path, e);
+            }
+        }
+        Ok(dropped)
+    }
+
+    // called by the cron of server like `Aof::cron`, the buffered entries are written every time,
+    // and the current segment is synced every second if the policy is `everysec`.
+    pub fn cron(&mut self, every_second: bool) -> Result<(), CstError> {
+        if !self.unsynced {
+            return Ok(());
+        }
+        if let Some(seg) = self.segments.back_mut() {
+            seg.flush()?;
+            if self.fsync == AppendFsync::EverySec && every_second {
+                sync_in_background(&seg.file)?;
+                self.unsynced = false;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn first_uuid(&self) -> Option<u64> {
+        self.segments.front().and_then(|s| s.first_uuid())
+    }
+
+    pub fn last_uuid(&self) -> Option<u64> {
+        self.segments.back().and_then(|s| s.last_uuid())
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn contains(&self, uuid: u64) -> bool {
+        self.segments.iter().any(|s| s.position(uuid).is_ok())
+    }
+
+    // the command with exactly this uuid
+    pub fn get(&self, uuid: u64) -> Result<Option<BacklogEntry>, CstError> {
+        for seg in self.segments.iter() {
+            if let Ok(pos) = seg.position(uuid) {
+                return seg.read_at(pos).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    // the command following the one with this uuid. Zero means the first one.
+    pub fn next(&self, uuid: u64) -> Result<Option<BacklogEntry>, CstError> {
+        for seg in self.segments.iter() {
+            match seg.last_uuid() {
+                Some(last) if last > uuid => {},
+                _ => continue,
+            }
+            let pos = match seg.position(uuid) {
+                Ok(pos) => pos + 1,
+                Err(pos) => pos,
+            };
+            return seg.read_at(pos).map(Some);
+        }
+        Ok(None)
+    }
+
+    pub fn uuids(&self) -> Vec<u64> {
+        self.segments.iter().flat_map(|s| s.index.iter().map(|(u, _)| *u)).collect()
+    }
+}
+
+impl Drop for DiskBacklog {
+    fn drop(&mut self) {
+        if let Some(seg) = self.segments.back_mut() {
+            if let Err(e) = seg.flush() {
+                error!("Failed to flush the backlog segment {:?} because {}", seg.path, e);
+            }
+        }
+    }
+}
+
+// the main thread must not be blocked by fsync. Out of the runtime, such as when the
+// backlog is appended while the server is being created, it's synced in place.
+fn sync_in_background(file: &File) -> Result<(), CstError> {
+    let handle = match tokio::runtime::Handle::try_current() {
+        Ok(h) => h,
+        Err(_) => return file.sync_data().map_err(Into::into),
+    };
+    let file = file.try_clone()?;
+    handle.spawn_blocking(move || {
+        if let Err(e) = file.sync_data() {
+            error!("Failed to sync the repl backlog because {}", e);
+        }
+    });
+    Ok(())
+}
+
+// the encoding of a command, which is shared with the aof.
+pub(crate) fn encode_command(dst: &mut Vec<u8>, cmd_name: &str, args: &[Message]) {
+    encode_bytes(dst, cmd_name.as_bytes());
+    dst.extend_from_slice(&(args.len() as u32).to_le_bytes());
+    for arg in args {
+        encode_message(dst, arg);
+    }
+}
+
+// decode a command encoded by `encode_command`, the offset is only used for reporting errors.
+pub(crate) fn decode_command(body: &[u8], offset: u64) -> Result<(&'static str, Vec<Message>), CstError> {
+    let mut cursor = Cursor{data: body, pos: 0, offset};
+    let name = cursor.read_bytes()?;
+    let cmd_name = command_name(name).ok_or(CstError::InvalidBacklog(offset))?;
+    let argc = cursor.read_u32()? as usize;
+    let mut args = Vec::with_capacity(argc);
+    for _ in 0..argc {
+        args.push(cursor.read_message()?);
+    }
+    Ok((cmd_name, args))
+}
+
+pub(crate) fn encode_bytes(dst: &mut Vec<u8>, b: &[u8]) {
+    dst.extend_from_slice(&(b.len() as u32).to_le_bytes());
+    dst.extend_from_slice(b);
+}
+
+fn encode_message(dst: &mut Vec<u8>, msg: &Message) {
+    match msg {
+        Message::None => dst.push(MSG_NONE),
+        Message::Nil => dst.push(MSG_NIL),
+        Message::String(s) => {
+            dst.push(MSG_STRING);
+            encode_bytes(dst, s.as_bytes());
+        }
+        Message::Integer(i) => {
+            dst.push(MSG_INTEGER);
+            dst.extend_from_slice(&i.to_le_bytes());
+        }
+        Message::Error(e) => {
+            dst.push(MSG_ERROR);
+            encode_bytes(dst, e.as_bytes());
+        }
+        Message::BulkString(b) => {
+            dst.push(MSG_BULK);
+            encode_bytes(dst, b.as_bytes());
+        }
+        Message::Array(args) => {
+            dst.push(MSG_ARRAY);
+            dst.extend_from_slice(&(args.len() as u32).to_le_bytes());
+            for arg in args {
+                encode_message(dst, arg);
+            }
+        }
+    }
+}
+
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    offset: u64,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], CstError> {
+        if self.pos + size > self.data.len() {
+            return Err(CstError::InvalidBacklog(self.offset));
+        }
+        let r = &self.data[self.pos..self.pos + size];
+        self.pos += size;
+        Ok(r)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, CstError> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8], CstError> {
+        let l = self.read_u32()? as usize;
+        self.take(l)
+    }
+
+    fn read_message(&mut self) -> Result<Message, CstError> {
+        let msg = match self.take(1)?[0] {
+            MSG_NONE => Message::None,
+            MSG_NIL => Message::Nil,
+            MSG_STRING => Message::String(self.read_bytes()?.into()),
+            MSG_INTEGER => {
+                let b = self.take(8)?;
+                Message::Integer(i64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+            }
+            MSG_ERROR => Message::Error(self.read_bytes()?.into()),
+            MSG_BULK => Message::BulkString(self.read_bytes()?.into()),
+            MSG_ARRAY => {
+                let l = self.read_u32()? as usize;
+                let mut args = Vec::with_capacity(l);
+                for _ in 0..l {
+                    args.push(self.read_message()?);
+                }
+                Message::Array(args)
+            }
+            _ => return Err(CstError::InvalidBacklog(self.offset)),
+        };
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::conf::AppendFsync;
+    use crate::replica::backlog::DiskBacklog;
+    use crate::resp::Message;
+
+    #[test]
+    fn test_disk_backlog() {
+        let dir = std::env::temp_dir().join(format!("constdb_backlog_test_{}", std::process::id()));
+        let mut b = DiskBacklog::open(dir.to_str().unwrap(), 64, 256, AppendFsync::No).unwrap();
+        let mut dropped = None;
+        for uuid in 1..=20u64 {
+            let args = vec![Message::BulkString(format!("key{}", uuid).into()), Message::Integer(uuid as i64)];
+            if let Some(d) = b.append(uuid * 10, "incr", &args).unwrap() {
+                dropped = Some(d);
+            }
+        }
+        assert!(b.size() <= 256);
+        assert_eq!(b.last_uuid(), Some(200));
+        let first = b.first_uuid().unwrap();
+        assert_eq!(dropped, Some(first - 10));
+
+        assert!(b.get(first - 10).unwrap().is_none());
+        let (u, name, args) = b.get(180).unwrap().unwrap();
+        assert_eq!((u, name), (180, "incr"));
+        assert_eq!(args, vec![Message::BulkString("key18".into()), Message::Integer(18)]);
+        assert_eq!(b.next(180).unwrap().map(|(u, _, _)| u), Some(190));
+        assert_eq!(b.next(0).unwrap().map(|(u, _, _)| u), Some(first));
+        assert!(b.next(200).unwrap().is_none());
+        assert_eq!(b.uuids().len() as u64, (200 - first) / 10 + 1);
+        assert!(b.contains(first) && !b.contains(first - 10));
+
+        // the buffered entries are read before and after being written
+        let last_segment = dir.join("backlog.200");
+        assert_eq!(std::fs::metadata(&last_segment).unwrap().len(), 0);
+        b.cron(false).unwrap();
+        assert!(std::fs::metadata(&last_segment).unwrap().len() > 0);
+        assert_eq!(b.get(200).unwrap().map(|(u, _, _)| u), Some(200));
+
+        // a restarted process continues with the same backlog, ignoring a partially written entry
+        drop(b);
+        let mut f = std::fs::OpenOptions::new().append(true).open(&last_segment).unwrap();
+        f.write_all(&[1, 2, 3]).unwrap();
+        drop(f);
+        let b = DiskBacklog::open(dir.to_str().unwrap(), 64, 256, AppendFsync::Always).unwrap();
+        assert_eq!(b.first_uuid(), Some(first));
+        assert_eq!(b.last_uuid(), Some(200));
+        assert_eq!(b.get(200).unwrap().map(|(_, _, args)| args), Some(vec![Message::BulkString("key20".into()), Message::Integer(20)]));
+        drop(b);
+
+        // the full segments are synced even without a runtime to sync them in background
+        let mut b = DiskBacklog::open(dir.to_str().unwrap(), 64, 256, AppendFsync::EverySec).unwrap();
+        for uuid in 21..=30u64 {
+            b.append(uuid * 10, "incr", &[Message::BulkString(format!("key{}", uuid).into())]).unwrap();
+        }
+        b.cron(true).unwrap();
+        assert_eq!(b.last_uuid(), Some(300));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,111 @@
+use crc64::crc64;
+
+use crate::resp::Message;
+
+// the keys are hashed into the leaves of a tree with this fanout and depth. two replicas compare their
+// trees from the root down, so that only the few buckets they differ in are exchanged.
+pub const MERKLE_FANOUT: usize = 16;
+pub const MERKLE_DEPTH: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct MerkleTree {
+    // the hashes of the nodes level by level, from the root to the leaves
+    levels: Vec<Vec<u64>>,
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        MerkleTree {
+            levels: (0..=MERKLE_DEPTH).map(|l| vec![0; MERKLE_FANOUT.pow(l as u32)]).collect(),
+        }
+    }
+}
+
+impl MerkleTree {
+    pub fn bucket(key: &[u8]) -> usize {
+        (crc64(0, key) % MERKLE_FANOUT.pow(MERKLE_DEPTH as u32) as u64) as usize
+    }
+
+    // the keys of a bucket may be added in any order
+    pub fn add(&mut self, key: &[u8], digest: u64) {
+        let leaf = &mut self.levels[MERKLE_DEPTH][Self::bucket(key)];
+        *leaf = leaf.wrapping_add(crc64(digest, key));
+    }
+
+    // compute the inner nodes after all the keys are added
+    pub fn seal(&mut self) {
+        for level in (0..MERKLE_DEPTH).rev() {
+            for i in 0..self.levels[level].len() {
+                let h = self.children(level, i).iter().fold(0, |h, c| crc64(h, &c.to_le_bytes()));
+                self.levels[level][i] = h;
+            }
+        }
+    }
+
+    pub fn root(&self) -> u64 {
+        self.levels[0][0]
+    }
+
+    pub fn children(&self, level: usize, index: usize) -> &[u64] {
+        &self.levels[level + 1][index * MERKLE_FANOUT..(index + 1) * MERKLE_FANOUT]
+    }
+
+    // the indexes of the children of the node, in the level below, which differ from his
+    pub fn diff(&self, level: usize, index: usize, his: &[u64]) -> Vec<usize> {
+        self.children(level, index).iter().zip(his.iter()).enumerate()
+            .filter(|(_, (mine, his))| mine != his)
+            .map(|(i, _)| index * MERKLE_FANOUT + i)
+            .collect()
+    }
+
+    // the hashes of the children of the node, which he compares with his
+    pub fn to_message(&self, horizon: u64, level: usize, index: usize) -> Message {
+        let mut args = Vec::with_capacity(MERKLE_FANOUT + 4);
+        args.push(Message::BulkString("MERKLE".into()));
+        args.push(Message::Integer(horizon as i64));
+        args.push(Message::Integer(level as i64));
+        args.push(Message::Integer(index as i64));
+        args.extend(self.children(level, index).iter().map(|h| Message::Integer(*h as i64)));
+        Message::Array(args)
+    }
+
+    // whether the node is there, whose children are compared
+    pub fn has_node(level: usize, index: usize) -> bool {
+        level < MERKLE_DEPTH && index < MERKLE_FANOUT.pow(level as u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::replica::merkle::{MerkleTree, MERKLE_DEPTH};
+
+    #[test]
+    fn test_merkle_diff() {
+        let (mut a, mut b) = (MerkleTree::default(), MerkleTree::default());
+        for i in 0..1000 {
+            let key = format!("k{}", i);
+            a.add(key.as_bytes(), i);
+            b.add(key.as_bytes(), if i == 7 { 0 } else { i });
+        }
+        // in another order
+        let mut c = MerkleTree::default();
+        for i in (0..1000).rev() {
+            c.add(format!("k{}", i).as_bytes(), i);
+        }
+        a.seal();
+        b.seal();
+        c.seal();
+        assert_eq!(a.root(), c.root());
+        assert_ne!(a.root(), b.root());
+
+        // only the nodes above the bucket of k7 differ
+        let (mut level, mut index) = (0, 0);
+        while level < MERKLE_DEPTH {
+            let diff = a.diff(level, index, b.children(level, index));
+            assert_eq!(diff.len(), 1);
+            index = diff[0];
+            level += 1;
+        }
+        assert_eq!(index, MerkleTree::bucket(b"k7"));
+    }
+}
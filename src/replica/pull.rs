@@ -1,12 +1,17 @@
 use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 use tokio::io::{AsyncSeekExt, SeekFrom};
 
 use crate::cmd::{Cmd, NextArg};
-use crate::conn::reader::Reader;
-use crate::CstError;
-use crate::replica::replica::{Replica, ReplicaMeta};
+use crate::conn::reader::{Reader, SnapshotStream};
+use crate::{Bytes, CstError, now_mil};
+use crate::clock::UUID_COUNTER_BITS;
+use crate::crdt::vclock::VClock;
+use crate::replica::replica::{Replica, ReplicaMeta, SnapshotLoading, REPL_PROTOCOL_VERSION};
+use crate::replica::push::DELTA_SNAPSHOT_PREFIX;
 use crate::resp::Message;
+use crate::object::Object;
 use crate::server::Server;
 use crate::snapshot::{SnapshotEntry, SnapshotLoader, FileSnapshotLoader};
 use crate::link::SharedLink;
@@ -19,8 +24,19 @@ pub struct Puller {
 
     pub(crate) stats: PullStat,
     pub(crate) reader: Reader,
+    // the size of the snapshot being loaded, and how many entries of it are merged
+    pub(crate) snapshot_size: usize,
+    pub(crate) merged_entries: u64,
+    // his wall clock minus ours in milliseconds, measured when he acks
+    pub(crate) clock_skew_ms: Option<i64>,
+    // the writes of every node he has, which he tells in his acks
+    pub(crate) his_versions: Option<VClock<u64>>,
     pub(crate) snapshot_entries: VecDeque<SnapshotEntry>,
     pub(crate) replicates: VecDeque<Message>,
+    // the objects he sent to repair ours, which are loaded in the io threads
+    pub(crate) repairs: VecDeque<(Bytes, Object)>,
+    // our replies to his anti-entropy, which are sent by our pusher
+    pub(crate) replies: Vec<Message>,
 }
 
 #[derive(Debug)]
@@ -28,9 +44,44 @@ pub enum PullStat {
     SyncSent,
     DownloadingSnapshot(usize),
     LoadingSnapshot(FileSnapshotLoader),
+    // his snapshot is loaded while being received, the reader is owned by the loader meanwhile
+    StreamingSnapshot(SnapshotLoader<SnapshotStream>),
     PullingCommands,
 }
 
+// no more entries are loaded from the snapshot until the main thread merges some of those loaded
+const MAX_PENDING_SNAPSHOT_ENTRIES: usize = 1024;
+
+// the snapshots of our replicas are saved into these files before being loaded, unless
+// they're loaded while being received.
+const STAGING_SNAPSHOT_PREFIX: &str = "snapshot.";
+
+// remove the staging snapshots left behind by a previous process, called at startup.
+pub fn remove_staging_snapshots() {
+    let entries = match std::fs::read_dir(".") {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Failed to list the staging snapshots because {}", e);
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        // the deltas dumped for our replicas are left behind as well if we crashed while sending them
+        let addr = match name.to_str().and_then(|n| n.strip_prefix(STAGING_SNAPSHOT_PREFIX).or_else(|| n.strip_prefix(DELTA_SNAPSHOT_PREFIX))) {
+            Some(a) => a,
+            None => continue,
+        };
+        if addr.parse::<std::net::SocketAddr>().is_err() {
+            continue;
+        }
+        match std::fs::remove_file(entry.path()) {
+            Ok(()) => info!("Removed the stale staging snapshot {:?}", name),
+            Err(e) => error!("Failed to remove the stale staging snapshot {:?} because {}", name, e),
+        }
+    }
+}
+
 impl Puller {
     pub async fn download_snapshot(&mut self) -> Result<(), CstError> {
         loop {
@@ -50,19 +101,31 @@ impl Puller {
                     }
                 }
                 PullStat::DownloadingSnapshot(snapshot_size) => {
+                    let snapshot_size = *snapshot_size;
                     debug!("Replica at {} is in DownloadingSnapshot stat", self.meta.he.addr);
-                    let file_name = format!("snapshot.{}", self.meta.he.addr);
-                    let mut snapshot = tokio::fs::OpenOptions::new().create(true).write(true).read(true).truncate(true).open(file_name).await?;
-                    if let Err(e) = self.reader.save_to_file(&mut snapshot, *snapshot_size).await {
+                    self.snapshot_size = snapshot_size;
+                    self.merged_entries = 0;
+                    if self.meta.diskless_load {
+                        let stream = SnapshotStream::new(std::mem::take(&mut self.reader), snapshot_size);
+                        self.stats = PullStat::StreamingSnapshot(SnapshotLoader::new(stream));
+                        continue;
+                    }
+                    let file_name = self.staging_file();
+                    let mut snapshot = tokio::fs::OpenOptions::new().create(true).write(true).read(true).truncate(true).open(&file_name).await?;
+                    if let Err(e) = self.reader.save_to_file(&mut snapshot, snapshot_size).await {
                         error!("Failed to save snapshot into local file because {}", e);
+                        let _ = std::fs::remove_file(&file_name);
                         return Err(CstError::SystemError);
                     }
-                    debug!("Finished downloading the snapshot from replica at {}, snapshot_size={}", self.meta.he.addr, *snapshot_size);
+                    debug!("Finished downloading the snapshot from replica at {}, snapshot_size={}", self.meta.he.addr, snapshot_size);
                     snapshot.seek(SeekFrom::Start(0)).await?;
                     self.stats = PullStat::LoadingSnapshot(SnapshotLoader::new(snapshot));
                 }
                 PullStat::LoadingSnapshot(loader) => {
                     debug!("Replica at {} is in LoadingSnapshot stat", self.meta.he.addr);
+                    if self.snapshot_entries.len() >= MAX_PENDING_SNAPSHOT_ENTRIES {
+                        return Ok(());
+                    }
                     for _ in 0usize..32 {
                         match loader.next().await? {
                             Some(entry) => self.snapshot_entries.push_back(entry),
@@ -74,6 +137,40 @@ impl Puller {
                     }
                     if self.snapshot_entries.len() == 0 {
                         self.stats = PullStat::PullingCommands;
+                        if let Err(e) = std::fs::remove_file(self.staging_file()) {
+                            warn!("Failed to remove the staging snapshot {} because {}", self.staging_file(), e);
+                        }
+                    }
+                    return Ok(())
+                }
+                PullStat::StreamingSnapshot(loader) => {
+                    debug!("Replica at {} is in StreamingSnapshot stat", self.meta.he.addr);
+                    if self.snapshot_entries.len() >= MAX_PENDING_SNAPSHOT_ENTRIES {
+                        return Ok(());
+                    }
+                    let mut finished = false;
+                    for _ in 0usize..32 {
+                        match loader.next().await? {
+                            Some(entry) => self.snapshot_entries.push_back(entry),
+                            None => {
+                                finished = true;
+                                break;
+                            }
+                        }
+                    }
+                    if finished {
+                        // the checksum has been verified, take the reader back to receive his commands,
+                        // the entries left are merged before them.
+                        let stream = match std::mem::replace(&mut self.stats, PullStat::PullingCommands) {
+                            PullStat::StreamingSnapshot(loader) => loader.into_inner(),
+                            _ => unreachable!(),
+                        };
+                        if stream.remaining() > 0 {
+                            error!("The snapshot from replica at {} has {} bytes following its checksum", self.meta.he.addr, stream.remaining());
+                            return Err(CstError::InvalidSnapshot(stream.remaining()));
+                        }
+                        self.reader = stream.into_inner();
+                        debug!("Finished streaming the snapshot from replica at {}", self.meta.he.addr);
                     }
                     return Ok(())
                 }
@@ -85,6 +182,10 @@ impl Puller {
     }
 
     pub async fn accept_replicates(&mut self) -> Result<(), CstError> {
+        // the rest of his snapshot must be merged before his commands
+        if !self.snapshot_entries.is_empty() {
+            return Ok(());
+        }
         loop {
             match &mut self.stats {
                 PullStat::PullingCommands => {
@@ -95,7 +196,16 @@ impl Puller {
                                 return Err(CstError::ConnBroken(self.meta.he.addr.clone()));
                             }
                             Ok(None) => break,
-                            Ok(Some(msg)) => self.replicates.push_back(msg),
+                            Ok(Some(msg)) => {
+                                // the bytes following a FULLSYNC are a snapshot rather than resp messages,
+                                // so we must stop parsing here and leave them to download_snapshot.
+                                let fullsync = is_command(&msg, b"fullsync");
+                                let msg = self.load_repairs(msg).await?;
+                                self.replicates.push_back(msg);
+                                if fullsync {
+                                    return Ok(());
+                                }
+                            }
                         }
                     }
                     if self.replicates.is_empty() {
@@ -113,60 +223,78 @@ impl Puller {
         }
     }
 
+    // the objects in a REPAIR are loaded here, and the number of them is left in their place
+    pub(crate) async fn load_repairs(&mut self, msg: Message) -> Result<Message, CstError> {
+        if !is_command(&msg, b"repair") {
+            return Ok(msg);
+        }
+        let mut args = match msg {
+            Message::Array(args) => args,
+            _ => return Ok(msg),
+        };
+        let buckets = args.get(3).cloned().into_iter().next_u64()? as usize;
+        let objects = args.split_off(std::cmp::min(4 + buckets, args.len()));
+        let cnt = objects.len();
+        for o in objects {
+            let o = match o {
+                Message::BulkString(o) => o,
+                _ => return Err(CstError::InvalidRequestMsg("the objects to repair should be bulk strings".to_string())),
+            };
+            let mut loader = SnapshotLoader::new(o.as_bytes());
+            self.repairs.push_back(loader.read_entry(Some(o.len())).await?);
+        }
+        args.push(Message::Integer(cnt as i64));
+        Ok(Message::Array(args))
+    }
+
+    // we treat it not as a bug if he speaks a newer protocol, as it's likely of a data type we don't know,
+    // it's skipped then and our data differs from his until we're upgraded too.
+    fn skip_unknown_command(&self, server: &mut Server, e: &CstError) {
+        server.metrics.incr_skipped_commands();
+        if self.meta.protocol_version > REPL_PROTOCOL_VERSION {
+            warn!("the replica named {} of a newer protocol version {} sent us {}, which is skipped", self.meta.he.alias, self.meta.protocol_version, e);
+        } else {
+            error!("the replica named {} sent us an unknown command {}", self.meta.he.alias, e);
+        }
+    }
+
+    fn staging_file(&self) -> String {
+        format!("{}{}", STAGING_SNAPSHOT_PREFIX, self.meta.he.addr)
+    }
+
+    // returns Err(ReplicateCommandsLost) if he sent us some commands following those we don't have,
+    // which are dropped, and we're to resync with him from uuid_he_sent.
     pub fn merge_replicates_in_main(&mut self, server: &mut Server) -> Result<(), CstError> {
-        match &mut self.stats {
-            PullStat::LoadingSnapshot(_) => {
-                debug!("Replica at {} is in LoadingSnapshot stat", self.meta.he.addr);
-                while let Some(entry) = self.snapshot_entries.pop_front() {
-                    match entry {
-                        SnapshotEntry::Version(version) => {
-                            info!("Received snapshot with version {:?}", version);
-                        }
-                        SnapshotEntry::Data(k, v) => {
-                            debug!("Merging entry from snapshot, k={:?}, v={:?}", k, v);
-                            server.db.merge_entry(k, v);
-                        },
-                        SnapshotEntry::Deletes(k, uuid) => server.db.delete(&k, uuid),
-                        SnapshotEntry::Expires(k, t) => server.db.expire_at(&k, t),
-                        SnapshotEntry::Node(node_id, node_alias, _addr, uuid) => {
-                            self.uuid_he_sent = uuid;
-                            self.meta.he.id = node_id;
-                            self.meta.he.alias = node_alias;
-                        },
-                        SnapshotEntry::ReplicaAdd(add_time, node_id, node_alias, addr, uuid) => {
-                            if node_id == self.meta.myself.id {
-                                continue;
-                            }
-                            debug!("Found a new replica from the snapshot, node_id={}, alias={}, addr={}, uuid={}", node_id, node_alias, addr, uuid);
-                            let mut r = Replica::new(addr.clone(), server.node_id, server.config.node_alias.clone(), format!("{}:{}", server.config.ip, server.config.port));
-                            r.meta.he.id = node_id;
-                            r.meta.uuid_he_sent = uuid;
-                            r.meta.he.alias = node_alias;
-                            r.events = Some(server.events.new_consumer());
-                            if server.replicas.add_replica(addr.clone(), r.meta.clone(), add_time) {
-                                let mut sl = SharedLink::from(r);
-                                let client_chan = server.client_chan.clone();
-                                tokio::spawn(async move {
-                                    sl.prepare(client_chan).await;
-                                });
-                            }
-                        },
-                        SnapshotEntry::ReplicaDel(addr, t) => {
-                            server.replicas.remove_replica(&addr, t);
-                        }
-                    }
-                }
+        let mut lost = None;
+        match self.stats {
+            PullStat::LoadingSnapshot(_) | PullStat::StreamingSnapshot(_) => {
+                debug!("Replica at {} is loading his snapshot", self.meta.he.addr);
+                self.merge_snapshot_entries(server);
             },
             PullStat::PullingCommands => {
+                // a snapshot may leave some entries behind, which precede his commands
+                if !self.merge_snapshot_entries(server) {
+                    server.replicas.update_replica_loading(&self.meta.he, Some(self.loading_progress()));
+                    return Ok(());
+                }
                 let mut applied = 0;
                 for _ in 0..16 {
+                    if let PullStat::SyncSent = self.stats {
+                        break;
+                    }
                     while let Some(cmd) = self.replicates.pop_front() {
                         match self.apply_his_replicates(server, cmd) {
                             Ok(true) => applied += 1,
                             Ok(false) => break,
-                            Err(_) => { // some commands are lost, resync from the latest position
-                                error!("some commands from the peer {} are lost", self.meta.he.alias);
-                                // TODO
+                            Err(e @ CstError::ReplicateCommandsLost(_)) => {
+                                // the rest follow the lost ones, we resync from the latest position we have instead
+                                error!("some commands from the peer {} are lost after uuid {}, resync from there", self.meta.he.alias, self.uuid_he_sent);
+                                self.replicates.clear();
+                                lost = Some(e);
+                                break;
+                            }
+                            Err(e) => {
+                                error!("Failed to apply the command from the peer {} because {}", self.meta.he.alias, e);
                                 break;
                             }
                         }
@@ -177,8 +305,93 @@ impl Puller {
             }
             _ => {}
         }
+        let loading = match self.stats {
+            PullStat::DownloadingSnapshot(_) | PullStat::LoadingSnapshot(_) | PullStat::StreamingSnapshot(_) => Some(self.loading_progress()),
+            _ => None,
+        };
+        server.replicas.update_replica_loading(&self.meta.he, loading);
+        server.replicas.update_replica_clock_skew(&self.meta.he, self.clock_skew_ms);
         server.replicas.update_replica_pull_stat(&self.meta.he, self.uuid_he_sent, self.uuid_he_acked);
-        Ok(())
+        lost.map_or(Ok(()), Err)
+    }
+
+    fn loading_progress(&self) -> SnapshotLoading {
+        let received = match &self.stats {
+            PullStat::StreamingSnapshot(loader) => self.snapshot_size - loader.get_ref().remaining(),
+            PullStat::DownloadingSnapshot(_) => 0,
+            _ => self.snapshot_size,
+        };
+        SnapshotLoading{
+            size: self.snapshot_size,
+            received,
+            merged: self.merged_entries,
+            pending: self.snapshot_entries.len(),
+        }
+    }
+
+    // merge the entries loaded from his snapshot until the time budget of this round is used up,
+    // so that our clients are not blocked for long. the budget is shared by all the replicas, the
+    // first of them merges one entry at least. returns whether all of them are merged.
+    fn merge_snapshot_entries(&mut self, server: &mut Server) -> bool {
+        if self.snapshot_entries.is_empty() {
+            return true;
+        }
+        let first = server.merge_deadline.is_none();
+        let chunk = Duration::from_millis(server.config.repl_merge_chunk_ms);
+        let deadline = *server.merge_deadline.get_or_insert_with(|| Instant::now() + chunk);
+        if !first && Instant::now() >= deadline {
+            return false;
+        }
+        while let Some(entry) = self.snapshot_entries.pop_front() {
+            self.merged_entries += 1;
+            match entry {
+                SnapshotEntry::Version(version) => {
+                    info!("Received snapshot with version {:?}", version);
+                }
+                SnapshotEntry::Data(k, v) | SnapshotEntry::Shadow(k, v) => {
+                    debug!("Merging entry from snapshot, k={:?}, v={:?}", k, v);
+                    server.db.merge_entry(k, v);
+                    server.dirty += 1;
+                },
+                SnapshotEntry::Deletes(k, uuid) => server.db.delete(&k, uuid),
+                SnapshotEntry::Expires(k, t) => server.db.expire_at(&k, t),
+                // the versions of the snapshot are told by VERSIONS following it, to the ones who relay
+                SnapshotEntry::Versions(..) => {}
+                SnapshotEntry::Node(node_id, node_alias, _addr, uuid) => {
+                    server.observe_uuid(uuid, &self.meta.he.addr);
+                    server.versions.observe(node_id, uuid);
+                    self.uuid_he_sent = uuid;
+                    self.meta.he.id = node_id;
+                    self.meta.he.alias = node_alias;
+                },
+                SnapshotEntry::ReplicaAdd(add_time, node_id, node_alias, addr, uuid) => {
+                    if node_id == self.meta.myself.id {
+                        continue;
+                    }
+                    debug!("Found a new replica from the snapshot, node_id={}, alias={}, addr={}, uuid={}", node_id, node_alias, addr, uuid);
+                    server.versions.observe(node_id, uuid);
+                    let mut r = Replica::new(addr.clone(), server.node_id, server.config.node_alias.clone(), format!("{}:{}", server.config.ip, server.config.port), server.config.repl_diskless_load);
+                    r.meta.he.id = node_id;
+                    r.meta.uuid_he_sent = uuid;
+                    r.meta.he.alias = node_alias;
+                    r.events = Some(server.events.new_consumer());
+                    if server.replicas.add_replica(addr.clone(), r.meta.clone(), add_time) {
+                        let mut sl = SharedLink::from(r);
+                        let client_chan = server.client_chan.clone();
+                        tokio::spawn(async move {
+                            sl.prepare(client_chan).await;
+                        });
+                    }
+                },
+                SnapshotEntry::ReplicaDel(addr, t) => {
+                    server.replicas.remove_replica(&addr, t);
+                }
+            }
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+        self.snapshot_entries.is_empty()
     }
 
     fn apply_his_replicates(&mut self, server: &mut Server, cmd: Message) -> Result<bool, CstError> {
@@ -205,32 +418,112 @@ impl Puller {
                     return Ok(false);
                 } else {
                     let current_uuid = args.next_u64()?;
+                    server.observe_uuid(current_uuid, &self.meta.he.addr);
                     let rpl_command_name = args.next_bytes()?;
                     let args: Vec<Message> = args.collect();
                     match Cmd::new(rpl_command_name.as_bytes(), args) {
                         Err(e) => {
-                            error!("the replica named {} sent us an unknown command {}", self.meta.he.alias, e);
+                            self.skip_unknown_command(server, &e);
                             self.uuid_he_sent = current_uuid;
-                            // we treat it not as a bug because the replica may have a greater version.
                         }
                         Ok(cmd) => {
-                            // Note! We do not replicate those commands received from our replicas.
-                            if let Err(e) = cmd.exec_detail(server, None, nodeid, current_uuid, false) {
-                                error!("error '{}' occurred when executing command from our replica {}", e, self.meta.he.alias);
-                            }
+                            // it's skipped if another replica has relayed it to us
+                            server.apply_replicated(nodeid, nodeid, last_uuid, current_uuid, &cmd, &self.meta.he.addr);
                             self.uuid_he_sent = current_uuid;
                         }
                     };
                 }
             },
+            b"relay" => {
+                let (origin, prev_uuid, uuid) = (args.next_u64()?, args.next_u64()?, args.next_u64()?);
+                let rpl_command_name = args.next_bytes()?;
+                match Cmd::new(rpl_command_name.as_bytes(), args.collect()) {
+                    Err(e) => {
+                        self.skip_unknown_command(server, &e);
+                        if prev_uuid == server.versions.uuid_of(origin) {
+                            server.versions.observe(origin, uuid);
+                        }
+                    }
+                    Ok(cmd) => if server.apply_relayed(origin, self.meta.he.id, prev_uuid, uuid, &cmd, &self.meta.he.addr) {
+                        server.metrics.incr_relayed_commands();
+                    },
+                }
+            },
+            b"versions" => {
+                // the writes of others in the snapshot he has just sent us
+                for (origin, uuid) in next_versions(&mut args)?.iter() {
+                    if *origin != self.meta.myself.id {
+                        server.versions.observe(*origin, *uuid);
+                    }
+                }
+            },
+            b"merkle" => {
+                let (horizon, level, index) = (args.next_u64()?, args.next_u64()? as usize, args.next_u64()? as usize);
+                // the hashes are any 64 bits
+                let mut his = Vec::with_capacity(16);
+                while let Ok(h) = args.next_i64() {
+                    his.push(h as u64);
+                }
+                if server.config.anti_entropy_interval > 0 {
+                    let replies = server.anti_entropy_reply(horizon, level, index, &his)?;
+                    self.replies.extend(replies);
+                }
+            },
+            b"repair" => {
+                let (horizon, reply, cnt) = (args.next_u64()?, args.next_u64()?, args.next_u64()?);
+                let buckets = (0..cnt).map(|_| args.next_u64().map(|b| b as usize)).collect::<Result<Vec<usize>, CstError>>()?;
+                for _ in 0..args.next_u64()? {
+                    if let Some((k, v)) = self.repairs.pop_front() {
+                        server.repair(k, v);
+                    }
+                }
+                if reply == 1 {
+                    self.replies.push(server.repair_msg(horizon, false, &buckets)?);
+                }
+            },
             b"replack" => {
                 self.uuid_he_acked = args.next_u64()?;
+                // his current uuid, which our clock is advanced past, and his wall clock which tells how far it's
+                // from ours. the older versions don't tell the later, but their uuids are of their wall clocks.
+                if let Ok(his_uuid) = args.next_u64() {
+                    server.observe_uuid(his_uuid, &self.meta.he.addr);
+                    let his_time = args.next_u64().unwrap_or(his_uuid >> UUID_COUNTER_BITS);
+                    self.clock_skew_ms = Some(his_time as i64 - now_mil() as i64);
+                }
+                // and the writes of every node he has, which the older versions don't tell
+                let versions = next_versions(&mut args)?;
+                if versions.iter().next().is_some() {
+                    self.his_versions = Some(versions);
+                }
+            },
+            b"fullsync" => {
+                info!("The replica at {} is going to resync us with a full snapshot", self.meta.he.addr);
+                self.stats = PullStat::SyncSent;
+                return Ok(false);
             },
             _ => {
-                error!("we met an invalid command {:?} from our replica {} which should be `replicate` or `replack`", cmd_name, self.meta.he.addr);
+                error!("we met an invalid command {:?} from our replica {} which should be `replicate`, `relay`, `versions`, `merkle`, `repair`, `replack` or `fullsync`", cmd_name, self.meta.he.addr);
                 return Err(CstError::InvalidRequestMsg(format!("{:?}", cmd_name.to_vec())));
             }
         }
         Ok(true)
     }
-}
\ No newline at end of file
+}
+// the pairs of node id and uuid following the other arguments
+fn next_versions<T: Iterator<Item = Message>>(args: &mut T) -> Result<VClock<u64>, CstError> {
+    let mut versions = VClock::default();
+    while let Ok(node_id) = args.next_u64() {
+        versions.observe(node_id, args.next_u64()?);
+    }
+    Ok(versions)
+}
+
+fn is_command(msg: &Message, cmd_name: &[u8]) -> bool {
+    match msg {
+        Message::Array(args) => match args.first() {
+            Some(Message::BulkString(name)) => name.as_bytes().eq_ignore_ascii_case(cmd_name),
+            _ => false,
+        },
+        _ => false,
+    }
+}
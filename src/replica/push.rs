@@ -1,11 +1,11 @@
-use crate::{CstError, now_secs};
-use nix::sys::wait::waitpid;
+use crate::{CstError, now_mil, now_secs};
+use crate::crdt::vclock::VClock;
 use tokio::fs::OpenOptions;
 use crate::resp::Message;
-use crate::server::{EVENT_TYPE_REPLICATED, Server, EventsConsumer};
-use nix::unistd::Pid;
-use crate::replica::replica::ReplicaMeta;
+use crate::server::{DumpWaiter, EVENT_TYPE_REPLICATED, Server, EventsConsumer};
+use crate::replica::replica::{command_for, ReplicaMeta, REPL_CAPA_ANTI_ENTROPY, REPL_CAPA_RELAY};
 use crate::conn::writer::Writer;
+use crate::snapshot::{convert_snapshot, snapshot_encoding, Compression, SNAPSHOT_FORMAT};
 use tokio::time::sleep;
 use tokio::time::Duration;
 
@@ -18,16 +18,35 @@ pub struct Pusher {
     pub(crate) stats: PushStat,
     pub(crate) writer: Writer,
     pub(crate) events: EventsConsumer,
+    // the seq of the latest write of others in our relay_log we've relayed or skipped
+    pub(crate) relay_i_sent: u64,
+    // the writes of every node he has as far as we know, and when he told us the latest, in milliseconds.
+    // nothing is relayed to the older versions who don't tell us.
+    pub(crate) his_versions: Option<VClock<u64>>,
+    pub(crate) his_versions_time: u64,
+    pub(crate) his_acks: u64,
+    // he was found to miss some writes we can't relay to him, when he had acked us so many times
+    pub(crate) relay_gap_at: Option<u64>,
+    // the writes of others in the snapshot we're sending him
+    pub(crate) versions_to_send: Option<VClock<u64>>,
+    pub(crate) latest_anti_entropy_time: u64,
 }
 
 #[derive(Debug, Clone)]
 pub enum PushStat {
     SyncReceived,
-    WaitingDump(Option<Pid>, String, u64),
+    WaitingDump(Option<DumpWaiter>, String, u64),
     SendingSnapshot(String, u64),
+    // a snapshot of what's written since his position, which is dumped for him only by the main thread
+    // in chunks, and then sent
+    DumpingDelta(String, u64),
+    SendingDelta(String, u64),
     PushingCommands,
 }
 
+// the deltas for our replicas are dumped into these files, which are removed once sent.
+pub const DELTA_SNAPSHOT_PREFIX: &str = "delta.";
+
 impl Pusher {
     // send out snapshot to the replica.
     // this function runs in io threads, not in the main one.
@@ -37,30 +56,41 @@ impl Pusher {
                 PushStat::SyncReceived => {
                     return Ok(());
                 },
-                PushStat::WaitingDump(pid, file_name, tombstone) => {
+                // the main thread is dumping it
+                PushStat::DumpingDelta(..) => {
+                    return Ok(());
+                },
+                PushStat::WaitingDump(waiter, file_name, tombstone) => {
                     debug!("I am at WaitingDump stat in the view of replica at {}", self.meta.he.addr);
-                    let pid_to_wait = pid.take();
-                    tokio::task::spawn_blocking(move || {
-                        if let Some(pid) = pid_to_wait {
-                            if let Err(e) = waitpid(pid, None) {
-                                error!("Error occurred when waiting for child process, {}", e);
+                    // the snapshot is dumped by a BGSAVE, which is finished by the main thread
+                    if let Some(mut rx) = waiter.take() {
+                        loop {
+                            if let Some(ok) = *rx.borrow() {
+                                if !ok {
+                                    error!("Failed to dump the snapshot for the replica at {}", self.meta.he.addr);
+                                    return Err(CstError::SystemError);
+                                }
+                                break;
                             }
-                            debug!("child process terminated");
+                            rx.changed().await.map_err(|_| CstError::SystemError)?;
                         }
-                    }).await.map_err(|x| {
-                        error!("Error occurred when waiting for the blocking task who's waiting for the termination of child process, error: {}", x);
-                        CstError::SystemError
-                    })?;
+                        debug!("the snapshot has been dumped");
+                    }
                     self.stats = PushStat::SendingSnapshot(file_name.clone(), *tombstone);
                 }
                 PushStat::SendingSnapshot(filename, uuid_tombstone) => {
                     debug!("Child process finished dumping the snapshot");
-                    let mut snapshot = OpenOptions::new().read(true).open(filename).await?;
-                    let snapshot_size = snapshot.metadata().await?.len();
-                    self.writer.send_msg(Message::Integer(snapshot_size as i64)).await?;
-                    self.writer.send_file(&mut snapshot).await?;
-                    self.uuid_i_sent = *uuid_tombstone;
-                    debug!("Finished sending the snapshot to replica at {}, before uuid={}", self.meta.he.addr, *uuid_tombstone);
+                    let (filename, uuid_tombstone) = (filename.clone(), *uuid_tombstone);
+                    self.send_snapshot(&filename, uuid_tombstone).await?;
+                    self.stats = PushStat::PushingCommands;
+                },
+                PushStat::SendingDelta(filename, uuid_tombstone) => {
+                    let (filename, uuid_tombstone) = (filename.clone(), *uuid_tombstone);
+                    let r = self.send_snapshot(&filename, uuid_tombstone).await;
+                    if let Err(e) = tokio::fs::remove_file(&filename).await {
+                        warn!("Failed to remove the delta {} because {}", filename, e);
+                    }
+                    r?;
                     self.stats = PushStat::PushingCommands;
                 },
                 PushStat::PushingCommands => {
@@ -70,6 +100,34 @@ impl Pusher {
         }
     }
 
+    // send him a snapshot, after which he has our data up to the uuid
+    async fn send_snapshot(&mut self, filename: &str, uuid_tombstone: u64) -> Result<(), CstError> {
+        // a replica running an older version gets a snapshot in the format he knows
+        let (format, compression) = snapshot_encoding(filename).await?;
+        let readable = format <= self.meta.snapshot_format
+            && (compression == Compression::None || self.meta.snapshot_compressions.contains(&compression));
+        let converted = if !readable {
+            let format = std::cmp::min(self.meta.snapshot_format, SNAPSHOT_FORMAT);
+            let converted = format!("{}.{}.v{}", filename, self.meta.he.id, format);
+            info!("Converting the snapshot into format {} without compression for the replica at {}", format, self.meta.he.addr);
+            convert_snapshot(filename, &converted, format, Compression::None).await?;
+            Some(converted)
+        } else {
+            None
+        };
+        let mut snapshot = OpenOptions::new().read(true).open(converted.as_deref().unwrap_or(filename)).await?;
+        let snapshot_size = snapshot.metadata().await?.len();
+        self.writer.send_msg(Message::Integer(snapshot_size as i64)).await?;
+        let r = self.writer.send_file(&mut snapshot).await;
+        if let Some(converted) = converted {
+            let _ = tokio::fs::remove_file(converted).await;
+        }
+        r?;
+        self.uuid_i_sent = uuid_tombstone;
+        debug!("Finished sending the snapshot {} to replica at {}, before uuid={}", filename, self.meta.he.addr, uuid_tombstone);
+        Ok(())
+    }
+
     // waiting for an EVENT_TYPE_REPLICATED event. If it comes, we know there
     // are some new commands that ought to be replicated.
     pub async fn watching_my_replog(&mut self) -> Result<(), CstError> {
@@ -92,25 +150,36 @@ impl Pusher {
         match self.stats {
             PushStat::SyncReceived => {
                 debug!("Replica at {} is in SyncReceived stat", self.meta.he.addr);
-                if self.meta.uuid_i_sent > 0 && server.get_repl_first_uuid() < self.meta.uuid_i_sent {
+                if self.meta.uuid_i_sent > 0 && server.repl_log_continuable(self.meta.uuid_i_sent) {
                     self.writer.write_msg(Message::Integer(0));
                     self.stats = PushStat::PushingCommands;
+                } else if self.sync_with_delta(server, self.meta.uuid_i_sent) {
+                    debug!("Dumping what's written since uuid {} for the replica at {}", self.meta.uuid_i_sent, self.meta.he.addr);
                 } else {
-                    match server.dump_snapshot_in_background() {
-                        Err(e) => {
-                            error!("Failed to dump the snapshot for {}", e);
-                            return Err(CstError::SystemError);
-                        }
-                        Ok((pid, file_name, tombstone)) => {
-                            debug!("Forked a child process to dump the snapshot, pid={:?}", pid);
-                            // self.stat = ReplicaStat::SyncingSnapshotTo((pid, file_name, tombstone));
-                            self.stats = PushStat::WaitingDump(pid, file_name, tombstone);
-                        }
-                    }
+                    self.sync_with_snapshot(server)?;
                 }
                 server.replicas.update_replica_identity(&self.meta.he);
+                server.replicas.update_replica_protocol(&self.meta.he, self.meta.protocol_version, self.meta.capabilities);
             },
+            PushStat::DumpingDelta(ref file_name, tombstone) => {
+                let file_name = file_name.clone();
+                match server.dump_delta_incrementally(&file_name) {
+                    Ok(false) => {}
+                    Ok(true) => {
+                        server.metrics.incr_sync_delta();
+                        self.stats = PushStat::SendingDelta(file_name, tombstone);
+                    }
+                    Err(e) => {
+                        error!("Failed to dump the delta for the replica at {} because {}, send him a full snapshot instead", self.meta.he.addr, e);
+                        self.sync_with_snapshot(server)?;
+                    }
+                }
+            }
             PushStat::PushingCommands => {
+                // the older versions know nothing about the writes of others
+                if let Some(versions) = self.versions_to_send.take().filter(|_| self.meta.capable_of(REPL_CAPA_RELAY)) {
+                    self.writer.write_msg(with_versions(Message::Array(vec![Message::BulkString("VERSIONS".into())]), &versions));
+                }
                 let mut sent = 0;
                 for _ in 0..16 {
                     let r = self.send_my_replicates(server);
@@ -118,16 +187,37 @@ impl Pusher {
                         Ok(true) => sent += 1,
                         Ok(false) => break,
                         Err(_) => {
-                            error!("the replica {} is too delayed", self.meta.he.addr);
-                            // TODO
-                            break;
+                            error!("the replica {} is too delayed, resync it with a full snapshot", self.meta.he.addr);
+                            return self.resync_with_snapshot(server, true);
                         }
                     }
                 }
+                match self.send_relays(server) {
+                    Ok(relayed) => sent += relayed,
+                    Err(_) => {
+                        error!("the replica {} misses some writes of others we can't relay, resync it with a full snapshot", self.meta.he.addr);
+                        return self.resync_with_snapshot(server, false);
+                    }
+                }
                 debug!("Sent {} commands to the replica at {}", sent, self.meta.he.addr);
                 let now = now_secs();
+                // compare our data with his from time to time
+                let interval = server.config.anti_entropy_interval;
+                if interval > 0 && self.meta.capable_of(REPL_CAPA_ANTI_ENTROPY) && self.latest_anti_entropy_time + interval <= now {
+                    let horizon = server.anti_entropy_horizon();
+                    self.writer.write_msg(server.merkle_tree(horizon).to_message(horizon, 0, 0));
+                    self.latest_anti_entropy_time = now;
+                }
                 if self.latest_ack_time + 4 < now {
-                    self.writer.write_msg(mkcmd!("REPLACK", uuid_he_sent, server.next_uuid(false)));
+                    // our uuid is ahead of our wall clock if we've observed a later one, so he's told both
+                    let mut ack = mkcmd!("REPLACK", uuid_he_sent, server.next_uuid(false), now_mil());
+                    // and where we are with every node, so that he knows what to relay to us
+                    if self.meta.capable_of(REPL_CAPA_RELAY) {
+                        let mut versions = server.versions.clone();
+                        versions.observe(server.node_id, server.get_repl_last_uuid());
+                        ack = with_versions(ack, &versions);
+                    }
+                    self.writer.write_msg(ack);
                     self.latest_ack_time = now;
                 }
             },
@@ -136,17 +226,136 @@ impl Pusher {
         Ok(())
     }
 
-    // TODO, need to tell the caller whether it's uuid is too late
+    // the commands the replica needs have been dropped from our repl_log, so we tell him to
+    // expect a snapshot and start to dump one. The snapshot is sent in the io threads as usual.
+    // a delta is not enough if he misses the writes of others, which are not in it.
+    fn resync_with_snapshot(&mut self, server: &mut Server, delta: bool) -> Result<(), CstError> {
+        self.writer.write_msg(mkcmd!("FULLSYNC", server.node_id));
+        if delta && self.sync_with_delta(server, self.uuid_i_sent) {
+            return Ok(());
+        }
+        self.sync_with_snapshot(server)
+    }
+
+    fn sync_with_snapshot(&mut self, server: &mut Server) -> Result<(), CstError> {
+        server.metrics.incr_sync_full();
+        match server.dump_snapshot_in_background() {
+            Err(e) => {
+                error!("Failed to dump the snapshot for {}", e);
+                Err(CstError::SystemError)
+            }
+            Ok((waiter, file_name, tombstone, versions)) => {
+                debug!("Dumping the snapshot for the replica at {}, waiting={}", self.meta.he.addr, waiter.is_some());
+                self.stats = PushStat::WaitingDump(waiter, file_name, tombstone);
+                self.snapshot_versions(versions);
+                Ok(())
+            }
+        }
+    }
+
+    // he's going to have the writes of others in our snapshot, after which we relay him the rest
+    fn snapshot_versions(&mut self, versions: VClock<u64>) {
+        if let Some(his) = &mut self.his_versions {
+            his.merge(&versions);
+        }
+        self.relay_i_sent = 0;
+        self.relay_gap_at = None;
+        self.versions_to_send = Some(versions);
+    }
+
+    pub fn acked_versions(&mut self, versions: VClock<u64>) {
+        match &mut self.his_versions {
+            None => self.his_versions = Some(versions),
+            Some(his) => his.merge(&versions),
+        }
+        self.his_versions_time = now_mil();
+        self.his_acks += 1;
+    }
+
+    // relay him the writes of others he doesn't have as far as we know from his latest ack. those applied
+    // after it are left until his next one, as he may well get them from the nodes themselves.
+    // returns Err(ReplicateCommandsLost) if he still misses some writes we can't relay after his next ack.
+    fn send_relays(&mut self, server: &mut Server) -> Result<usize, CstError> {
+        let his = match &mut self.his_versions {
+            None => return Ok(0),
+            Some(v) => v,
+        };
+        let mut sent = 0;
+        while let Some(r) = server.relay_log_next(self.relay_i_sent) {
+            if r.applied_at > self.his_versions_time {
+                break;
+            }
+            let version = his.uuid_of(r.origin);
+            if r.origin != self.meta.he.id && r.via != self.meta.he.id && r.uuid > version {
+                if r.prev_uuid != version {
+                    match self.relay_gap_at {
+                        None => self.relay_gap_at = Some(self.his_acks),
+                        Some(acks) if acks < self.his_acks => return Err(CstError::ReplicateCommandsLost(self.meta.he.addr.clone())),
+                        Some(_) => {},
+                    }
+                    break;
+                }
+                self.writer.write_msg(command_for(r.to_message(), self.meta.capabilities));
+                his.set(r.origin, r.uuid);
+                sent += 1;
+            }
+            self.relay_i_sent = r.seq;
+            self.relay_gap_at = None;
+        }
+        Ok(sent)
+    }
+
+    // he has our data up to the uuid, so a snapshot of what's written since then is enough for him, which is
+    // much smaller than a full one if only a few of our keys are written. returns false if it's not available.
+    fn sync_with_delta(&mut self, server: &mut Server, uuid: u64) -> bool {
+        if uuid == 0 || !server.db.delta_available(uuid) {
+            return false;
+        }
+        let file_name = format!("{}{}", DELTA_SNAPSHOT_PREFIX, self.meta.he.addr);
+        match server.begin_delta_dump(uuid, &file_name) {
+            Ok(tombstone) => {
+                self.stats = PushStat::DumpingDelta(file_name, tombstone);
+                true
+            }
+            Err(e) => {
+                error!("Failed to dump the delta for the replica at {} because {}", self.meta.he.addr, e);
+                false
+            }
+        }
+    }
+
+    // returns Ok(false) if there is nothing new to send, and Err(ReplicateDelayed)
+    // if the commands following uuid_i_sent are no longer in our repl_log.
     fn send_my_replicates(&mut self, server: &mut Server) -> Result<bool, CstError> {
         match server.repl_log_next(self.uuid_i_sent) {
-            None => Ok(false),
+            None => {
+                if server.repl_log_continuable(self.uuid_i_sent) {
+                    Ok(false)
+                } else {
+                    Err(CstError::ReplicateDelayed)
+                }
+            }
             Some((uuid, msg)) => {
                 debug!("Sending my replicate with uuid={} to the replica at {}", uuid, self.meta.he.addr);
-                self.writer.write_msg(msg);
+                self.writer.write_msg(command_for(msg, self.meta.capabilities));
                 self.uuid_i_sent = uuid;
                 server.replicas.update_replica_push_stat(&self.meta.he, uuid, self.uuid_i_acked);
                 Ok(true)
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// the pairs of node id and uuid follow the other arguments
+fn with_versions(msg: Message, versions: &VClock<u64>) -> Message {
+    match msg {
+        Message::Array(mut args) => {
+            for (node_id, uuid) in versions.iter() {
+                args.push(Message::Integer(*node_id as i64));
+                args.push(Message::Integer(*uuid as i64));
+            }
+            Message::Array(args)
+        }
+        others => others,
+    }
+}
@@ -1,21 +1,70 @@
 use crate::crdt::lwwhash::LWWHash;
 use crate::resp::Message;
 use std::io::Write;
-use crate::snapshot::{SnapshotWriter, SNAPSHOT_FLAG_REPLICA_ADD, SNAPSHOT_FLAG_REPLICA_REM};
+use crate::snapshot::{Compression, SnapshotWriter, SNAPSHOT_COMPRESSIONS, SNAPSHOT_FLAG_REPLICA_ADD, SNAPSHOT_FLAG_REPLICA_REM, SNAPSHOT_FORMAT_COMPRESSED, SNAPSHOT_FORMAT_LEGACY};
 use std::collections::HashMap;
 use crate::conn::Conn;
 use crate::server::{EventsConsumer, Server};
 use crate::replica::pull::{Puller, PullStat};
 use crate::link::{Link, LinkType};
-use crate::CstError;
+use crate::{CstError, now_secs};
 use tokio::net::TcpSocket;
 use tokio::time::{sleep, Duration};
 use crate::cmd::NextArg;
 use crate::replica::push::{Pusher, PushStat};
 
+// the version of the replication protocol, which is told in SYNC after the snapshot format and the compressions,
+// along with what we're capable of. The older versions tell neither, they're of version 1 and capable of nothing.
+pub const REPL_PROTOCOL_VERSION: u64 = 2;
+// the oldest version we're able to replicate with, the nodes of an older one are refused in the handshake
+pub const REPL_PROTOCOL_VERSION_MIN: u64 = 1;
+
+// relaying the writes of others and telling where we are with every node, by `relay`, VERSIONS and REPLACK
+pub const REPL_CAPA_RELAY: u64 = 1<<0;
+// comparing the data by Merkle trees and repairing the differences, by MERKLE and REPAIR
+pub const REPL_CAPA_ANTI_ENTROPY: u64 = 1<<1;
+// taking the values of a deleted counter back in the slot of the deleter, by `delcounter`
+pub const REPL_CAPA_DELCOUNTER: u64 = 1<<2;
+pub const REPL_CAPABILITIES: u64 = REPL_CAPA_RELAY | REPL_CAPA_ANTI_ENTROPY | REPL_CAPA_DELCOUNTER;
+
+// they're told by names, so that every version knows which of them the others have
+const REPL_CAPABILITY_NAMES: [(u64, &str); 3] = [(REPL_CAPA_RELAY, "relay"), (REPL_CAPA_ANTI_ENTROPY, "anti-entropy"), (REPL_CAPA_DELCOUNTER, "delcounter")];
+
+pub fn capabilities_names(capabilities: u64) -> String {
+    REPL_CAPABILITY_NAMES.iter().filter(|(c, _)| capabilities & c != 0).map(|(_, n)| *n).collect::<Vec<&str>>().join(",")
+}
+
+// the `replicate` or `relay` of a command for a replica of the capabilities. The ones not knowing `delcounter`
+// get the `delcnt` of the same values, which take them back in the slots of their nodes, the counter has the same
+// value on them then.
+pub fn command_for(msg: Message, capabilities: u64) -> Message {
+    if capabilities & REPL_CAPA_DELCOUNTER != 0 {
+        return msg;
+    }
+    match msg {
+        Message::Array(mut args) => {
+            if let Some(Message::BulkString(name)) = args.get_mut(4) {
+                if name.as_bytes() == b"delcounter" {
+                    *name = "delcnt".into();
+                }
+            }
+            Message::Array(args)
+        }
+        others => others,
+    }
+}
+
+// the names we don't know are of the newer versions, which are ignored
+pub fn parse_capabilities(s: &str) -> u64 {
+    s.split(',')
+        .filter_map(|x| REPL_CAPABILITY_NAMES.iter().find(|(_, n)| n.eq_ignore_ascii_case(x.trim())))
+        .fold(0, |capabilities, (c, _)| capabilities | c)
+}
+
 pub struct ReplicaManager {
     myself: ReplicaIdentity,
-    replicas: LWWHash<String, ReplicaMeta>,
+    // the replicas are met and forgotten only by our clients, so the uuids are enough to order them
+    replicas: LWWHash<String, ReplicaMeta, u64>,
 }
 
 impl ReplicaManager {
@@ -54,12 +103,38 @@ impl ReplicaManager {
         }
     }
 
+    pub fn update_replica_loading(&mut self, id: &ReplicaIdentity, loading: Option<SnapshotLoading>) {
+        if let Some(r) = self.replicas.get_mut(&id.addr) {
+            r.loading = loading;
+        }
+    }
+
+    pub fn update_replica_clock_skew(&mut self, id: &ReplicaIdentity, skew: Option<i64>) {
+        if let Some(r) = self.replicas.get_mut(&id.addr) {
+            r.clock_skew_ms = skew;
+        }
+    }
+
+    // the replicas alive, sorted by their addresses
+    pub fn metas(&self) -> Vec<&ReplicaMeta> {
+        let mut metas: Vec<&ReplicaMeta> = self.replicas.add.iter().filter(|(addr, _)| !self.replicas.removed(addr)).map(|(_, (_, meta))| meta).collect();
+        metas.sort_by(|a, b| a.he.addr.cmp(&b.he.addr));
+        metas
+    }
+
     pub fn update_replica_identity(&mut self, id: &ReplicaIdentity) {
         if let Some(r) = self.replicas.get_mut(&id.addr) {
             r.he = id.clone();
         }
     }
 
+    pub fn update_replica_protocol(&mut self, id: &ReplicaIdentity, version: u64, capabilities: u64) {
+        if let Some(r) = self.replicas.get_mut(&id.addr) {
+            r.protocol_version = version;
+            r.capabilities = capabilities;
+        }
+    }
+
     pub fn generate_replicas_reply(&self, current_uuid: u64) -> Message {
         let replicas = {
             let replica_cnt = self.replicas.add.len();
@@ -98,26 +173,53 @@ impl ReplicaManager {
     }
 
     pub fn dump_snapshot<T: Write>(&self, w: &mut SnapshotWriter<T>) -> Result<(), CstError> {
+        let _ = w.write_section(SNAPSHOT_FLAG_REPLICA_ADD, self.replicas.add.len())?;
         for (_, (t, meta)) in self.replicas.add.iter() {
-            w.write_byte(SNAPSHOT_FLAG_REPLICA_ADD)?
-                .write_integer(*t as i64)?
-                .write_integer(meta.he.id as i64)?
-                .write_integer(meta.he.alias.len() as i64)?
-                .write_bytes(meta.he.alias.as_bytes())?
-                .write_integer(meta.he.addr.len() as i64)?
-                .write_bytes(meta.he.addr.as_ref())?
-                .write_integer(meta.uuid_he_sent as i64)?;
+            w.write_item(|w| {
+                let _ = w.write_integer(*t as i64)?
+                    .write_integer(meta.he.id as i64)?
+                    .write_integer(meta.he.alias.len() as i64)?
+                    .write_bytes(meta.he.alias.as_bytes())?
+                    .write_integer(meta.he.addr.len() as i64)?
+                    .write_bytes(meta.he.addr.as_ref())?
+                    .write_integer(meta.uuid_he_sent as i64)?;
+                Ok(())
+            })?;
         }
 
+        let _ = w.write_section(SNAPSHOT_FLAG_REPLICA_REM, self.replicas.del.len())?;
         for (addr, t) in self.replicas.del.iter() {
-            w.write_byte(SNAPSHOT_FLAG_REPLICA_REM)?
-                .write_integer(addr.len() as i64)?
-                .write_bytes(addr.as_bytes())?
-                .write_integer(*t as i64)?;
+            w.write_item(|w| {
+                let _ = w.write_integer(addr.len() as i64)?
+                    .write_bytes(addr.as_bytes())?
+                    .write_integer(*t as i64)?;
+                Ok(())
+            })?;
         }
         Ok(())
     }
 
+    pub fn get_replica(&self, addr: &String) -> Option<&ReplicaMeta> {
+        self.replicas.get(addr)
+    }
+
+    // the replication progress with each replica, which is saved periodically so that we can
+    // continue replicating with them from where we stopped after a restart.
+    pub fn positions(&self) -> Vec<ReplicaPosition> {
+        let mut positions: Vec<ReplicaPosition> = self.replicas.add.iter().filter(|(addr, _)| !self.replicas.removed(addr)).map(|(addr, (t, meta))| ReplicaPosition{
+            addr: addr.clone(),
+            id: meta.he.id,
+            alias: meta.he.alias.clone(),
+            add_time: *t,
+            uuid_he_sent: meta.uuid_he_sent,
+            uuid_he_acked: meta.uuid_he_acked,
+            uuid_i_sent: meta.uuid_i_sent,
+            uuid_i_acked: meta.uuid_i_acked,
+        }).collect();
+        positions.sort_by(|a, b| a.addr.cmp(&b.addr));
+        positions
+    }
+
     pub fn replica_progress(&self) -> HashMap<String, u64> {
         let mut r = HashMap::with_capacity(self.replicas.add.len());
         for (_, (_, v)) in self.replicas.add.iter() {
@@ -141,11 +243,91 @@ pub struct ReplicaMeta {
     pub status: &'static str,
 
     pub uuid_he_sent_last_dump: u64,
+    // the newest snapshot format and the compressions he's able to load, which are told in SYNC
+    pub snapshot_format: u8,
+    pub snapshot_compressions: Vec<Compression>,
+    // the version of the replication protocol he speaks and what he's capable of, which are told in SYNC too
+    pub protocol_version: u64,
+    pub capabilities: u64,
+    // whether his snapshot is loaded while being received, rather than from a local copy
+    pub diskless_load: bool,
+    // the progress of loading his snapshot, if we are
+    pub loading: Option<SnapshotLoading>,
+    // his wall clock minus ours in milliseconds, including the latency of the network
+    pub clock_skew_ms: Option<i64>,
 
     pub latest_acked_time: u64,
     pub close: bool,
 }
 
+impl ReplicaMeta {
+    // the fields following the positions in his SYNC, which the older versions leave out.
+    // Err if his protocol is too old for us to replicate with.
+    pub fn read_sync_capabilities<T: Iterator<Item = Message>>(&mut self, args: &mut T) -> Result<(), CstError> {
+        self.snapshot_format = args.next_u64().map(|x| x as u8).unwrap_or(SNAPSHOT_FORMAT_LEGACY);
+        self.snapshot_compressions = args.next_string().map(|x| Compression::parse_list(&x)).unwrap_or_default();
+        self.protocol_version = args.next_u64().unwrap_or(1);
+        self.capabilities = args.next_string().map(|x| parse_capabilities(&x)).unwrap_or(0);
+        if self.protocol_version < REPL_PROTOCOL_VERSION_MIN {
+            return Err(CstError::IncompatibleReplica(self.he.addr.clone(), self.protocol_version));
+        }
+        if self.protocol_version > REPL_PROTOCOL_VERSION {
+            warn!("The replica at {} speaks a newer replication protocol of version {}, the commands we don't know from him are skipped", self.he.addr, self.protocol_version);
+        }
+        if self.capabilities & REPL_CAPABILITIES != REPL_CAPABILITIES {
+            info!("The replica at {} of protocol version {} is capable of only [{}], the others are not used with him",
+                self.he.addr, self.protocol_version, capabilities_names(self.capabilities));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    pub fn capable_of(&self, capability: u64) -> bool {
+        self.capabilities & capability != 0
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotLoading {
+    // the size of his snapshot, the bytes of it we've received and the entries we've merged
+    pub size: usize,
+    pub received: usize,
+    pub merged: u64,
+    // the entries loaded but not merged yet
+    pub pending: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplicaPosition {
+    pub addr: String,
+    pub id: u64,
+    pub alias: String,
+    pub add_time: u64,
+    pub uuid_he_sent: u64,
+    pub uuid_he_acked: u64,
+    pub uuid_i_sent: u64,
+    pub uuid_i_acked: u64,
+}
+
+pub fn save_positions(file_name: &str, positions: &[ReplicaPosition]) -> Result<(), CstError> {
+    let tmp_name = format!("{}.tmp", file_name);
+    let content = serde_json::to_vec(positions).map_err(|e| CstError::IoError(e.into()))?;
+    let mut f = std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_name)?;
+    f.write_all(&content)?;
+    f.sync_all()?;
+    std::fs::rename(tmp_name, file_name)?;
+    Ok(())
+}
+
+pub fn load_positions(file_name: &str) -> Result<Vec<ReplicaPosition>, CstError> {
+    let content = match std::fs::read(file_name) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_slice(&content).map_err(|e| CstError::IoError(e.into()))
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct ReplicaIdentity{
     pub id: u64,
@@ -207,7 +389,7 @@ impl Link for Replica {
 }
 
 impl Replica {
-    pub fn new(his_addr: String, my_id: u64, my_alias: String, my_addr: String) -> Self {
+    pub fn new(his_addr: String, my_id: u64, my_alias: String, my_addr: String, diskless_load: bool) -> Self {
         Self{
             meta: ReplicaMeta{
                 myself: ReplicaIdentity{id: my_id, alias: my_alias, addr: my_addr},
@@ -217,6 +399,13 @@ impl Replica {
                 uuid_he_sent: 0,
                 uuid_i_acked: 0,
                 uuid_he_sent_last_dump: 0,
+                snapshot_format: SNAPSHOT_FORMAT_LEGACY,
+                snapshot_compressions: vec![],
+                protocol_version: 1,
+                capabilities: 0,
+                diskless_load,
+                loading: None,
+                clock_skew_ms: None,
                 close: false,
                 latest_acked_time: 0,
                 status: "",
@@ -237,7 +426,24 @@ impl Replica {
                     pusher.writer.write_msg(Message::Error("Stop replication because you're removed from the cluster".into()));
                     self.to_close = true;
                 } else {
-                    puller.merge_replicates_in_main(server)?;
+                    match puller.merge_replicates_in_main(server) {
+                        Err(CstError::ReplicateCommandsLost(_)) => {
+                            self.resync();
+                            return Ok(());
+                        }
+                        r => r?,
+                    }
+                    if let Some(versions) = puller.his_versions.take() {
+                        pusher.acked_versions(versions);
+                    }
+                    // they'd be mixed with the snapshot we're sending him otherwise, and he asks again later anyway
+                    if let PushStat::PushingCommands = pusher.stats {
+                        for reply in puller.replies.drain(..) {
+                            pusher.writer.write_msg(reply);
+                        }
+                    } else {
+                        puller.replies.clear();
+                    }
                     pusher.push_to_replica_in_main(server, puller.uuid_he_sent)?;
                 }
             }
@@ -247,6 +453,18 @@ impl Replica {
     }
     
 
+    // some of his commands are lost, so we reconnect and sync again from the latest one we have, after
+    // which he sends us the rest of his commands, a delta, or a full snapshot if they're no longer in his repl_log.
+    fn resync(&mut self) {
+        if let ReplicaStat::Alive(puller, pusher) = std::mem::replace(&mut self.stat, ReplicaStat::NotConnected) {
+            info!("Resync with the replica at {} from uuid {}", self.meta.he.addr, puller.uuid_he_sent);
+            self.meta.uuid_he_sent = puller.uuid_he_sent;
+            self.meta.uuid_he_acked = puller.uuid_he_acked;
+            self.meta.uuid_i_sent = pusher.uuid_i_sent;
+            self.events = Some(pusher.events);
+        }
+    }
+
     pub async fn interact_independently(&mut self) -> Result<(), CstError> {
         self.to_serve = false;
         loop {
@@ -273,7 +491,8 @@ impl Replica {
                 ReplicaStat::Handshake(conn, passive) => {
                     debug!("Replica at {} is in Handshake stat", self.meta.he.addr);
                     if !*passive { // send the sync command and wait for his response
-                        conn.send_msg(mkcmd!("SYNC", 0, self.meta.myself.id, self.meta.myself.alias, self.meta.uuid_he_sent)).await?;
+                        conn.send_msg(mkcmd!("SYNC", 0, self.meta.myself.id, self.meta.myself.alias, self.meta.uuid_he_sent, SNAPSHOT_FORMAT_COMPRESSED, SNAPSHOT_COMPRESSIONS,
+                            REPL_PROTOCOL_VERSION, capabilities_names(REPL_CAPABILITIES))).await?;
                         let mut args = match conn.next_msg().await? {
                             Message::Array(args) => args.into_iter(),
                             others => {
@@ -287,9 +506,11 @@ impl Replica {
                         self.meta.he.id = his_id;
                         self.meta.he.alias = his_alias;
                         self.meta.uuid_i_sent = uuid_i_sent;
+                        self.meta.read_sync_capabilities(&mut args)?;
                     } else {  // we've already received his sync command, send our response and start to exchange dataset.
                         let meta = &self.meta;
-                        conn.send_msg(mkcmd!("SYNC", 1, meta.myself.id, meta.myself.alias, meta.uuid_he_sent)).await?;
+                        conn.send_msg(mkcmd!("SYNC", 1, meta.myself.id, meta.myself.alias, meta.uuid_he_sent, SNAPSHOT_FORMAT_COMPRESSED, SNAPSHOT_COMPRESSIONS,
+                            REPL_PROTOCOL_VERSION, capabilities_names(REPL_CAPABILITIES))).await?;
                     }
                     let (reader, writer) = conn.split();
                     let puller = Puller{
@@ -298,8 +519,14 @@ impl Replica {
                         meta: self.meta.clone(),
                         stats: PullStat::SyncSent,
                         reader,
+                        snapshot_size: 0,
+                        merged_entries: 0,
+                        clock_skew_ms: None,
+                        his_versions: None,
                         snapshot_entries: Default::default(),
-                        replicates: Default::default()
+                        replicates: Default::default(),
+                        repairs: Default::default(),
+                        replies: vec![],
                     };
                     let events = self.events.take().ok_or(CstError::SystemError)?;
                     let pusher = Pusher{
@@ -310,6 +537,13 @@ impl Replica {
                         stats: PushStat::SyncReceived,
                         writer,
                         events,
+                        relay_i_sent: 0,
+                        his_versions: None,
+                        his_versions_time: 0,
+                        his_acks: 0,
+                        relay_gap_at: None,
+                        versions_to_send: None,
+                        latest_anti_entropy_time: now_secs(),
                     };
                     self.stat = ReplicaStat::Alive(puller, pusher);
                 }
@@ -357,3 +591,58 @@ impl Replica {
 
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::resp::Message;
+    use crate::snapshot::{Compression, SNAPSHOT_FORMAT_COMPRESSED, SNAPSHOT_FORMAT_LEGACY};
+    use crate::replica::replica::{Replica, REPL_CAPA_ANTI_ENTROPY, REPL_CAPA_DELCOUNTER, REPL_CAPA_RELAY, REPL_CAPABILITIES, REPL_PROTOCOL_VERSION, capabilities_names, command_for};
+
+    fn sync_tail(args: &[&str]) -> Vec<Message> {
+        args.iter().map(|x| Message::BulkString(x.to_string().into())).collect()
+    }
+
+    #[test]
+    fn test_sync_capabilities() {
+        let mut r = Replica::new("127.0.0.1:9001".to_string(), 1, "a".to_string(), "127.0.0.1:9002".to_string(), false);
+        // the older versions tell nothing after the positions, they're replicated with in the degraded mode:
+        // no relays, no anti-entropy, and the counters are deleted by `delcnt`
+        r.meta.read_sync_capabilities(&mut vec![].into_iter()).unwrap();
+        assert_eq!((r.meta.snapshot_format, r.meta.protocol_version, r.meta.capabilities), (SNAPSHOT_FORMAT_LEGACY, 1, 0));
+        r.meta.read_sync_capabilities(&mut sync_tail(&["3", "lz4"]).into_iter()).unwrap();
+        assert_eq!((r.meta.snapshot_format, r.meta.protocol_version, r.meta.capabilities), (3, 1, 0));
+        assert!(!r.meta.capable_of(REPL_CAPA_RELAY));
+        assert!(!r.meta.capable_of(REPL_CAPA_ANTI_ENTROPY));
+        assert!(!r.meta.capable_of(REPL_CAPA_DELCOUNTER));
+        let del = |name: &str| Message::Array(vec![
+            Message::BulkString("replicate".into()), Message::Integer(1), Message::Integer(10), Message::Integer(11),
+            Message::BulkString(name.to_string().into()), Message::BulkString("c".into()), Message::Integer(1), Message::Integer(-3),
+        ]);
+        assert_eq!(command_for(del("delcounter"), r.meta.capabilities), del("delcnt"));
+
+        let version = REPL_PROTOCOL_VERSION.to_string();
+        let capabilities = capabilities_names(REPL_CAPABILITIES);
+        r.meta.read_sync_capabilities(&mut sync_tail(&["3", "lz4", &version, &capabilities]).into_iter()).unwrap();
+        assert_eq!(r.meta.snapshot_format, SNAPSHOT_FORMAT_COMPRESSED);
+        assert_eq!(r.meta.snapshot_compressions, vec![Compression::Lz4]);
+        assert_eq!((r.meta.protocol_version, r.meta.capabilities), (REPL_PROTOCOL_VERSION, REPL_CAPABILITIES));
+
+        // the capabilities of the newer versions are ignored
+        r.meta.read_sync_capabilities(&mut sync_tail(&["9", "lz4,zstd", "9", "lists, anti-entropy"]).into_iter()).unwrap();
+        assert_eq!((r.meta.protocol_version, r.meta.capabilities), (9, REPL_CAPA_ANTI_ENTROPY));
+        assert!(r.meta.capable_of(REPL_CAPA_ANTI_ENTROPY) && !r.meta.capable_of(REPL_CAPA_RELAY));
+
+        assert!(r.meta.read_sync_capabilities(&mut sync_tail(&["3", "lz4", "0", ""]).into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_command_for() {
+        let msg = |name: &str| Message::Array(vec![
+            Message::BulkString("replicate".into()), Message::Integer(1), Message::Integer(10), Message::Integer(11),
+            Message::BulkString(name.to_string().into()), Message::BulkString("c".into()), Message::Integer(2), Message::Integer(-3),
+        ]);
+        assert_eq!(command_for(msg("delcounter"), REPL_CAPABILITIES), msg("delcounter"));
+        assert_eq!(command_for(msg("delcounter"), REPL_CAPA_RELAY), msg("delcnt"));
+        assert_eq!(command_for(msg("incrby"), 0), msg("incrby"));
+    }
+}
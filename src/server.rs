@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
+use std::io::Write;
 use std::net::SocketAddr;
 use std::ops::Deref;
 use std::rc::Rc;
@@ -7,6 +8,7 @@ use std::result::Result::Ok;
 
 use bitflags::_core::option::Option::Some;
 use bitflags::_core::time::Duration;
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::{fork, ForkResult, Pid};
 use tokio::net::TcpSocket;
 use tokio::sync::OwnedMutexGuard;
@@ -14,40 +16,134 @@ use tokio::task::spawn_local;
 use tokio::time::Instant;
 use tokio::time::interval_at;
 
-use crate::{Bytes, CstError, now_mil};
-use crate::conf::Config;
-use crate::db::DB;
+use crate::{Bytes, CstError, now_mil, now_secs};
+use crate::aof::Aof;
+use crate::clock::{HybridClock, MAX_CLOCK_DRIFT_MS, UUID_COUNTER_BITS};
+use crate::cmd::Cmd;
+use crate::conf::{Config, SnapshotMode};
+use crate::crdt::vclock::VClock;
+use crate::db::{DeltaDump, DB};
 use crate::link::{Client, Link, SharedLink};
-use crate::object::Object;
-use crate::replica::replica::{ReplicaIdentity, ReplicaManager};
+use crate::lib::utils::glob_match;
+use crate::crdt::lwwhash::{Dict, Set};
+use crate::object::{Encoding, Object};
+use crate::rdb::{RdbImport, RdbImportStats, RdbItem, RdbValue};
+use crate::replica::backlog::DiskBacklog;
+use crate::replica::merkle::{MerkleTree, MERKLE_DEPTH, MERKLE_FANOUT};
+use crate::replica::pull::remove_staging_snapshots;
+use crate::replica::{restore_replicas, REPLICATION_META_FILE};
+use crate::replica::replica::{ReplicaIdentity, ReplicaManager, ReplicaPosition, save_positions};
 use crate::resp::Message;
-use crate::snapshot::{SNAPSHOT_FLAG_CHECKSUM, SnapshotWriter};
+use crate::snapshot::{ChunkSender, convert_snapshot, SNAPSHOT_FLAG_NODE, SNAPSHOT_FLAG_VERSIONS, SNAPSHOT_FORMAT, SnapshotEntry, SnapshotLoader, SnapshotWriter, spawn_snapshot_writer};
 use crate::stats::{incr_clients, Metrics};
+use crate::type_counter::Counter;
+
+pub const SNAPSHOT_FILE: &str = "db.snapshot";
+// the uuids reserved by our clock, see HybridClock
+pub const CLOCK_FILE: &str = "clock.meta";
+// seconds to wait before an automatic save is retried after a failure
+const SAVE_RETRY_DELAY: u64 = 5;
+// milliseconds an import of rdb may take in every tick of cron
+const RDB_IMPORT_BUDGET_MS: u64 = 20;
+// the most links served in a round of the main loop
+const LINKS_PER_ROUND: usize = 1024;
+
+// (uuid the snapshot was dumped at, replica addr => uuid we received from him, the writes of other nodes in it)
+type SnapshotStat = (u64, HashMap<String, u64>, VClock<u64>);
+
+// a snapshot being dumped in the background
+struct BgSave {
+    dumper: Dumper,
+    // the dirty count and the snapshot stat when it began
+    dirty: u64,
+    stat: SnapshotStat,
+    // the sequence of the aof rotated when it began
+    rotated: Option<u64>,
+    done: tokio::sync::watch::Sender<Option<bool>>,
+}
+
+enum Dumper {
+    Child(Pid),
+    // the writer is taken when all the data has been serialized, then we wait for the writer thread.
+    Incremental(Option<SnapshotWriter<ChunkSender>>, std::thread::JoinHandle<Result<(), CstError>>),
+}
+
+// a delta dumped for a replica in chunks, like the incremental dump, and written by a thread
+struct DeltaDumper {
+    dump: DeltaDump,
+    // taken when the delta has been serialized, then we wait for the writer thread
+    writer: Option<SnapshotWriter<ChunkSender>>,
+    handle: std::thread::JoinHandle<Result<(), CstError>>,
+    // when it was moved forward lately, it's aborted if the replica is gone
+    touched: u64,
+}
+
+// seconds a delta is kept after it's moved forward lately, the replica it's for is gone after that
+const DELTA_DUMP_TIMEOUT: u64 = 10;
+
+// notified with whether the snapshot was dumped successfully
+pub type DumpWaiter = tokio::sync::watch::Receiver<Option<bool>>;
+
+fn finish_incremental_dump(w: &mut SnapshotWriter<ChunkSender>) -> Result<(), CstError> {
+    w.write_checksum()?;
+    w.flush()?;
+    w.get_mut().commit()?;
+    Ok(())
+}
 
 pub struct Server {
     pub config: &'static Config,
     pub addr: String,
     pub node_id: u64,
     pub node_alias: String,
-    uuid: u64,
+    clock: HybridClock,
     pub expires: HashMap<Bytes, Object>,
     pub db: DB,
     repl_log: VecDeque<(u64, &'static str, Vec<Message>)>,
     latest_repl_uuid_overflowed: Option<u64>,
+    latest_repl_uuid_evicted: Option<u64>,
     repl_log_size: u64,
     repl_log_size_limit: u64,
+    repl_backlog: Option<DiskBacklog>,
+    // the uuid of every other node, up to which all of his writes are applied here
+    pub versions: VClock<u64>,
+    // the writes of other nodes we've applied, which are relayed to the replicas unable to get them from those nodes
+    relay_log: VecDeque<Relayed>,
+    relay_log_size: u64,
+    relay_seq: u64,
+    // the uuid of the latest write of every node dropped from the relay_log
+    relay_log_evicted: VClock<u64>,
+    // the tree of our data at a horizon, which is compared with those of all the replicas
+    merkle: Option<(u64, MerkleTree)>,
 
     pub replicas: ReplicaManager,
+    // the replication positions we saved to REPLICATION_META_FILE most recently
+    saved_positions: Vec<ReplicaPosition>,
     // replicas: LWWHash<u64, ReplicaIdentity>,
     pub events: EventsProducer,
     #[allow(unused)]
     events_wather: EventsConsumer,
 
     // latest time a snapshot was dumped, and the replica ids and their uuids we received at that time
-    snapshot: (u64, HashMap<String, u64>),
+    snapshot: SnapshotStat,
 
-    latest_dump_time: u64,
+    pub latest_dump_time: u64,
     latest_dumped_at_uuid: u64,
+    // the number of changes since the latest successful save
+    pub dirty: u64,
+    bgsave: Option<BgSave>,
+    pub bgsave_start_time: u64,
+    pub latest_bgsave_ok: bool,
+    pub latest_bgsave_time_sec: i64,
+    pub aof: Option<Aof>,
+    pub rdb_import: Option<RdbImport>,
+    // the stats of the latest finished import and whether it succeeded
+    pub latest_rdb_import: Option<(RdbImportStats, bool)>,
+    // the end of the time budget to merge the snapshots of the replicas, which they share in a round
+    // of the main loop. it's set by the first of them merging in the round.
+    pub merge_deadline: Option<std::time::Instant>,
+    // the deltas being dumped for the replicas by the file names
+    delta_dumps: HashMap<String, DeltaDumper>,
     pub client_chan: tokio::sync::mpsc::Sender<OwnedMutexGuard<Box<dyn Link + Send>>>,
     pub metrics: Metrics,
 }
@@ -67,32 +163,90 @@ impl Server {
             alias: config.node_alias.clone(),
         };
 
+        let repl_backlog = if config.repl_backlog_dir.is_empty() {
+            None
+        } else {
+            match DiskBacklog::open(&config.repl_backlog_dir, config.repl_backlog_segment_size, config.repl_backlog_disk_size, config.appendfsync) {
+                Ok(b) => Some(b),
+                Err(e) => {
+                    error!("Failed to open the repl backlog at {} because {}, only the in-memory repl_log is used", config.repl_backlog_dir, e);
+                    None
+                }
+            }
+        };
+        // the commands restored from the backlog of our previous process are all considered as evicted
+        // from the repl_log, and we can't tell what was dropped before the first of them.
+        let restored = repl_backlog.as_ref().and_then(|b| b.first_uuid().zip(b.last_uuid()));
+
         Server {
             node_id: config.node_id,
             node_alias: config.node_alias.clone(),
             addr: config.addr.clone(),
             config,
-            uuid: 1,
+            clock: HybridClock::new(restored.map(|(_, last)| last).unwrap_or(1)),
             expires: HashMap::new(),
             db: DB::empty(),
             repl_log: VecDeque::with_capacity(1024),
-            latest_repl_uuid_overflowed: None,
+            latest_repl_uuid_overflowed: restored.map(|(first, _)| first),
+            latest_repl_uuid_evicted: restored.map(|(_, last)| last),
             repl_log_size: 0,
-            repl_log_size_limit: 1024000,
+            repl_log_size_limit: config.repl_backlog_size,
+            repl_backlog,
+            versions: VClock::default(),
+            relay_log: VecDeque::new(),
+            relay_log_size: 0,
+            relay_seq: 0,
+            relay_log_evicted: VClock::default(),
+            merkle: None,
             events: tx,
             events_wather: rx,
             //replicas: HashMap::new(),
             replicas: ReplicaManager::new(identity),
-            snapshot: (0, Default::default()),
-            latest_dump_time: 0,
+            saved_positions: vec![],
+            snapshot: (0, Default::default(), Default::default()),
+            latest_dump_time: chrono::Local::now().timestamp() as u64,
             latest_dumped_at_uuid: 0,
+            dirty: 0,
+            bgsave: None,
+            bgsave_start_time: 0,
+            latest_bgsave_ok: true,
+            latest_bgsave_time_sec: -1,
+            aof: None,
+            rdb_import: None,
+            latest_rdb_import: None,
+            merge_deadline: None,
+            delta_dumps: HashMap::new(),
             client_chan: c_tx,
             metrics: Default::default(),
         }
     }
 
     pub async fn run(c: &'static Config) -> Result<(), std::io::Error> {
-        let server = Rc::new(RefCell::new(Server::new(c)));
+        // we don't listen before the snapshot is loaded, so that clients never see a partial dataset
+        let mut s = Server::new(c);
+        if let Err(e) = s.clock.persist(CLOCK_FILE) {
+            error!("Failed to load the clock file {} because {}", CLOCK_FILE, e);
+            std::process::exit(-1);
+        }
+        remove_staging_snapshots();
+        let snapshot_positions = match s.load_snapshot(SNAPSHOT_FILE).await {
+            Ok(p) => p,
+            Err(e) => {
+                error!("Failed to load the snapshot {} because {}", SNAPSHOT_FILE, e);
+                std::process::exit(-1);
+            }
+        };
+        let mut pulled: HashMap<String, u64> = snapshot_positions.iter().map(|p| (p.addr.clone(), p.uuid_he_sent)).collect();
+        if c.appendonly {
+            if let Err(e) = s.load_aof(&mut pulled) {
+                error!("Failed to load the aof {} because {}", c.appendfilename, e);
+                std::process::exit(-1);
+            }
+        }
+        // we don't know which tombstones were collected before, so no delta since then is complete
+        let now = s.next_uuid(false);
+        s.db.forget_collected(now);
+        let server = Rc::new(RefCell::new(s));
         let addr = format!("{}:{}", c.ip, c.port).parse::<SocketAddr>().unwrap();
         let socket = TcpSocket::new_v4()?;
         socket.set_reuseaddr(true)?;
@@ -103,6 +257,7 @@ impl Server {
         let server_cc = server.clone();
         let (tx, mut rx) = tokio::sync::mpsc::channel(102400);
         server.deref().borrow_mut().client_chan = tx.clone();
+        restore_replicas(&mut server.deref().borrow_mut(), snapshot_positions, pulled);
         spawn_local(async move {
             loop {
                 match listener.accept().await {
@@ -126,25 +281,57 @@ impl Server {
             Self::cron(server_c).await;
         });
         while let Some(mut l) = rx.recv().await {
-            l.serve( &mut *(*server).borrow_mut());
+            let mut s = server.deref().borrow_mut();
+            l.serve(&mut s);
+            // the links ready by now make a round
+            for _ in 1..LINKS_PER_ROUND {
+                match rx.try_recv() {
+                    Ok(mut l) => l.serve(&mut s),
+                    Err(_) => break,
+                }
+            }
+            s.merge_deadline = None;
         }
         Ok(())
     }
 
     async fn cron(server: Rc<RefCell<Server>>) {
         let mut timer = interval_at(Instant::now() + Duration::from_secs(1), Duration::from_millis(100));
+        let mut ticks: u64 = 0;
         loop {
+            ticks = (ticks + 1) % 10;
             {
                 let mut s = server.deref().borrow_mut();
                 let _ = s.next_uuid(true);
             }
             let _ = timer.tick().await;
             server.deref().borrow_mut().gc();
+            server.deref().borrow_mut().check_bgsave();
+            server.deref().borrow_mut().flush_logs(ticks == 0);
+            server.deref().borrow_mut().import_rdb();
+            if ticks == 0 {
+                let mut s = server.deref().borrow_mut();
+                s.save_replication_positions();
+                s.check_save_policy();
+                s.check_delta_dumps();
+            }
             // check for new replicas
             let _ = server.clone();
         }
     }
 
+    // save the replication positions if they've changed since the last time
+    fn save_replication_positions(&mut self) {
+        let positions = self.replicas.positions();
+        if positions == self.saved_positions {
+            return;
+        }
+        match save_positions(REPLICATION_META_FILE, &positions) {
+            Ok(()) => self.saved_positions = positions,
+            Err(e) => error!("Failed to save the replication positions to {} because {}", REPLICATION_META_FILE, e),
+        }
+    }
+
     fn new_event_consumer(server: Rc<RefCell<Server>>) -> EventsConsumer {
         let e = server.deref().borrow().events.events.subscribe();
         EventsConsumer{
@@ -157,54 +344,61 @@ impl Server {
     // this uuid is also used as a timestamp.
     // for writing,  we always return a bigger uuid.
     pub fn next_uuid(&mut self, is_write: bool) -> u64 {
-        let (time_mil, mut sequnce) = (self.uuid >> 22, (self.uuid & ((1<<22)-1)));
-        let now = now_mil();
-        self.uuid = {
-            if is_write {
-                if time_mil == now {
-                    sequnce += 1;
-                } else {
-                    sequnce = 0;
-                }
-            }
-            now << 22 | sequnce
-        };
-        self.uuid
+        self.clock.next(is_write)
+    }
+
+    // move our clock past a uuid generated by the replica at addr
+    pub fn observe_uuid(&mut self, uuid: u64, addr: &str) {
+        if !self.clock.observe(uuid) {
+            warn!("The clock of the replica at {} is ahead of ours by more than {}ms, uuid={}", addr, MAX_CLOCK_DRIFT_MS, uuid);
+        }
     }
 
     pub fn current_uuid(&self) -> u64 {
-        self.uuid
+        self.clock.current()
     }
 
     pub fn current_time(&self) -> u64 {
-        self.uuid >> 22
+        self.clock.current() >> UUID_COUNTER_BITS
+    }
+
+    // the magic, the version and my metadatas
+    fn dump_head<W: Write>(&self, w: &mut SnapshotWriter<W>) -> Result<(), CstError> {
+        let _ = w.write_head()?.write_section(SNAPSHOT_FLAG_NODE, 1)?;
+        w.write_item(|w| {
+            let _ = w.write_integer(self.node_id as i64)?
+                .write_integer(self.node_alias.len() as i64)?
+                .write_bytes(self.node_alias.as_bytes())?
+                .write_integer(self.addr.len() as i64)?
+                .write_bytes(self.addr.as_ref())?
+                .write_integer(self.get_repl_last_uuid() as i64)?;
+            Ok(())
+        })?;
+        let _ = w.write_section(SNAPSHOT_FLAG_VERSIONS, self.versions.iter().count())?;
+        for (node_id, uuid) in self.versions.iter() {
+            w.write_item(|w| {
+                let _ = w.write_integer(*node_id as i64)?.write_integer(*uuid as i64)?;
+                Ok(())
+            })?;
+        }
+        Ok(())
     }
 
     pub fn dump_all(&mut self, file_name: String) -> Result<(), CstError> {
         debug!("begin to dump, current_dir is {:?}", std::env::current_dir());
-        let tmp_name = format!("snapshot_{}", chrono::Local::now().timestamp());
+        // the pid makes the name unique when several children are dumping at the same time
+        let tmp_name = format!("snapshot_{}_{}", chrono::Local::now().timestamp(), std::process::id());
         let f = std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(tmp_name.clone())?;
         debug!("tmp file {} created", tmp_name);
 
-        let mut w = SnapshotWriter::new(4096, f);
-        w.write_bytes(b"CONSTDB")?;
-        w.write_bytes([0u8, 1, 1, 1].as_ref())?;
-
-        // dump my metadatas
-        let _ = w.write_integer(self.node_id as i64)?
-            .write_integer(self.node_alias.len() as i64)?
-            .write_bytes(self.node_alias.as_bytes())?
-            .write_integer(self.addr.len() as i64)?
-            .write_bytes(self.addr.as_ref())?
-            .write_integer(self.get_repl_last_uuid() as i64)?;
+        let mut w = SnapshotWriter::new(4096, f).with_compression(self.config.snapshot_compression);
+        self.dump_head(&mut w)?;
 
         // dump the db
         self.db.dump(&mut w)?;
 
         self.replicas.dump_snapshot(&mut w)?;
-        w.write_byte(SNAPSHOT_FLAG_CHECKSUM)?;
-        let checksum = w.checksum();
-        w.write_bytes(checksum.to_le_bytes().as_ref())?;
+        w.write_checksum()?;
         w.flush()?;
         debug!("dump finished");
         self.latest_dumped_at_uuid = self.current_uuid();
@@ -214,39 +408,421 @@ impl Server {
         Ok(())
     }
 
+    // start to dump what's written since the uuid into a snapshot, which brings a replica having the rest up to date
+    // when he's too far behind to be sent the commands. it's moved forward by dump_delta_incrementally.
+    // returns the uuid he's up to after loading it.
+    pub fn begin_delta_dump(&mut self, uuid: u64, file_name: &str) -> Result<u64, CstError> {
+        // the one dumped for him before he reconnected is useless
+        if let Some(d) = self.delta_dumps.remove(file_name) {
+            drop(d.writer);
+            let _ = d.handle.join();
+        }
+        let (sender, handle) = spawn_snapshot_writer(file_name.to_string());
+        let mut w = SnapshotWriter::new(64 * 1024, sender).with_compression(self.config.snapshot_compression);
+        let tombstone = self.get_repl_last_uuid();
+        self.dump_head(&mut w)?;
+        self.delta_dumps.insert(file_name.to_string(), DeltaDumper{
+            dump: self.db.begin_delta_dump(uuid),
+            writer: Some(w),
+            handle,
+            touched: now_secs(),
+        });
+        Ok(tombstone)
+    }
+
+    // dump the delta until the budget of this round is used up, returns true once it's in the file.
+    pub fn dump_delta_incrementally(&mut self, file_name: &str) -> Result<bool, CstError> {
+        let r = self.continue_delta_dump(file_name);
+        if let Ok(false) = r {
+            return r;
+        }
+        // the writer thread discards the file if the writer is dropped before committed
+        let d = self.delta_dumps.remove(file_name).ok_or(CstError::SystemError)?;
+        r?;
+        match d.handle.join() {
+            Ok(Ok(())) => Ok(true),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(CstError::SystemError),
+        }
+    }
+
+    fn continue_delta_dump(&mut self, file_name: &str) -> Result<bool, CstError> {
+        let d = self.delta_dumps.get_mut(file_name).ok_or(CstError::SystemError)?;
+        d.touched = now_secs();
+        if let Some(w) = &mut d.writer {
+            let deadline = std::time::Instant::now() + Duration::from_millis(self.config.snapshot_chunk_ms);
+            if !self.db.dump_delta_incrementally(&mut d.dump, w, deadline)? {
+                return Ok(false);
+            }
+            // a tombstone collected during the dump would be missed
+            if !self.db.delta_available(d.dump.since()) {
+                return Err(CstError::ReplicateDelayed);
+            }
+            self.db.dump_delta_tombstones(&d.dump, w)?;
+            self.replicas.dump_snapshot(w)?;
+            finish_incremental_dump(w)?;
+            d.writer = None;
+        }
+        Ok(d.handle.is_finished())
+    }
+
+    // abort the deltas whose replicas are gone
+    fn check_delta_dumps(&mut self) {
+        let now = now_secs();
+        let stale: Vec<String> = self.delta_dumps.iter()
+            .filter(|(_, d)| d.touched + DELTA_DUMP_TIMEOUT < now)
+            .map(|(f, _)| f.clone())
+            .collect();
+        for file_name in stale {
+            warn!("Aborted the delta {} as nobody is waiting for it", file_name);
+            self.delta_dumps.remove(&file_name);
+        }
+    }
+
+    // load the snapshot we dumped before restarting, returns the positions of the replicas
+    // which the data in the snapshot has been pulled to.
+    pub async fn load_snapshot(&mut self, file_name: &str) -> Result<Vec<ReplicaPosition>, CstError> {
+        let f = match tokio::fs::File::open(file_name).await {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        info!("Loading the snapshot {}", file_name);
+        let mut loader = SnapshotLoader::new(tokio::io::BufReader::new(f));
+        let mut positions = vec![];
+        let mut keys = 0u64;
+        while let Some(entry) = loader.next().await? {
+            match entry {
+                SnapshotEntry::Version(version) => info!("The snapshot is of version {}", version.to_string()),
+                SnapshotEntry::Node(node_id, _, _, uuid) => {
+                    if node_id != self.node_id {
+                        warn!("The snapshot was dumped by node {}, but my node_id is {}", node_id, self.node_id);
+                    }
+                    // never hand out a uuid smaller than those already dumped
+                    self.clock.restore(uuid);
+                }
+                SnapshotEntry::Data(k, v) => {
+                    keys += 1;
+                    self.db.merge_entry(k, v);
+                }
+                SnapshotEntry::Shadow(k, v) => self.db.merge_entry(k, v),
+                SnapshotEntry::Deletes(k, t) => self.db.delete(&k, t),
+                SnapshotEntry::Expires(k, t) => self.db.expire_at(&k, t),
+                SnapshotEntry::ReplicaAdd(add_time, node_id, alias, addr, uuid) => {
+                    if node_id == self.node_id {
+                        continue;
+                    }
+                    positions.push(ReplicaPosition{
+                        addr,
+                        id: node_id,
+                        alias,
+                        add_time,
+                        uuid_he_sent: uuid,
+                        uuid_he_acked: uuid,
+                        uuid_i_sent: 0,
+                        uuid_i_acked: 0,
+                    });
+                }
+                SnapshotEntry::ReplicaDel(addr, t) => {
+                    let _ = self.replicas.remove_replica(&addr, t);
+                }
+                SnapshotEntry::Versions(node_id, uuid) => self.versions.observe(node_id, uuid),
+            }
+        }
+        info!("Loaded {} keys from the snapshot {}, {} bytes read", keys, file_name, loader.total_read());
+        // only the snapshots of the legacy format are rewritten, those compressed otherwise than the config
+        // are readable as they are, and the next dump is compressed by the config anyway.
+        if loader.format() < SNAPSHOT_FORMAT {
+            let compression = self.config.snapshot_compression;
+            info!("Rewriting the snapshot {} from format {} to {}, compressed by {}", file_name, loader.format(), SNAPSHOT_FORMAT, compression.name());
+            let tmp_name = format!("{}.upgrading", file_name);
+            convert_snapshot(file_name, &tmp_name, SNAPSHOT_FORMAT, compression).await?;
+            tokio::fs::rename(tmp_name, file_name).await?;
+        }
+        Ok(positions)
+    }
+
     pub fn get_max_uuid_dumped(&self) -> u64 {
         self.latest_dumped_at_uuid
     }
 
-    pub fn dump_snapshot_in_background(&mut self) -> Result<(Option<Pid>, String, u64), CstError> {
-        // check for the latest time we've dumped a snapshot
+    // the snapshot a replica should be sent, returns a receiver to wait on if it is being dumped,
+    // along with the file name and the uuid it's dumped at.
+    // returns the uuid the snapshot is dumped at, and the versions of other nodes in it
+    pub fn dump_snapshot_in_background(&mut self) -> Result<(Option<DumpWaiter>, String, u64, VClock<u64>), CstError> {
         debug!("dumping snapshot in background");
-        let file_name = "db.snapshot".to_string();
-        let pid = if self.snapshot.0 > self.get_repl_first_uuid() {  // Congratulations! we've dumped a snapshot not long before, so we can use that snapshot
-            debug!("we've dumped a snapshot not long before, we can use that one!");
-            None
-        } else {  // we need to dump a fresh snapshot now!
-            match unsafe { fork() } {
-                Ok(ForkResult::Child) => {
-                    if let Err(e) = self.dump_all(file_name.clone()) {
-                        error!("unable to dump a snapshot because {}", e);
-                    }
-                    std::process::exit(0);
+        let file_name = SNAPSHOT_FILE.to_string();
+        if self.bgsave.is_none() {
+            // Congratulations! we've dumped a snapshot not long before, so we can use that snapshot.
+            // the writes of others after it must be all in the relay_log as well, or the replica would miss some.
+            if self.snapshot.0 > self.get_repl_first_uuid() && self.relay_log_follows(&self.snapshot.2) {
+                debug!("we've dumped a snapshot not long before, we can use that one!");
+                return Ok((None, file_name, self.snapshot.0, self.snapshot.2.clone()));
+            }
+            // we need to dump a fresh snapshot now!
+            self.bgsave()?;
+        }
+        let bg = self.bgsave.as_ref().unwrap();
+        Ok((Some(bg.done.subscribe()), file_name, bg.stat.0, bg.stat.2.clone()))
+    }
+
+    // the uuid the snapshot is going to be dumped at, and the progress with each replica at that time
+    fn snapshot_stat(&self) -> SnapshotStat {
+        let mut tombstones = self.replicas.replica_progress();
+        tombstones.insert(self.addr.clone(), self.get_repl_last_uuid());
+        (self.get_repl_last_uuid(), tombstones, self.versions.clone())
+    }
+
+    // fork a child process which dumps a snapshot into SNAPSHOT_FILE and exits with 1 if it fails.
+    fn fork_dump(&mut self) -> Result<Pid, CstError> {
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                if let Err(e) = self.dump_all(SNAPSHOT_FILE.to_string()) {
+                    error!("unable to dump a snapshot because {}", e);
+                    std::process::exit(1);
                 }
-                Ok(ForkResult::Parent { child: pid }) => {
-                    debug!("forked a child process {}", pid);
-                    let mut tombstones = self.replicas.replica_progress();
-                    tombstones.insert(self.addr.clone(), self.get_repl_last_uuid());
-                    self.snapshot = (self.get_repl_last_uuid(), tombstones);
-                    Some(pid)
+                std::process::exit(0);
+            }
+            Ok(ForkResult::Parent { child: pid }) => {
+                debug!("forked a child process {}", pid);
+                Ok(pid)
+            }
+            Err(e) => {
+                error!("unable to fork a new process because {}", e);
+                Err(CstError::SystemError)
+            }
+        }
+    }
+
+    // write everything except the data, which is dumped in chunks by check_bgsave later.
+    fn begin_incremental_dump(&mut self, file_name: &str) -> Result<Dumper, CstError> {
+        let (sender, handle) = spawn_snapshot_writer(file_name.to_string());
+        let mut w = SnapshotWriter::new(64 * 1024, sender).with_compression(self.config.snapshot_compression);
+        self.dump_head(&mut w)?;
+        self.replicas.dump_snapshot(&mut w)?;
+        self.db.dump_tombstones(&mut w)?;
+        self.db.begin_incremental_dump(&mut w)?;
+        Ok(Dumper::Incremental(Some(w), handle))
+    }
+
+    pub fn bgsave_in_progress(&self) -> bool {
+        self.bgsave.is_some()
+    }
+
+    // dump a snapshot in the main thread, clients are blocked until it's done.
+    pub fn save(&mut self) -> Result<(), CstError> {
+        let stat = self.snapshot_stat();
+        let dirty = self.dirty;
+        let rotated = self.rotate_aof()?;
+        self.dump_all(SNAPSHOT_FILE.to_string())?;
+        self.dirty -= dirty;
+        self.snapshot = stat;
+        self.remove_rotated_aof(rotated);
+        Ok(())
+    }
+
+    // the snapshot also serves as the rewritten aof, so that the aof only need to hold the commands
+    // executed after it was dumped.
+    pub fn bgsave(&mut self) -> Result<(), CstError> {
+        let rotated = self.rotate_aof()?;
+        let dumper = match self.config.snapshot_mode {
+            SnapshotMode::Fork => {
+                let pid = self.fork_dump()?;
+                info!("Background saving started by pid {}", pid);
+                Dumper::Child(pid)
+            }
+            SnapshotMode::Incremental => {
+                info!("Background saving started incrementally");
+                self.begin_incremental_dump(SNAPSHOT_FILE)?
+            }
+        };
+        let (done, _) = tokio::sync::watch::channel(None);
+        self.bgsave = Some(BgSave{
+            dumper,
+            dirty: self.dirty,
+            stat: self.snapshot_stat(),
+            rotated,
+            done,
+        });
+        self.bgsave_start_time = chrono::Local::now().timestamp() as u64;
+        Ok(())
+    }
+
+    // move the running BGSAVE forward, and finish it if it has terminated
+    fn check_bgsave(&mut self) {
+        let bg = match &mut self.bgsave {
+            None => return,
+            Some(bg) => bg,
+        };
+        let ok = match &mut bg.dumper {
+            Dumper::Child(pid) => match waitpid(*pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => return,
+                Ok(WaitStatus::Exited(_, code)) => code == 0,
+                Ok(s) => {
+                    error!("The background saving child {} terminated abnormally, {:?}", pid, s);
+                    false
                 }
                 Err(e) => {
-                    error!("unable to fork a new process because {}", e);
-                    return Err(CstError::SystemError);
+                    error!("Failed to wait for the background saving child {} because {}", pid, e);
+                    false
                 }
+            },
+            Dumper::Incremental(w, handle) => {
+                if let Some(writer) = w {
+                    let deadline = std::time::Instant::now() + Duration::from_millis(self.config.snapshot_chunk_ms);
+                    match self.db.dump_incrementally(writer, deadline) {
+                        Ok(false) => return,
+                        Ok(true) => {
+                            if let Err(e) = finish_incremental_dump(writer) {
+                                error!("Failed to finish the incremental dump because {}", e);
+                            }
+                        }
+                        Err(e) => {
+                            error!("Failed to dump the data incrementally because {}", e);
+                            self.db.abort_incremental_dump();
+                        }
+                    }
+                    // the writer thread discards the snapshot if it's dropped before committed
+                    *w = None;
+                }
+                if !handle.is_finished() {
+                    return;
+                }
+                true
             }
         };
-        Ok((pid, file_name, self.snapshot.0))
+        let bg = self.bgsave.take().unwrap();
+        let ok = ok && match bg.dumper {
+            Dumper::Child(_) => true,
+            Dumper::Incremental(_, handle) => match handle.join() {
+                Ok(Ok(())) => true,
+                Ok(Err(e)) => {
+                    error!("Failed to write the snapshot because {}", e);
+                    false
+                }
+                Err(_) => false,
+            },
+        };
+        let now = chrono::Local::now().timestamp() as u64;
+        self.latest_bgsave_ok = ok;
+        self.latest_bgsave_time_sec = now.saturating_sub(self.bgsave_start_time) as i64;
+        if ok {
+            info!("Background saving terminated with success");
+            self.dirty -= bg.dirty;
+            self.latest_dump_time = now;
+            self.snapshot = bg.stat;
+            self.remove_rotated_aof(bg.rotated);
+        } else {
+            error!("Background saving failed");
+        }
+        let _ = bg.done.send(Some(ok));
+    }
+
+    // start a BGSAVE if any of the save points is reached, or the aof has grown too large
+    fn check_save_policy(&mut self) {
+        if self.bgsave_in_progress() || self.dirty == 0 {
+            return;
+        }
+        let now = chrono::Local::now().timestamp() as u64;
+        // don't retry too frequently when the latest one has failed
+        if !self.latest_bgsave_ok && now < self.bgsave_start_time + SAVE_RETRY_DELAY {
+            return;
+        }
+        // the wall clock may step back
+        let elapsed = now.saturating_sub(self.latest_dump_time);
+        if let Some((secs, changes)) = self.config.save.iter().find(|(secs, changes)| self.dirty >= *changes && elapsed >= *secs) {
+            info!("{} changes in {} seconds. Saving...", changes, secs);
+        } else if self.aof_too_large() {
+            info!("The aof has grown to {} bytes. Rewriting...", self.aof.as_ref().map(|x| x.size()).unwrap_or_default());
+        } else {
+            return;
+        }
+        if let Err(e) = self.bgsave() {
+            error!("Failed to start a background saving because {}", e);
+        }
+    }
+
+    fn aof_too_large(&self) -> bool {
+        let size = match &self.aof {
+            None => return false,
+            Some(aof) => aof.size(),
+        };
+        let snapshot_size = std::fs::metadata(SNAPSHOT_FILE).map(|x| x.len()).unwrap_or_default();
+        size >= self.config.auto_aof_rewrite_min_size && size * 100 >= snapshot_size * self.config.auto_aof_rewrite_percentage
+    }
+}
+
+/*
+ *  the append only file
+ */
+impl Server {
+    // replay the aof after the snapshot is loaded, and move the positions of the replicas we pulled
+    // the commands from forward.
+    pub fn load_aof(&mut self, pulled: &mut HashMap<String, u64>) -> Result<(), CstError> {
+        let file_name = self.config.appendfilename.clone();
+        for f in Aof::files(&file_name)? {
+            let mut entries = vec![];
+            Aof::replay(&f, |e| entries.push(e))?;
+            info!("Replaying {} commands from the aof {}", entries.len(), f);
+            for e in entries {
+                if e.addr.is_empty() {
+                    self.clock.restore(e.uuid);
+                } else {
+                    let p = pulled.entry(e.addr.clone()).or_default();
+                    if *p < e.uuid {
+                        *p = e.uuid;
+                    }
+                    // the writes of others are logged in the order we applied them, as are relayed or not
+                    self.versions.observe(e.nodeid, e.uuid);
+                }
+                match Cmd::new(e.cmd_name.as_bytes(), e.args) {
+                    Err(err) => error!("Unknown command {} in the aof, {}", e.cmd_name, err),
+                    Ok(cmd) => {
+                        if let Err(err) = cmd.exec_detail(self, None, e.nodeid, e.uuid, false) {
+                            error!("Failed to replay the command {} with uuid {} in the aof, {}", e.cmd_name, e.uuid, err);
+                        }
+                    }
+                }
+            }
+        }
+        self.aof = Some(Aof::open(&file_name, self.config.appendfsync)?);
+        Ok(())
+    }
+
+    pub fn append_aof(&mut self, nodeid: u64, uuid: u64, addr: &str, cmd_name: &str, args: &[Message]) {
+        if let Some(aof) = &mut self.aof {
+            if let Err(e) = aof.append(nodeid, uuid, addr, cmd_name, args) {
+                error!("Failed to append the command with uuid {} into the aof because {}", uuid, e);
+            }
+        }
+    }
+
+    fn flush_logs(&mut self, every_second: bool) {
+        if let Some(aof) = &mut self.aof {
+            if let Err(e) = aof.cron(every_second) {
+                error!("Failed to flush the aof because {}", e);
+            }
+        }
+        if let Some(backlog) = &mut self.repl_backlog {
+            if let Err(e) = backlog.cron(every_second) {
+                error!("Failed to flush the repl backlog because {}", e);
+            }
+        }
+    }
+
+    fn rotate_aof(&mut self) -> Result<Option<u64>, CstError> {
+        match &mut self.aof {
+            None => Ok(None),
+            Some(aof) => aof.rotate().map(Some),
+        }
+    }
+
+    fn remove_rotated_aof(&mut self, rotated: Option<u64>) {
+        if let (Some(aof), Some(seq)) = (&mut self.aof, rotated) {
+            if let Err(e) = aof.remove_rotated(seq) {
+                error!("Failed to remove the rotated aof files because {}", e);
+            }
+        }
     }
 }
 
@@ -254,6 +830,118 @@ impl Server {
  *  data management
  */
 impl Server {
+    // apply the keys parsed from the rdb being imported, until the budget of this tick is used up.
+    // every key is written by a command of ours, so it is replicated and appended to the aof as usual.
+    fn import_rdb(&mut self) {
+        let mut import = match self.rdb_import.take() {
+            None => return,
+            Some(i) => i,
+        };
+        let deadline = now_mil() + RDB_IMPORT_BUDGET_MS;
+        while now_mil() < deadline {
+            let item = match import.try_next() {
+                Ok(None) => break,
+                Ok(Some(item)) => item,
+                Err(e) => {
+                    let st = import.stats;
+                    match &e {
+                        None => info!("Imported the rdb {}, {} keys imported, {} skipped, {} expired, {} ttl applied, {} failed",
+                                      import.file_name, st.imported, st.skipped, st.expired, st.ttl_applied, st.failed),
+                        Some(e) => error!("Failed to import the rdb {} because {}, {} keys imported before it", import.file_name, e, st.imported),
+                    }
+                    self.latest_rdb_import = Some((st, e.is_none()));
+                    return;
+                }
+            };
+            let (key, value, expire_ms) = match item {
+                RdbItem::Skipped(key, reason) => {
+                    debug!("Skipped the key {} of the rdb, {}", String::from_utf8_lossy(&key), reason);
+                    import.stats.skipped += 1;
+                    continue;
+                }
+                RdbItem::Entry(key, value, expire_ms) => (key, value, expire_ms),
+            };
+            if let Some(at) = expire_ms {
+                if at <= now_mil() {
+                    import.stats.expired += 1;
+                    continue;
+                }
+            }
+            let as_counter = import.counters.as_ref().map(|p| glob_match(p, &key)).unwrap_or(false);
+            match self.import_rdb_entry(key, value, as_counter, expire_ms) {
+                Ok(()) => {
+                    import.stats.imported += 1;
+                    if expire_ms.is_some() {
+                        import.stats.ttl_applied += 1;
+                    }
+                }
+                Err(e) => {
+                    debug!("Failed to import a key of the rdb because {}", e);
+                    import.stats.failed += 1;
+                }
+            }
+        }
+        self.rdb_import = Some(import);
+    }
+
+    // the key is written as our client would, so a key of another type here is an error rather than
+    // a shadow, and a counter is set to the value in the file, which makes importing it again harmless.
+    fn import_rdb_entry(&mut self, key: Vec<u8>, value: RdbValue, as_counter: bool, expire_ms: Option<u64>) -> Result<(), CstError> {
+        let key = Bytes::from(key);
+        let uuid = self.next_uuid(true);
+        let mut args = vec![Message::BulkString(key.clone())];
+        let mut unchanged = false;
+        let (name, enc): (&[u8], Encoding) = match value {
+            RdbValue::String(v) => match std::str::from_utf8(&v).ok().and_then(|x| x.parse::<i64>().ok()) {
+                Some(i) if as_counter => {
+                    let current = match self.db.query(&key, uuid) {
+                        Some(o) => o.enc.as_counter().map(|c| c.get()).unwrap_or_default(),
+                        None => 0,
+                    };
+                    unchanged = i == current;
+                    args.push(Message::Integer(i.wrapping_sub(current)));
+                    (b"incrby", Encoding::from(Counter::default()))
+                }
+                _ => {
+                    args.push(Message::BulkString(v.into()));
+                    (b"set", Encoding::Bytes(Vec::new().into()))
+                }
+            },
+            RdbValue::Set(members) => {
+                args.extend(members.into_iter().map(|m| Message::BulkString(m.into())));
+                (b"sadd", Encoding::from(Set::empty()))
+            }
+            RdbValue::Hash(kvs) => {
+                for (k, v) in kvs {
+                    args.push(Message::BulkString(k.into()));
+                    args.push(Message::BulkString(v.into()));
+                }
+                (b"hset", Encoding::from(Dict::empty()))
+            }
+        };
+        if let Some(o) = self.db.query(&key, uuid) {
+            if !o.enc.same_type(&enc) {
+                return Err(CstError::InvalidType);
+            }
+        }
+        if args.len() < 2 {
+            return Ok(());
+        }
+        if !unchanged {
+            let cmd = Cmd::new(name, args)?;
+            if let Message::Error(e) = cmd.exec_detail(self, None, self.node_id, uuid, true)? {
+                return Err(CstError::InvalidRequestMsg(e.to_string()));
+            }
+        }
+        // replicated and logged as the value is, or the other nodes would never expire it
+        if let Some(at) = expire_ms {
+            let cmd = Cmd::new(b"pexpireat", vec![Message::BulkString(key), Message::Integer(at as i64)])?;
+            let uuid = self.next_uuid(true);
+            cmd.exec_detail(self, None, self.node_id, uuid, true)?;
+        }
+        Ok(())
+    }
+
     pub fn gc(&mut self) {
         match self.replicas.min_uuid() {
             None => return,
@@ -270,6 +958,8 @@ impl Server {
     pub fn replicate_cmd(&mut self, uuid: u64, cmd_name: &'static str, args: Vec<Message>) {
         //let prev_uuid = self.repl_log.back().map(|(x, _, _)| *x).unwrap_or(1);
         let s: usize = args.iter().map(|x| x.size()).sum();
+        self.append_aof(self.node_id, uuid, "", cmd_name, &args);
+        self.append_repl_backlog(uuid, cmd_name, &args);
         self.repl_log.push_back((uuid, cmd_name, args));
         self.repl_log_size += s as u64;
         while self.repl_log_size > self.repl_log_size_limit {
@@ -280,38 +970,73 @@ impl Server {
                 Some((u, _, ms)) => {
                     let s: usize = ms.iter().map(|x| x.size()).sum();
                     self.repl_log_size -= s as u64;
-                    self.latest_repl_uuid_overflowed = Some(u);
+                    self.latest_repl_uuid_evicted = Some(u);
+                    if self.repl_backlog.is_none() {
+                        self.latest_repl_uuid_overflowed = Some(u);
+                    }
                 }
             }
         }
         self.events.trigger(Event::Replicated(uuid));
     }
 
+    // the disk backlog is written through, so that it survives a restart and still holds
+    // the commands after they are evicted from the repl_log.
+    fn append_repl_backlog(&mut self, uuid: u64, cmd_name: &'static str, args: &[Message]) {
+        let backlog = match &mut self.repl_backlog {
+            None => return,
+            Some(b) => b,
+        };
+        match backlog.append(uuid, cmd_name, args) {
+            Ok(None) => {},
+            Ok(Some(dropped)) => self.latest_repl_uuid_overflowed = Some(dropped),
+            Err(e) => {
+                // a hole in the backlog would make replicas skip commands, so we give it up
+                // and only serve them from the repl_log from now on.
+                error!("Failed to append the command with uuid {} into the repl backlog because {}, disable it", uuid, e);
+                self.repl_backlog = None;
+                if let Some(u) = self.latest_repl_uuid_evicted {
+                    self.latest_repl_uuid_overflowed = Some(u);
+                }
+            }
+        }
+    }
+
     pub fn repl_log_next(&self, uuid: u64) -> Option<(u64, Message)> {
-        let msg_pos = if uuid == 0 {
-            if self.latest_repl_uuid_overflowed.is_some() {
-                None
-            } else {
-                Some(0)
+        match self.repl_log_entry_after(uuid) {
+            None => None,
+            Some((next_uuid, cmd_name, args)) => {
+                let mut replicates = Vec::with_capacity(args.len() + 5);
+                replicates.push(Message::BulkString("replicate".into()));
+                replicates.push(Message::Integer(self.node_id as i64));
+                replicates.push(Message::Integer(uuid as i64));
+                replicates.push(Message::Integer(next_uuid as i64));
+                replicates.push(Message::BulkString(cmd_name.into()));
+                replicates.extend(args);
+                Some((next_uuid, Message::Array(replicates)))
             }
+        }
+    }
+
+    // find the command following the one with this uuid, either in the repl_log or in the disk backlog.
+    fn repl_log_entry_after(&self, uuid: u64) -> Option<(u64, &'static str, Vec<Message>)> {
+        if self.repl_log_overflowed(uuid) {
+            return None;
+        }
+        let msg_pos = if uuid == self.latest_repl_uuid_evicted.unwrap_or_default() {
+            Some(0)
         } else {
             self.repl_log_uuid_index(uuid).map(|x| x+1)
         };
-        match msg_pos {
-            None => return None,
-            Some(pos) => match self.repl_log.get(pos) {
-                None => None,
-                Some((next_uuid, cmd_name, args)) => {
-                    let mut replicates = Vec::with_capacity(args.len() + 5);
-                    replicates.push(Message::BulkString("replicate".into()));
-                    replicates.push(Message::Integer(self.node_id as i64));
-                    replicates.push(Message::Integer(uuid as i64));
-                    replicates.push(Message::Integer(*next_uuid as i64));
-                    replicates.push(Message::BulkString((*cmd_name).into()));
-                    replicates.extend_from_slice(args);
-                    Some((*next_uuid, Message::Array(replicates)))
-                }
-            }
+        if let Some(pos) = msg_pos {
+            return self.repl_log.get(pos).map(|(u, cmd_name, args)| (*u, *cmd_name, args.clone()));
+        }
+        match &self.repl_backlog {
+            None => None,
+            Some(backlog) => backlog.next(uuid).unwrap_or_else(|e| {
+                error!("Failed to read the command after uuid {} from the repl backlog because {}", uuid, e);
+                None
+            }),
         }
     }
 
@@ -350,43 +1075,252 @@ impl Server {
     }
 
     pub fn repl_log_at(&self, uuid: u64) -> Option<Message> {
-        match self.repl_log_uuid_index(uuid) {
-            None => None,
-            Some(idx) => match self.repl_log.get(idx) {
+        let entry = match self.repl_log_uuid_index(uuid) {
+            Some(idx) => self.repl_log.get(idx).map(|(_, cmd_name, args)| (*cmd_name, args.clone())),
+            None => match &self.repl_backlog {
                 None => None,
-                Some((_, cmd_name, args)) => {
-                    let mut cmd = Vec::with_capacity(args.len() + 1);
-                    cmd.push(Message::BulkString(cmd_name.to_string().into()));
-                    cmd.extend_from_slice(args);
-                    Some(Message::Array(cmd))
-                },
+                Some(backlog) => backlog.get(uuid).unwrap_or_else(|e| {
+                    error!("Failed to read the command with uuid {} from the repl backlog because {}", uuid, e);
+                    None
+                }).map(|(_, cmd_name, args)| (cmd_name, args)),
             }
+        };
+        entry.map(|(cmd_name, args)| {
+            let mut cmd = Vec::with_capacity(args.len() + 1);
+            cmd.push(Message::BulkString(cmd_name.to_string().into()));
+            cmd.extend(args);
+            Message::Array(cmd)
+        })
+    }
+
+    // whether the commands following `uuid` have been dropped from the repl_log.
+    // a replica who has only received commands up to such a uuid can't be served
+    // from the repl_log anymore and needs a full snapshot.
+    pub fn repl_log_overflowed(&self, uuid: u64) -> bool {
+        match self.latest_repl_uuid_overflowed {
+            None => false,
+            Some(u) => uuid < u,
         }
     }
 
+    // whether we are able to continue sending commands to a replica who has received those up to `uuid`.
+    // it's not the case if the uuid was generated by a previous process whose repl_log is lost.
+    pub fn repl_log_continuable(&self, uuid: u64) -> bool {
+        if self.repl_log_overflowed(uuid) {
+            return false;
+        }
+        if uuid == self.latest_repl_uuid_evicted.unwrap_or_default() || self.repl_log_uuid_index(uuid).is_some() {
+            return true;
+        }
+        self.repl_backlog.as_ref().map(|b| b.contains(uuid)).unwrap_or(false)
+    }
+
     pub fn repl_log_uuids(&self) -> Vec<u64> {
-        self.repl_log.iter().map(|(x, _, _)| *x).collect()
+        let mut uuids = self.repl_backlog.as_ref().map(|b| b.uuids()).unwrap_or_default();
+        uuids.extend(self.repl_log.iter().map(|(x, _, _)| *x));
+        uuids
     }
 
     #[inline]
     pub fn get_repl_first_uuid(&self) -> u64 {
-        self.repl_log.front().map(|(u, _, _)| *u).unwrap_or_default()
+        self.repl_backlog.as_ref().and_then(|b| b.first_uuid())
+            .or(self.repl_log.front().map(|(u, _, _)| *u))
+            .unwrap_or_default()
     }
 
     #[inline]
     pub fn get_repl_last_uuid(&self) -> u64 {
-        self.repl_log.back().map(|(u, _, _)| *u).unwrap_or_default()
+        // the repl_log is empty after a restart, while the backlog still has what we wrote before
+        self.repl_log.back().map(|(u, _, _)| *u)
+            .or(self.repl_backlog.as_ref().and_then(|b| b.last_uuid()))
+            .unwrap_or_default()
+    }
+}
+
+// a write of another node we've applied, following the one of his at prev_uuid
+#[derive(Debug)]
+pub struct Relayed {
+    pub seq: u64,
+    pub origin: u64,
+    // the replica we received it from
+    pub via: u64,
+    pub prev_uuid: u64,
+    pub uuid: u64,
+    // in milliseconds
+    pub applied_at: u64,
+    cmd_name: &'static str,
+    args: Vec<Message>,
+}
+
+impl Relayed {
+    pub fn to_message(&self) -> Message {
+        let mut relayed = Vec::with_capacity(self.args.len() + 5);
+        relayed.push(Message::BulkString("relay".into()));
+        relayed.push(Message::Integer(self.origin as i64));
+        relayed.push(Message::Integer(self.prev_uuid as i64));
+        relayed.push(Message::Integer(self.uuid as i64));
+        relayed.push(Message::BulkString(self.cmd_name.into()));
+        relayed.extend(self.args.iter().cloned());
+        Message::Array(relayed)
+    }
+}
+
+/*
+ *  relaying the writes of other nodes, so that the replicas unreachable from a node still get his writes from us
+ *
+ */
+impl Server {
+    // apply a write of the origin received from the replica `via`, unless we've got it from another replica.
+    // the writes of a node are applied in the order he made them, so the uuid is where we are with him afterwards.
+    pub fn apply_replicated(&mut self, origin: u64, via: u64, prev_uuid: u64, uuid: u64, cmd: &Cmd, addr: &str) -> bool {
+        if uuid <= self.versions.uuid_of(origin) {
+            return false;
+        }
+        // Note! We do not replicate those commands received from our replicas.
+        match cmd.exec_detail(self, None, origin, uuid, false) {
+            Err(e) => error!("error '{}' occurred when executing command from our replica at {}", e, addr),
+            Ok(_) => self.append_aof(origin, uuid, addr, cmd.name(), cmd.args()),
+        }
+        self.versions.observe(origin, uuid);
+        self.relay_seq += 1;
+        let relayed = Relayed{
+            seq: self.relay_seq,
+            origin,
+            via,
+            prev_uuid,
+            uuid,
+            applied_at: now_mil(),
+            cmd_name: cmd.name(),
+            args: cmd.args().to_vec(),
+        };
+        self.relay_log_size += relayed.args.iter().map(|x| x.size()).sum::<usize>() as u64;
+        self.relay_log.push_back(relayed);
+        while self.relay_log_size > self.repl_log_size_limit {
+            match self.relay_log.pop_front() {
+                None => break,
+                Some(r) => {
+                    self.relay_log_size -= r.args.iter().map(|x| x.size()).sum::<usize>() as u64;
+                    self.relay_log_evicted.observe(r.origin, r.uuid);
+                }
+            }
+        }
+        true
+    }
+
+    // apply a write of the origin relayed by the replica `via`, if we have all of his writes before it.
+    // otherwise he is going to resync us when he finds we still miss them.
+    pub fn apply_relayed(&mut self, origin: u64, via: u64, prev_uuid: u64, uuid: u64, cmd: &Cmd, addr: &str) -> bool {
+        let version = self.versions.uuid_of(origin);
+        if origin == self.node_id || uuid <= version {
+            return false;
+        }
+        if prev_uuid != version {
+            debug!("Skip the write of node {} relayed by {} which follows uuid {}, we are at {}", origin, addr, prev_uuid, version);
+            return false;
+        }
+        self.observe_uuid(uuid, addr);
+        self.apply_replicated(origin, via, prev_uuid, uuid, cmd, addr)
+    }
+
+    pub fn relay_log_next(&self, seq: u64) -> Option<&Relayed> {
+        let pos = self.relay_log.partition_point(|r| r.seq <= seq);
+        self.relay_log.get(pos)
+    }
+
+    // whether the writes of others after those in the versions are all in the relay_log
+    pub fn relay_log_follows(&self, versions: &VClock<u64>) -> bool {
+        self.relay_log_evicted.iter().all(|(origin, uuid)| versions.uuid_of(*origin) >= *uuid)
+    }
+}
+
+/*
+ *  anti-entropy, which finds the differences between our data and a replica's and merges them
+ *
+ */
+impl Server {
+    // the objects written before it are compared. it's the same on all the nodes in an interval so that the tree is
+    // built once for all the replicas, and the writes of the latest interval are left out as they may be on the way.
+    pub fn anti_entropy_horizon(&self) -> u64 {
+        let interval = std::cmp::max(1, self.config.anti_entropy_interval * 1000);
+        ((self.current_time() / interval).saturating_sub(1) * interval) << UUID_COUNTER_BITS
+    }
+
+    pub fn merkle_tree(&mut self, horizon: u64) -> &MerkleTree {
+        if self.merkle.as_ref().map(|(h, _)| *h) != Some(horizon) {
+            self.merkle = Some((horizon, self.db.merkle_tree(horizon)));
+        }
+        &self.merkle.as_ref().unwrap().1
+    }
+
+    // he tells the hashes of the children of a node in his tree, we reply with ours of the children of those
+    // which differ, or the objects in them if they're the buckets.
+    pub fn anti_entropy_reply(&mut self, horizon: u64, level: usize, index: usize, his: &[u64]) -> Result<Vec<Message>, CstError> {
+        if !MerkleTree::has_node(level, index) || his.len() != MERKLE_FANOUT {
+            return Err(CstError::InvalidRequestMsg(format!("no node {} at level {} in the merkle tree", index, level)));
+        }
+        let diff = self.merkle_tree(horizon).diff(level, index, his);
+        if diff.is_empty() {
+            return Ok(vec![]);
+        }
+        if level + 1 == MERKLE_DEPTH {
+            return Ok(vec![self.repair_msg(horizon, true, &diff)?]);
+        }
+        let tree = self.merkle_tree(horizon);
+        Ok(diff.into_iter().map(|c| tree.to_message(horizon, level + 1, c)).collect())
+    }
+
+    // the objects in the buckets, he merges them and replies with his own if we ask him to
+    pub fn repair_msg(&self, horizon: u64, reply: bool, buckets: &[usize]) -> Result<Message, CstError> {
+        let mut args = vec![
+            Message::BulkString("REPAIR".into()),
+            Message::Integer(horizon as i64),
+            Message::Integer(reply as i64),
+            Message::Integer(buckets.len() as i64),
+        ];
+        args.extend(buckets.iter().map(|b| Message::Integer(*b as i64)));
+        for (k, o) in self.db.bucket_objects(buckets) {
+            let mut w = SnapshotWriter::new(256, vec![]);
+            w.write_entry(k.as_bytes(), o)?;
+            w.flush()?;
+            args.push(Message::BulkString(std::mem::take(w.get_mut()).into()));
+        }
+        Ok(Message::Array(args))
+    }
+
+    pub fn repair(&mut self, key: Bytes, value: Object) {
+        if self.db.repair(key, value) {
+            self.metrics.incr_repaired_keys();
+            self.dirty += 1;
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use bitflags::_core::time::Duration;
+    use std::cmp::max;
+
+    use crate::cmd::NextArg;
+    use crate::crdt::vclock::VClock;
+
     use tokio::macros::support::thread_rng_n;
 
-    use crate::conf::Config;
+    use crate::Bytes;
+    use crate::cmd::Cmd;
+    use crate::clock::UUID_COUNTER_BITS;
+    use crate::conf::{AppendFsync, Config, SnapshotMode};
+    use crate::crdt::lwwhash::{Dict, Set};
+    use crate::crdt::timestamp::Timestamp;
+    use crate::object::{Encoding, Object};
+    use crate::rdb::RdbValue;
     use crate::resp::Message;
-    use crate::server::Server;
+    use crate::replica::pull::{Puller, PullStat};
+    use crate::replica::push::{Pusher, PushStat};
+    use crate::replica::replica::{Replica, ReplicaStat};
+    use crate::link::Link;
+    use crate::server::{BgSave, Server};
+    use crate::snapshot::{Compression, SnapshotEntry};
+    use crate::type_counter::Counter;
     static Conf: Config = Config{
         daemon: false,
         node_id: 1,
@@ -399,7 +1333,23 @@ mod test {
         work_dir: String::new(),
         tcp_backlog: 1024,
         replica_heartbeat_frequency: 0,
-        replica_gossip_frequency: 0
+        replica_gossip_frequency: 0,
+        repl_backlog_size: 1024000,
+        repl_backlog_dir: String::new(),
+        repl_backlog_disk_size: 0,
+        repl_backlog_segment_size: 0,
+        save: vec![],
+        appendonly: false,
+        appendfilename: String::new(),
+        appendfsync: AppendFsync::No,
+        auto_aof_rewrite_percentage: 100,
+        auto_aof_rewrite_min_size: 0,
+        snapshot_mode: SnapshotMode::Fork,
+        snapshot_chunk_ms: 10,
+        snapshot_compression: Compression::None,
+        repl_diskless_load: false,
+        repl_merge_chunk_ms: 5,
+        anti_entropy_interval: 300,
     };
 
     #[test]
@@ -430,6 +1380,46 @@ mod test {
         // }
     }
 
+    #[test]
+    fn test_merge_snapshot_in_chunks() {
+        // only one entry is merged in every round if there is no time budget
+        let conf: &'static Config = Box::leak(Box::new(Config{repl_merge_chunk_ms: 0, ..Conf.clone()}));
+        let mut server = Server::new(conf);
+        let addr = "127.0.0.1:9999".to_string();
+        let r = Replica::new(addr.clone(), 1, String::new(), String::new(), false);
+        server.replicas.add_replica(addr.clone(), r.meta.clone(), 1);
+        let new_puller = |prefix: &str| Puller{
+            uuid_he_sent: 0,
+            uuid_he_acked: 0,
+            meta: r.meta.clone(),
+            stats: PullStat::PullingCommands,
+            reader: Default::default(),
+            snapshot_size: 100,
+            merged_entries: 0,
+            clock_skew_ms: None,
+            his_versions: None,
+            snapshot_entries: (0..3).map(|i| SnapshotEntry::Data(Bytes::from(format!("{}{}", prefix, i)), Object::new(Encoding::Bytes("v".into()), Timestamp::new(10, 1), Timestamp::default()))).collect(),
+            replicates: Default::default(),
+            repairs: Default::default(),
+            replies: vec![],
+        };
+        let mut puller = new_puller("k");
+        let mut other = new_puller("o");
+        for i in 1..=3 {
+            // a new round of the main loop
+            server.merge_deadline = None;
+            puller.merge_replicates_in_main(&mut server).unwrap();
+            assert_eq!(puller.snapshot_entries.len(), 3 - i);
+            let loading = server.replicas.get_replica(&addr).unwrap().loading.clone();
+            assert_eq!(loading.map(|l| l.merged), if i < 3 { Some(i as u64) } else { None });
+            // the budget of the round is used up by the first replica
+            other.merge_replicates_in_main(&mut server).unwrap();
+            assert_eq!(other.snapshot_entries.len(), 3);
+        }
+        let uuid = server.next_uuid(false);
+        assert!(server.db.query(&Bytes::from("k2"), uuid).is_some());
+    }
+
     #[test]
     fn test_uuid() {
         let mut server = Server::new(&Conf);
@@ -441,6 +1431,869 @@ mod test {
             prev = c;
         }
     }
+
+    #[test]
+    fn test_replog_overflow() {
+        let mut server = Server::new(&Conf);
+        server.repl_log_size_limit = 10;
+        let uuids: Vec<u64> = (0..5).map(|_| server.next_uuid(true)).collect();
+        for uuid in uuids.iter() {
+            server.replicate_cmd(*uuid, "set", vec![Message::BulkString("k".into()), Message::BulkString("v".into())]);
+        }
+        // each command takes 2 bytes, so only the latest 5 ones are kept
+        assert_eq!(server.get_repl_first_uuid(), uuids[0]);
+        assert!(!server.repl_log_overflowed(0));
+        assert_eq!(server.repl_log_next(0).map(|(u, _)| u), Some(uuids[0]));
+
+        let more: Vec<u64> = (0..2).map(|_| server.next_uuid(true)).collect();
+        for uuid in more.iter() {
+            server.replicate_cmd(*uuid, "set", vec![Message::BulkString("k".into()), Message::BulkString("v".into())]);
+        }
+        assert_eq!(server.get_repl_first_uuid(), uuids[2]);
+        // nothing new for the one who has received all the commands
+        assert!(server.repl_log_next(more[1]).is_none());
+        assert!(!server.repl_log_overflowed(more[1]));
+        // the one who has received up to the latest dropped command can continue
+        assert!(!server.repl_log_overflowed(uuids[1]));
+        assert_eq!(server.repl_log_next(uuids[1]).map(|(u, _)| u), Some(uuids[2]));
+        // while those who are behind it are too delayed
+        assert!(server.repl_log_next(uuids[0]).is_none());
+        assert!(server.repl_log_overflowed(uuids[0]));
+        assert!(server.repl_log_overflowed(0));
+    }
+
+    #[test]
+    fn test_replog_restart() {
+        let dir = std::env::temp_dir().join(format!("constdb_server_backlog_test_{}", std::process::id()));
+        let conf: &'static Config = Box::leak(Box::new(Config{
+            repl_backlog_size: 10,
+            repl_backlog_dir: dir.to_str().unwrap().to_string(),
+            repl_backlog_disk_size: 1024,
+            repl_backlog_segment_size: 64,
+            ..Conf.clone()
+        }));
+        let mut server = Server::new(conf);
+        let uuids: Vec<u64> = (0..10).map(|_| server.next_uuid(true)).collect();
+        for uuid in uuids.iter() {
+            server.replicate_cmd(*uuid, "set", vec![Message::BulkString("k".into()), Message::BulkString("v".into())]);
+        }
+        server.flush_logs(false);
+        drop(server);
+
+        // the repl_log is empty after the restart, the replicas continue with the backlog
+        let mut server = Server::new(conf);
+        assert_eq!(server.get_repl_last_uuid(), uuids[9]);
+        let (tombstone, _, _) = server.snapshot_stat();
+        assert_eq!(tombstone, uuids[9]);
+        // neither the one who has received everything, nor the one loading a snapshot at that uuid needs a FULLSYNC
+        assert!(server.repl_log_continuable(tombstone));
+        assert!(server.repl_log_next(tombstone).is_none());
+        assert_eq!(server.repl_log_next(uuids[5]).map(|(u, _)| u), Some(uuids[6]));
+
+        let uuid = server.next_uuid(true);
+        assert!(uuid > uuids[9]);
+        server.replicate_cmd(uuid, "set", vec![Message::BulkString("k".into()), Message::BulkString("v".into())]);
+        assert_eq!(server.repl_log_next(tombstone).map(|(u, _)| u), Some(uuid));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_incremental_dump() {
+        let conf: &'static Config = Box::leak(Box::new(Config{snapshot_mode: SnapshotMode::Incremental, snapshot_chunk_ms: 0, ..Conf.clone()}));
+        let mut server = Server::new(conf);
+        let uuid = server.next_uuid(true);
+        let keys: Vec<Bytes> = (0..200).map(|i| Bytes::from(format!("k{}", i).as_str())).collect();
+        for k in keys.iter() {
+            server.db.add(k.clone(), Object::new(Encoding::from(Bytes::from("old")), Timestamp::new(uuid, 1), Timestamp::default()));
+        }
+        let dumper = server.begin_incremental_dump("test_incremental_snapshot").unwrap();
+        let (done, rx) = tokio::sync::watch::channel(None);
+        server.bgsave = Some(BgSave{dumper, dirty: 0, stat: server.snapshot_stat(), rotated: None, done});
+        // keys are dumped 64 at a time, and all of them are modified after the first chunk
+        server.check_bgsave();
+        let uuid = server.next_uuid(true);
+        for k in keys.iter() {
+            server.db.query(k, uuid).unwrap().enc = Encoding::from(Bytes::from("new"));
+        }
+        server.db.add("added".into(), Object::new(Encoding::from(Bytes::from("new")), Timestamp::new(uuid, 1), Timestamp::default()));
+        server.db.add(keys[199].clone(), Object::new(Encoding::from(Bytes::from("new")), Timestamp::new(uuid, 1), Timestamp::default()));
+        while server.bgsave_in_progress() {
+            server.check_bgsave();
+        }
+        assert_eq!(*rx.borrow(), Some(true));
+
+        let mut loaded = Server::new(&Conf);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let r = rt.block_on(loaded.load_snapshot("test_incremental_snapshot"));
+        let _ = std::fs::remove_file("test_incremental_snapshot");
+        r.unwrap();
+        let now = loaded.next_uuid(false);
+        for k in keys.iter() {
+            match &loaded.db.query(k, now).unwrap().enc {
+                Encoding::Bytes(b) => assert_eq!(b.as_bytes(), b"old"),
+                _ => panic!("{} should be bytes", k.to_string()),
+            }
+        }
+        assert!(loaded.db.query(&"added".into(), now).is_none());
+    }
+
+    #[test]
+    fn test_snapshot_reload() {
+        let mut server = Server::new(&Conf);
+        let uuid = server.next_uuid(true);
+        server.db.add("b".into(), Object::new(Encoding::from(Bytes::from("bytes")), Timestamp::new(uuid, 1), Timestamp::default()));
+        let mut counter = Counter::default();
+        counter.change(1, 5, uuid);
+        server.db.add("c".into(), Object::new(Encoding::from(counter), Timestamp::new(uuid, 1), Timestamp::default()));
+        let mut set = Set::empty();
+        set.add_members(&["m1".into(), "m2".into()], Timestamp::new(uuid, 1));
+        set.remove_member(&"m2".into(), Timestamp::new(uuid + 1, 2));
+        server.db.add("s".into(), Object::new(Encoding::from(set), Timestamp::new(uuid, 1), Timestamp::default()));
+        let mut dict = Dict::empty();
+        dict.set_field("f".into(), "v".into(), Timestamp::new(uuid, 2));
+        server.db.add("d".into(), Object::new(Encoding::from(dict), Timestamp::new(uuid, 1), Timestamp::default()));
+        server.db.delete(&"gone".into(), uuid);
+        server.replicate_cmd(uuid, "set", vec![Message::BulkString("b".into()), Message::BulkString("bytes".into())]);
+        server.dump_all("test_server_snapshot".to_string()).unwrap();
+
+        let mut loaded = Server::new(&Conf);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let positions = rt.block_on(loaded.load_snapshot("test_server_snapshot"));
+        let _ = std::fs::remove_file("test_server_snapshot");
+        assert!(positions.unwrap().is_empty());
+        assert!(loaded.current_uuid() >= uuid);
+        let now = loaded.next_uuid(false);
+        match &loaded.db.query(&"b".into(), now).unwrap().enc {
+            Encoding::Bytes(b) => assert_eq!(b.as_bytes(), b"bytes"),
+            _ => panic!("b should be bytes"),
+        }
+        match &loaded.db.query(&"c".into(), now).unwrap().enc {
+            Encoding::Counter(c) => assert_eq!(c.get(), 5),
+            _ => panic!("c should be a counter"),
+        }
+        match &loaded.db.query(&"s".into(), now).unwrap().enc {
+            Encoding::LWWSet(s) => {
+                let members: Vec<&Bytes> = s.iter().map(|(m, _)| m).collect();
+                assert_eq!(members, vec![&Bytes::from("m1")]);
+            }
+            _ => panic!("s should be a set"),
+        }
+        match &loaded.db.query(&"d".into(), now).unwrap().enc {
+            Encoding::LWWDict(d) => assert_eq!(d.get(&"f".into()).map(|v| v.as_bytes()), Some(b"v".as_ref())),
+            _ => panic!("d should be a dict"),
+        }
+        // the node ids in the timestamps are kept
+        assert_eq!(loaded.db.query(&"b".into(), now).unwrap().create_time, Timestamp::new(uuid, 1));
+        assert_eq!(loaded.db.query(&"s".into(), now).unwrap().enc.as_set().unwrap().remove_time(&"m2".into()), Some(Timestamp::new(uuid + 1, 2)));
+        assert_eq!(loaded.db.query(&"d".into(), now).unwrap().enc.as_dict().unwrap().iter().next().map(|(_, (t, _))| t), Some(Timestamp::new(uuid, 2)));
+    }
+
+    #[test]
+    fn test_simultaneous_writes() {
+        let uuid = Server::new(&Conf).next_uuid(true);
+        let writes: Vec<(u64, &str)> = vec![
+            (2, "set k a"), (3, "set k b"),
+            (2, "hset h f a"), (3, "hset h f b"),
+            (2, "sadd s1 m"), (3, "srem s1 m"),
+            (3, "sadd s2 m"), (2, "srem s2 m"),
+        ];
+        let apply = |writes: &mut dyn Iterator<Item=&(u64, &str)>| {
+            let mut server = Server::new(&Conf);
+            for (nodeid, w) in writes {
+                let mut parts = w.split(' ');
+                let name = parts.next().unwrap();
+                let args = parts.map(|x| Message::BulkString(x.into())).collect();
+                Cmd::new(name.as_bytes(), args).unwrap().exec_detail(&mut server, None, *nodeid, uuid, false).unwrap();
+            }
+            let now = server.next_uuid(false);
+            let mut query = |w: &str| {
+                let mut parts = w.split(' ');
+                let name = parts.next().unwrap();
+                let args = parts.map(|x| Message::BulkString(x.into())).collect();
+                Cmd::new(name.as_bytes(), args).unwrap().exec_detail(&mut server, None, 1, now, false).unwrap()
+            };
+            vec![query("get k"), query("hget h f"), query("smembers s1"), query("smembers s2")]
+        };
+        let expected = vec![
+            Message::BulkString("b".into()),
+            Message::BulkString("b".into()),
+            Message::Array(vec![]),
+            Message::Array(vec![Message::BulkString("m".into())]),
+        ];
+        assert_eq!(apply(&mut writes.iter()), expected);
+        assert_eq!(apply(&mut writes.iter().rev()), expected);
+
+        // the same when the objects are merged
+        let (older, newer) = (Bytes::from("a"), Bytes::from("b"));
+        let objects = vec![
+            Object::new(Encoding::from(older), Timestamp::new(uuid, 2), Timestamp::default()),
+            Object::new(Encoding::from(newer.clone()), Timestamp::new(uuid, 3), Timestamp::default()),
+        ];
+        for order in [[0, 1], [1, 0]].iter() {
+            let mut o = objects[order[0]].clone();
+            o.merge(objects[order[1]].clone()).unwrap();
+            match o.enc {
+                Encoding::Bytes(b) => assert_eq!(b, newer),
+                _ => panic!("k should be bytes"),
+            }
+        }
+    }
+
+    // the writes of the nodes, as they are replicated: (node_id, uuid, command, args)
+    type History = Vec<(u64, u64, &'static str, Vec<Message>)>;
+
+    const KEYS: [&str; 4] = ["b", "c", "s", "h"];
+
+    fn random_command() -> (&'static str, Vec<String>) {
+        let (m, v, n) = (format!("m{}", thread_rng_n(4)), format!("v{}", thread_rng_n(4)), thread_rng_n(10) as i64 - 5);
+        match thread_rng_n(10) {
+            0 => ("set", vec!["b".to_string(), v]),
+            1 => ("incr", vec!["c".to_string()]),
+            2 => ("decr", vec!["c".to_string()]),
+            3 => ("incrby", vec!["c".to_string(), n.to_string()]),
+            4 => ("sadd", vec!["s".to_string(), m, format!("m{}", thread_rng_n(4))]),
+            5 => ("srem", vec!["s".to_string(), m]),
+            6 => ("hset", vec!["h".to_string(), m, v]),
+            7 => ("hdel", vec!["h".to_string(), m]),
+            _ => ("del", vec![KEYS[thread_rng_n(4) as usize].to_string()]),
+        }
+    }
+
+    fn apply(server: &mut Server, nodeid: u64, uuid: u64, name: &str, args: Vec<Message>) {
+        Cmd::new(name.as_bytes(), args).unwrap().exec_detail(server, None, nodeid, uuid, false).unwrap();
+    }
+
+    // what the clients can see of the keys, and the tombstones of them
+    fn visible(server: &mut Server) -> Vec<Option<serde_json::Value>> {
+        KEYS.iter().map(|k| server.db.query(&Bytes::from(*k), 0).map(|o| serde_json::json!({
+            "alive": o.alive(),
+            "update_time": o.update_time,
+            "delete_time": o.delete_time,
+            "value": o.to_json()["value"],
+        }))).collect()
+    }
+
+    // the nodes write and receive each other's writes randomly, the uuid of a write is greater than those
+    // of the writes the node has received, but the nodes may write at the same uuid before they meet.
+    fn random_history(nodes: &mut [Server]) -> History {
+        let mut history: History = vec![];
+        let mut received = vec![0usize; nodes.len() * nodes.len()];
+        let mut clocks = vec![1000u64; nodes.len()];
+        for _ in 0..thread_rng_n(60) {
+            let node = thread_rng_n(nodes.len() as u32) as usize;
+            let nodeid = node as u64 + 1;
+            if thread_rng_n(2) == 0 {
+                let (name, args) = random_command();
+                clocks[node] += 1;
+                let uuid = clocks[node];
+                let server = &mut nodes[node];
+                let before = server.repl_log.len();
+                let args = args.into_iter().map(|x| Message::BulkString(x.into())).collect();
+                Cmd::new(name.as_bytes(), args).unwrap().exec_detail(server, None, nodeid, uuid, name != "del").unwrap();
+                history.extend(server.repl_log.iter().skip(before).map(|(u, name, args)| (nodeid, *u, *name, args.clone())));
+            } else {
+                let from = thread_rng_n(nodes.len() as u32) as u64 + 1;
+                let pos = &mut received[node * nodes.len() + from as usize - 1];
+                if let Some((_, uuid, name, args)) = history.iter().filter(|(n, _, _, _)| *n == from && from != nodeid).nth(*pos) {
+                    *pos += 1;
+                    clocks[node] = max(clocks[node], *uuid);
+                    apply(&mut nodes[node], from, *uuid, name, args.clone());
+                }
+            }
+        }
+        // and everyone receives all the writes finally
+        for (node, server) in nodes.iter_mut().enumerate() {
+            for from in 1..=3 {
+                let pos = received[node * 3 + from as usize - 1];
+                for (_, uuid, name, args) in history.iter().filter(|(n, _, _, _)| *n == from && from != node as u64 + 1).skip(pos) {
+                    apply(server, from, *uuid, name, args.clone());
+                }
+            }
+        }
+        history
+    }
+
+    // a random order of the writes in which those of the same node are still in the order they're made.
+    // some of them are received twice, as the replicas may pull from an older position after they reconnect.
+    fn random_interleaving(history: &History) -> History {
+        let mut queues: Vec<History> = (1..=3).map(|n| history.iter().filter(|(x, _, _, _)| *x == n).cloned().collect()).collect();
+        for q in queues.iter_mut() {
+            if !q.is_empty() && thread_rng_n(2) == 0 {
+                let start = thread_rng_n(q.len() as u32) as usize;
+                let again: History = q[start..].to_vec();
+                q.extend(again);
+            }
+        }
+        let mut res = vec![];
+        while queues.iter().any(|q| !q.is_empty()) {
+            let q = &mut queues[thread_rng_n(3) as usize];
+            if !q.is_empty() {
+                res.push(q.remove(0));
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn test_random_replications() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for round in 0..20 {
+            let mut nodes = vec![Server::new(&Conf), Server::new(&Conf), Server::new(&Conf)];
+            for (i, node) in nodes.iter_mut().enumerate() {
+                node.node_id = i as u64 + 1;
+                node.repl_log_size_limit = u64::MAX;
+            }
+            let history = random_history(&mut nodes);
+            let expected = visible(&mut nodes[0]);
+            for node in nodes[1..].iter_mut() {
+                assert_eq!(visible(node), expected, "{:?}", history);
+            }
+
+            // the same in any order the writes are received, and no matter how many times
+            for _ in 0..3 {
+                let mut server = Server::new(&Conf);
+                for (nodeid, uuid, name, args) in random_interleaving(&history) {
+                    apply(&mut server, nodeid, uuid, name, args);
+                }
+                assert_eq!(visible(&mut server), expected, "{:?}", history);
+            }
+
+            // and after they are reloaded from a snapshot
+            let file_name = format!("test_random_replications_{}", round);
+            nodes[0].dump_all(file_name.clone()).unwrap();
+            let mut loaded = Server::new(&Conf);
+            let r = rt.block_on(loaded.load_snapshot(&file_name));
+            let _ = std::fs::remove_file(&file_name);
+            assert!(r.is_ok());
+            assert_eq!(visible(&mut loaded), expected, "{:?}", history);
+        }
+    }
+
+    #[test]
+    fn test_delta_sync() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let exec = |server: &mut Server, name: &str, args: Vec<String>| {
+            let uuid = server.next_uuid(true);
+            let args = args.into_iter().map(|x| Message::BulkString(x.into())).collect();
+            Cmd::new(name.as_bytes(), args).unwrap().exec_detail(server, None, 1, uuid, name != "del").unwrap();
+        };
+        let mut a = Server::new(&Conf);
+        a.node_id = 1;
+        a.repl_log_size_limit = u64::MAX;
+        for i in 0..1000 {
+            exec(&mut a, "hset", vec!["big".to_string(), format!("f{}", i), format!("v{}", i)]);
+        }
+        for _ in 0..20 {
+            let (name, args) = random_command();
+            exec(&mut a, name, args);
+        }
+        a.dump_all("test_delta_sync_full".to_string()).unwrap();
+        let mut b = Server::new(&Conf);
+        b.node_id = 2;
+        assert!(rt.block_on(b.load_snapshot("test_delta_sync_full")).is_ok());
+        let full_size = std::fs::metadata("test_delta_sync_full").unwrap().len();
+        let _ = std::fs::remove_file("test_delta_sync_full");
+
+        // b is too far behind, and only gets what's written since he's got the snapshot
+        let since = a.current_uuid();
+        for _ in 0..30 {
+            let (name, args) = random_command();
+            exec(&mut a, name, args);
+        }
+        assert!(a.db.delta_available(since));
+        let tombstone = a.begin_delta_dump(since, "test_delta_sync_delta").unwrap();
+        assert_eq!(tombstone, a.get_repl_last_uuid());
+        // the writes during the dump are sent after it anyway
+        exec(&mut a, "hset", vec!["big".to_string(), "f0".to_string(), "changed".to_string()]);
+        while !a.dump_delta_incrementally("test_delta_sync_delta").unwrap() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        exec(&mut b, "hset", vec!["big".to_string(), "f0".to_string(), "changed".to_string()]);
+        let delta_size = std::fs::metadata("test_delta_sync_delta").unwrap().len();
+        let r = rt.block_on(b.load_snapshot("test_delta_sync_delta"));
+        let _ = std::fs::remove_file("test_delta_sync_delta");
+        assert!(r.is_ok());
+        assert_eq!(visible(&mut b), visible(&mut a));
+        let big = b.db.query(&"big".into(), 0).unwrap();
+        assert_eq!(big.enc.as_dict().unwrap().iter().count(), 1000);
+        assert!(delta_size < full_size / 10, "delta {} full {}", delta_size, full_size);
+
+        // the delta would miss a tombstone once it's collected
+        let t = a.next_uuid(true);
+        a.db.delete(&"big".into(), t);
+        a.db.gc(t);
+        assert!(!a.db.delta_available(since));
+        // or during the dump
+        let since = a.current_uuid();
+        exec(&mut a, "hset", vec!["big".to_string(), "f1".to_string(), "v".to_string()]);
+        a.begin_delta_dump(since, "test_delta_sync_delta").unwrap();
+        let t = a.next_uuid(true);
+        a.db.delete(&"big".into(), t);
+        a.db.gc(t);
+        assert!(a.dump_delta_incrementally("test_delta_sync_delta").is_err());
+        assert!(!std::path::Path::new("test_delta_sync_delta").exists());
+
+        // nor is any delta since before we restarted
+        let since = a.current_uuid();
+        assert!(a.db.delta_available(since));
+        let now = a.next_uuid(true);
+        a.db.forget_collected(now);
+        assert!(!a.db.delta_available(since));
+    }
+
+    #[test]
+    fn test_replicate_commands_lost() {
+        let mut server = Server::new(&Conf);
+        server.node_id = 2;
+        let mut r = Replica::new("127.0.0.1:9999".to_string(), 2, String::new(), String::new(), false);
+        r.meta.he.id = 1;
+        let uuids: Vec<u64> = (0..4).map(|_| server.next_uuid(true)).collect();
+        let replicate = |prev: u64, uuid: u64| Message::Array(vec![
+            Message::BulkString("replicate".into()), Message::Integer(1), Message::Integer(prev as i64), Message::Integer(uuid as i64),
+            Message::BulkString("incr".into()), Message::BulkString("c".into()),
+        ]);
+        let puller = Puller{
+            uuid_he_sent: 0,
+            uuid_he_acked: 0,
+            meta: r.meta.clone(),
+            stats: PullStat::PullingCommands,
+            reader: Default::default(),
+            snapshot_size: 0,
+            merged_entries: 0,
+            clock_skew_ms: None,
+            his_versions: None,
+            snapshot_entries: Default::default(),
+            // the one after uuids[0] is lost
+            replicates: vec![replicate(0, uuids[0]), replicate(uuids[1], uuids[2]), replicate(uuids[2], uuids[3])].into(),
+            repairs: Default::default(),
+            replies: vec![],
+        };
+        let pusher = Pusher{
+            uuid_i_sent: 0,
+            uuid_i_acked: 0,
+            latest_ack_time: 0,
+            meta: r.meta.clone(),
+            stats: PushStat::PushingCommands,
+            writer: Default::default(),
+            events: server.events.new_consumer(),
+            relay_i_sent: 0,
+            his_versions: None,
+            his_versions_time: 0,
+            his_acks: 0,
+            relay_gap_at: None,
+            versions_to_send: None,
+            latest_anti_entropy_time: 0,
+        };
+        r.stat = ReplicaStat::Alive(puller, pusher);
+        r.interact_in_main(&mut server).unwrap();
+        // he's synced again from the latest command we have, rather than being closed
+        assert!(matches!(r.stat, ReplicaStat::NotConnected));
+        assert!(!r.to_close() && r.events.is_some());
+        assert_eq!(r.meta.uuid_he_sent, uuids[0]);
+        let now = server.next_uuid(false);
+        assert_eq!(Cmd::new(b"get", vec![Message::BulkString("c".into())]).unwrap().exec_detail(&mut server, None, 2, now, false).unwrap(), Message::Integer(1));
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let mut a = Server::new(&Conf);
+        a.node_id = 1;
+        let mut b = Server::new(&Conf);
+        b.node_id = 2;
+        let mut puller = new_puller(&a, &b);
+        let replack = |args: &[u64]| Message::Array(std::iter::once(Message::BulkString("replack".into()))
+            .chain(args.iter().map(|x| Message::Integer(*x as i64))).collect());
+        // his uuid is ahead of his wall clock after he observed a later one, which is not a skew
+        let now = crate::now_mil();
+        let his_uuid = (now + 30_000) << UUID_COUNTER_BITS;
+        puller.replicates.push_back(replack(&[0, his_uuid, now, 1, his_uuid]));
+        puller.merge_replicates_in_main(&mut b).unwrap();
+        assert!(puller.clock_skew_ms.unwrap().abs() < 1000);
+        assert_eq!(puller.his_versions.take().map(|v| v.uuid_of(1)), Some(his_uuid));
+        // the uuids of the older versions are of their wall clocks
+        puller.replicates.push_back(replack(&[0, his_uuid]));
+        puller.merge_replicates_in_main(&mut b).unwrap();
+        assert!((puller.clock_skew_ms.unwrap() - 30_000).abs() < 1000);
+    }
+
+    // the writes relayed by one server received by another
+    fn deliver_relays(from: &Server, to: &mut Server) -> u64 {
+        let mut r = Replica::new(from.addr.clone(), to.node_id, String::new(), String::new(), false);
+        r.meta.he.id = from.node_id;
+        let mut puller = Puller{
+            uuid_he_sent: 0,
+            uuid_he_acked: 0,
+            meta: r.meta.clone(),
+            stats: PullStat::PullingCommands,
+            reader: Default::default(),
+            snapshot_size: 0,
+            merged_entries: 0,
+            clock_skew_ms: None,
+            his_versions: None,
+            snapshot_entries: Default::default(),
+            replicates: Default::default(),
+            repairs: Default::default(),
+            replies: vec![],
+        };
+        let mut seq = 0;
+        while let Some(r) = from.relay_log_next(seq) {
+            seq = r.seq;
+            puller.replicates.push_back(r.to_message());
+        }
+        let before = to.relay_seq;
+        while !puller.replicates.is_empty() {
+            puller.merge_replicates_in_main(to).unwrap();
+        }
+        to.relay_seq - before
+    }
+
+    #[test]
+    fn test_relay() {
+        let mut nodes: Vec<Server> = (1..=5).map(|i| {
+            let mut server = Server::new(&Conf);
+            server.node_id = i;
+            server.repl_log_size_limit = u64::MAX;
+            server
+        }).collect();
+        let mut a = nodes.remove(0);
+        for _ in 0..30 {
+            let (name, args) = random_command();
+            let uuid = a.next_uuid(true);
+            let args = args.into_iter().map(|x| Message::BulkString(x.into())).collect();
+            Cmd::new(name.as_bytes(), args).unwrap().exec_detail(&mut a, None, 1, uuid, name != "del").unwrap();
+        }
+        // b pulls from a, c is unable to reach a but pulls from b, and d from c
+        let (b, rest) = nodes.split_first_mut().unwrap();
+        let mut prev = 0;
+        while let Some((uuid, Message::Array(msg))) = a.repl_log_next(prev) {
+            let mut args = msg.into_iter().skip(4);
+            let name = args.next_bytes().unwrap();
+            let cmd = Cmd::new(name.as_bytes(), args.collect()).unwrap();
+            assert!(b.apply_replicated(1, 1, prev, uuid, &cmd, &a.addr));
+            prev = uuid;
+        }
+        let applied = deliver_relays(b, &mut rest[0]);
+        assert!(applied > 0);
+        let (c, rest) = rest.split_first_mut().unwrap();
+        assert_eq!(deliver_relays(c, &mut rest[0]), applied);
+        let expected = visible(&mut a);
+        assert_eq!(visible(b), expected);
+        assert_eq!(visible(c), expected);
+        assert_eq!(visible(&mut rest[0]), expected);
+        assert_eq!(c.versions.uuid_of(1), a.get_repl_last_uuid());
+
+        // the writes received twice are applied once
+        assert_eq!(deliver_relays(b, c), 0);
+        assert_eq!(deliver_relays(c, b), 0);
+        let last = a.get_repl_last_uuid();
+        let cmd = Cmd::new(b"incr", vec![Message::BulkString("c".into())]).unwrap();
+        assert!(!c.apply_replicated(1, 1, 0, last, &cmd, &a.addr));
+        assert_eq!(visible(c), expected);
+
+        // and none of those after some missing ones
+        let e = &mut rest[1];
+        b.relay_log.pop_front();
+        assert_eq!(deliver_relays(b, e), 0);
+        assert_eq!(e.versions.uuid_of(1), 0);
+
+        // a snapshot is followed by the relay_log only if none of the writes after it is dropped
+        assert!(b.relay_log_follows(&VClock::default()));
+        b.repl_log_size_limit = 0;
+        assert!(!b.apply_replicated(1, 1, 0, last, &cmd, &a.addr));
+        let uuid = b.next_uuid(true);
+        b.apply_replicated(4, 4, 0, uuid, &cmd, "d");
+        assert!(b.relay_log_next(0).is_none());
+        assert!(!b.relay_log_follows(&VClock::default()));
+        assert!(b.relay_log_follows(&b.versions));
+    }
+
+    #[test]
+    fn test_relay_after_restart() {
+        let dir = std::env::temp_dir().join(format!("constdb_relay_restart_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot = dir.join("snapshot").to_str().unwrap().to_string();
+        let conf: &'static Config = Box::leak(Box::new(Config{
+            node_id: 2,
+            appendonly: true,
+            appendfilename: dir.join("appendonly.aof").to_str().unwrap().to_string(),
+            ..Conf.clone()
+        }));
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let cmd = Cmd::new(b"incr", vec![Message::BulkString("c".into())]).unwrap();
+        let mut b = Server::new(conf);
+        b.load_aof(&mut std::collections::HashMap::new()).unwrap();
+        let uuids: Vec<u64> = (0..4).map(|_| b.next_uuid(true)).collect();
+        // the writes of a, some of them are in the snapshot, and the one after it only in the aof
+        assert!(b.apply_replicated(1, 1, 0, uuids[0], &cmd, "a"));
+        assert!(b.apply_replicated(1, 1, uuids[0], uuids[1], &cmd, "a"));
+        b.dump_all(snapshot.clone()).unwrap();
+        assert!(b.apply_replicated(1, 1, uuids[1], uuids[2], &cmd, "a"));
+        b.flush_logs(false);
+        drop(b);
+
+        let mut loaded = Server::new(conf);
+        rt.block_on(loaded.load_snapshot(&snapshot)).unwrap();
+        assert_eq!(loaded.versions.uuid_of(1), uuids[1]);
+        loaded.load_aof(&mut std::collections::HashMap::new()).unwrap();
+        assert_eq!(loaded.versions.uuid_of(1), uuids[2]);
+        // so the writes of a relayed by another replica still follow ours
+        assert!(!loaded.apply_relayed(1, 3, uuids[1], uuids[2], &cmd, "c"));
+        assert!(loaded.apply_relayed(1, 3, uuids[2], uuids[3], &cmd, "c"));
+        let now = loaded.next_uuid(false);
+        assert_eq!(Cmd::new(b"get", vec![Message::BulkString("c".into())]).unwrap().exec_detail(&mut loaded, None, 2, now, false).unwrap(), Message::Integer(4));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    fn new_puller(from: &Server, to: &Server) -> Puller {
+        let mut r = Replica::new(from.addr.clone(), to.node_id, String::new(), String::new(), false);
+        r.meta.he.id = from.node_id;
+        Puller{
+            uuid_he_sent: 0,
+            uuid_he_acked: 0,
+            meta: r.meta.clone(),
+            stats: PullStat::PullingCommands,
+            reader: Default::default(),
+            snapshot_size: 0,
+            merged_entries: 0,
+            clock_skew_ms: None,
+            his_versions: None,
+            snapshot_entries: Default::default(),
+            replicates: Default::default(),
+            repairs: Default::default(),
+            replies: vec![],
+        }
+    }
+
+    // a starts comparing his data with b's, returns how many messages they exchange
+    fn anti_entropy(rt: &tokio::runtime::Runtime, a: &mut Server, b: &mut Server) -> usize {
+        let horizon = a.anti_entropy_horizon();
+        let (mut pa, mut pb) = (new_puller(b, a), new_puller(a, b));
+        let mut msgs = vec![(false, a.merkle_tree(horizon).to_message(horizon, 0, 0))];
+        let mut exchanged = 0;
+        while let Some((to_a, msg)) = msgs.pop() {
+            exchanged += 1;
+            let (server, puller) = if to_a { (&mut *a, &mut pa) } else { (&mut *b, &mut pb) };
+            let msg = rt.block_on(puller.load_repairs(msg)).unwrap();
+            puller.replicates.push_back(msg);
+            puller.merge_replicates_in_main(server).unwrap();
+            msgs.extend(puller.replies.drain(..).map(|m| (!to_a, m)));
+        }
+        exchanged
+    }
+
+    #[test]
+    fn test_anti_entropy() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (mut a, mut b) = (Server::new(&Conf), Server::new(&Conf));
+        a.node_id = 1;
+        b.node_id = 2;
+        a.next_uuid(false);
+        b.next_uuid(false);
+        // the writes long before, and some of them are lost by b
+        let mut uuid = (a.current_time() - 3600 * 1000) << UUID_COUNTER_BITS;
+        for i in 0..500 {
+            uuid += 1;
+            let (name, args) = ("hset", vec![format!("h{}", i % 50), format!("f{}", i), "v".to_string()]);
+            let args: Vec<Message> = args.into_iter().map(|x| Message::BulkString(x.into())).collect();
+            apply(&mut a, 1, uuid, name, args.clone());
+            if i % 100 != 57 {
+                apply(&mut b, 1, uuid, name, args);
+            }
+        }
+        for _ in 0..30 {
+            uuid += 1;
+            let (name, args) = random_command();
+            let args: Vec<Message> = args.into_iter().map(|x| Message::BulkString(x.into())).collect();
+            apply(&mut a, 2, uuid, name, args.clone());
+            apply(&mut b, 2, uuid, name, args);
+        }
+        // and b has a key of his own, which is deleted on a
+        uuid += 1;
+        apply(&mut b, 2, uuid, "set", vec![Message::BulkString("k".into()), Message::BulkString("v".into())]);
+        apply(&mut a, 2, uuid, "set", vec![Message::BulkString("k".into()), Message::BulkString("v".into())]);
+        uuid += 1;
+        apply(&mut a, 1, uuid, "del", vec![Message::BulkString("k".into())]);
+        let horizon = a.anti_entropy_horizon();
+        assert_ne!(a.merkle_tree(horizon).root(), b.merkle_tree(horizon).root());
+
+        let dirty = (a.dirty, b.dirty);
+        assert!(anti_entropy(&rt, &mut a, &mut b) > 1);
+        a.merkle = None;
+        b.merkle = None;
+        assert_eq!(a.merkle_tree(horizon).root(), b.merkle_tree(horizon).root());
+        for i in 0..50 {
+            let k = Bytes::from(format!("h{}", i));
+            assert_eq!(a.db.query(&k, 0).unwrap().to_json(), b.db.query(&k, 0).unwrap().to_json());
+        }
+        assert!(!b.db.query(&"k".into(), 0).unwrap().alive());
+        // only the keys b is behind in are repaired
+        assert_eq!(a.dirty, dirty.0);
+        assert_eq!(b.dirty - dirty.1, 2);
+
+        // nothing is sent once they're the same
+        assert_eq!(anti_entropy(&rt, &mut a, &mut b), 1);
+    }
+
+    #[test]
+    fn test_anti_entropy_in_orders() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let (mut a, mut b) = (Server::new(&Conf), Server::new(&Conf));
+        a.node_id = 1;
+        b.node_id = 2;
+        a.next_uuid(false);
+        b.next_uuid(false);
+        // the same writes received in different orders, the objects are created at different times then
+        let uuid = (a.current_time() - 3600 * 1000) << UUID_COUNTER_BITS;
+        let writes: Vec<(u64, u64, &str)> = vec![(1, uuid, "hset h f1 v"), (2, uuid + 1, "hset h f2 v"), (1, uuid + 2, "sadd s m1"), (2, uuid + 3, "sadd s m2")];
+        for (i, (nodeid, uuid, w)) in writes.iter().chain(writes.iter().rev()).enumerate() {
+            let mut parts = w.split(' ');
+            let name = parts.next().unwrap();
+            let args = parts.map(|x| Message::BulkString(x.into())).collect();
+            apply(if i < writes.len() { &mut a } else { &mut b }, *nodeid, *uuid, name, args);
+        }
+        let h = Bytes::from("h");
+        assert_ne!(a.db.query(&h, 0).unwrap().create_time, b.db.query(&h, 0).unwrap().create_time);
+
+        let horizon = a.anti_entropy_horizon();
+        assert_eq!(a.merkle_tree(horizon).root(), b.merkle_tree(horizon).root());
+        let dirty = (a.dirty, b.dirty);
+        assert_eq!(anti_entropy(&rt, &mut a, &mut b), 1);
+        assert_eq!((a.dirty, b.dirty), dirty);
+    }
+
+    #[test]
+    fn test_delcnt_of_older_versions() {
+        let first = Server::new(&Conf).next_uuid(true);
+        let exec = |server: &mut Server, nodeid: u64, uuid: u64, w: &str| {
+            let mut parts = w.split(' ');
+            let name = parts.next().unwrap();
+            let args = parts.map(|x| Message::BulkString(x.into())).collect();
+            Cmd::new(name.as_bytes(), args).unwrap().exec_detail(server, None, nodeid, uuid, false).unwrap()
+        };
+        // the older versions take the values back in the slots of their nodes, while we take them back in ours
+        for del in ["delcnt c 1 -2 2 -1", "delcounter c 1 -2 2 -1"].iter() {
+            let mut server = Server::new(&Conf);
+            exec(&mut server, 1, first, "incrby c 2");
+            exec(&mut server, 2, first + 1, "incrby c 1");
+            exec(&mut server, 3, first + 2, del);
+            exec(&mut server, 2, first + 3, "incrby c 5");
+            let now = server.next_uuid(false);
+            assert_eq!(exec(&mut server, 1, now, "get c"), Message::Integer(5));
+        }
+    }
+
+    #[test]
+    fn test_type_conflicts() {
+        let first = Server::new(&Conf).next_uuid(true);
+        let writes: Vec<(u64, u64, &str)> = vec![(2, first, "incr k"), (3, first + 1, "sadd k m"), (2, first + 2, "incr k")];
+        let exec = |server: &mut Server, nodeid: u64, uuid: u64, w: &str| {
+            let mut parts = w.split(' ');
+            let name = parts.next().unwrap();
+            let args = parts.map(|x| Message::BulkString(x.into())).collect();
+            Cmd::new(name.as_bytes(), args).unwrap().exec_detail(server, None, nodeid, uuid, false)
+        };
+        // the writes of a node are always received in the order they're made
+        let orders = [[0, 1, 2], [0, 2, 1], [1, 0, 2]];
+        for order in orders.iter() {
+            let mut server = Server::new(&Conf);
+            for i in order.iter() {
+                let (nodeid, uuid, w) = writes[*i];
+                exec(&mut server, nodeid, uuid, w).unwrap();
+            }
+            // the counter is written later, and the set is kept
+            let now = server.next_uuid(false);
+            assert_eq!(exec(&mut server, 1, now, "get k").unwrap(), Message::Integer(2));
+            assert!(server.db.type_conflicts > 0);
+            let set = server.db.query_or_create(&"k".into(), Timestamp::new(now, 1), Encoding::from(Set::empty()), true).unwrap();
+            assert_eq!(set.enc.as_set().unwrap().size(), 1);
+            // but our clients can't write it as a set
+            assert!(server.db.query_or_create(&"k".into(), Timestamp::new(now, 1), Encoding::from(Set::empty()), false).is_err());
+        }
+
+        // the shadows are kept in the snapshots
+        let mut server = Server::new(&Conf);
+        for (nodeid, uuid, w) in writes.iter() {
+            exec(&mut server, *nodeid, *uuid, w).unwrap();
+        }
+        server.dump_all("test_type_conflicts_snapshot".to_string()).unwrap();
+        let mut loaded = Server::new(&Conf);
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let r = rt.block_on(loaded.load_snapshot("test_type_conflicts_snapshot"));
+        let _ = std::fs::remove_file("test_type_conflicts_snapshot");
+        assert!(r.is_ok());
+        let now = loaded.next_uuid(false);
+        assert_eq!(exec(&mut loaded, 1, now, "get k").unwrap(), Message::Integer(2));
+        // a newer write to the set makes it the one of the key
+        exec(&mut loaded, 3, now, "sadd k n").unwrap();
+        match exec(&mut loaded, 1, now, "smembers k").unwrap() {
+            Message::Array(members) => assert_eq!(members.len(), 2),
+            m => panic!("unexpected reply {}", m),
+        }
+    }
+
+    #[test]
+    fn test_import_rdb_entry() {
+        let mut server = Server::new(&Conf);
+        let get = |server: &mut Server, key: &str, uuid: u64| {
+            Cmd::new(b"get", vec![Message::BulkString(key.into())]).unwrap().exec_detail(server, None, 1, uuid, false).unwrap()
+        };
+        // the counters are set to the values in the file, however many times it's imported
+        for _ in 0..2 {
+            server.import_rdb_entry(b"c".to_vec(), RdbValue::String(b"5".to_vec()), true, None).unwrap();
+            let now = server.next_uuid(false);
+            assert_eq!(get(&mut server, "c", now), Message::Integer(5));
+        }
+        server.import_rdb_entry(b"c".to_vec(), RdbValue::String(b"-3".to_vec()), true, None).unwrap();
+        let now = server.next_uuid(false);
+        assert_eq!(get(&mut server, "c", now), Message::Integer(-3));
+
+        // the ttl is kept
+        let at = crate::now_mil() + 1000;
+        server.import_rdb_entry(b"s".to_vec(), RdbValue::String(b"v".to_vec()), false, Some(at)).unwrap();
+        let now = server.next_uuid(false);
+        assert_eq!(get(&mut server, "s", now), Message::BulkString("v".into()));
+        assert_eq!(get(&mut server, "s", (at + 1) << UUID_COUNTER_BITS), Message::Nil);
+        // and replicated with the value, so that the other nodes expire it at the same time
+        let mut other = Server::new(&Conf);
+        for uuid in server.repl_log_uuids() {
+            if let Some(Message::Array(cmd)) = server.repl_log_at(uuid) {
+                let mut cmd = cmd.into_iter();
+                let name = cmd.next_bytes().unwrap();
+                Cmd::new(name.as_bytes(), cmd.collect()).unwrap().exec_detail(&mut other, None, 1, uuid, false).unwrap();
+            }
+        }
+        assert_eq!(get(&mut other, "s", now), Message::BulkString("v".into()));
+        assert_eq!(get(&mut other, "s", (at + 1) << UUID_COUNTER_BITS), Message::Nil);
+
+        // a key of another type is an error, instead of a shadow
+        let conflicts = server.db.type_conflicts;
+        assert!(server.import_rdb_entry(b"c".to_vec(), RdbValue::Set(vec![b"m".to_vec()]), false, None).is_err());
+        assert_eq!(server.db.type_conflicts, conflicts);
+        let now = server.next_uuid(false);
+        assert_eq!(get(&mut server, "c", now), Message::Integer(-3));
+    }
+
+    #[test]
+    fn test_debug_digest_in_orders() {
+        let first = Server::new(&Conf).next_uuid(true);
+        let writes: Vec<(u64, u64, &str)> = vec![(1, first, "hset h f1 v"), (2, first + 1, "hset h f2 v"), (1, first + 2, "incr c"), (2, first + 3, "set k v")];
+        let exec = |server: &mut Server, nodeid: u64, uuid: u64, w: &str| {
+            let mut parts = w.split(' ');
+            let name = parts.next().unwrap();
+            let args = parts.map(|x| Message::BulkString(x.into())).collect();
+            Cmd::new(name.as_bytes(), args).unwrap().exec_detail(server, None, nodeid, uuid, false).unwrap()
+        };
+        let digests = |order: &mut dyn Iterator<Item = &(u64, u64, &str)>| {
+            let mut server = Server::new(&Conf);
+            for (nodeid, uuid, w) in order {
+                exec(&mut server, *nodeid, *uuid, w);
+            }
+            let now = server.next_uuid(false);
+            (exec(&mut server, 1, now, "debug digest"), exec(&mut server, 1, now, "debug digest-value h c k"))
+        };
+        // the objects are created at different times, while the data clients see is the same
+        assert_eq!(digests(&mut writes.iter()), digests(&mut writes.iter().rev()));
+    }
 }
 
 // pub struct EventsProducer {
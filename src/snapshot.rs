@@ -4,12 +4,66 @@ use crc64::Crc64;
 use std::fmt;
 use std::io;
 use std::io::{BufWriter, Write};
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// how the snapshot is compressed, which is written in the header following the version.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl Compression {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Compression::None),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    // the compressions separated by commas, the unknown ones are ignored
+    pub fn parse_list(s: &str) -> Vec<Self> {
+        s.split(',').filter_map(|x| Self::from_name(x.trim())).filter(|x| *x != Compression::None).collect()
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    fn code(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+}
+
+// everything following the header of a compressed snapshot is cut into blocks of this size, each
+// of which is written as the compressed length(4 bytes), the raw length(4 bytes) and the compressed data.
+const COMPRESSION_BLOCK_SIZE: usize = 256 * 1024;
+const COMPRESSION_BLOCK_HEADER_SIZE: usize = 8;
+// the most bytes the loader reads from the file at once
+const SNAPSHOT_READ_CHUNK: usize = 64 * 1024;
 
 pub struct SnapshotWriter<W: Write> {
     io: BufWriter<W>,
     wrote_size: usize,
     checksum_writter: Crc64,
+    compression: Compression,
+    // the bytes to be compressed, which is used after the header is written
+    block: Option<Vec<u8>>,
 }
 
 impl<W: Write> SnapshotWriter<W> {
@@ -18,12 +72,24 @@ impl<W: Write> SnapshotWriter<W> {
             io: BufWriter::with_capacity(size, io),
             wrote_size: 0,
             checksum_writter: Crc64::new(),
+            compression: Compression::None,
+            block: None,
         }
     }
 
+    // compress the snapshot, which takes effect after the header is written
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     #[inline]
     pub fn write_integer(&mut self, i: i64) -> std::io::Result<&mut Self> {
-        if i < 1 << 6 {
+        // the negative ones, like the values of counters, don't fit in the short forms
+        if i < 0 {
+            self.write_bytes([3 << 6].as_ref())?;
+            self.write_bytes(i.to_be_bytes().as_ref())
+        } else if i < 1 << 6 {
             self.write_bytes([i as u8].as_ref())
         } else if i < 1 << 14 {
             self.write_bytes(i16::to_be_bytes((i as i16) | 1 << 14).as_ref())
@@ -38,14 +104,48 @@ impl<W: Write> SnapshotWriter<W> {
 
     pub fn write_bytes(&mut self, src: &[u8]) -> std::io::Result<&mut Self> {
         self.checksum_writter.write(src)?;
-        self.io.write(src).map(|x| {
-            self.wrote_size += x;
-            x
-        })?;
+        self.wrote_size += src.len();
+        if self.block.is_none() {
+            self.io.write_all(src)?;
+            return Ok(self);
+        }
+        let mut src = src;
+        while let Some(block) = self.block.as_mut() {
+            let n = std::cmp::min(src.len(), COMPRESSION_BLOCK_SIZE - block.len());
+            block.extend_from_slice(&src[..n]);
+            src = &src[n..];
+            if block.len() >= COMPRESSION_BLOCK_SIZE {
+                self.write_block()?;
+            }
+            if src.is_empty() {
+                break;
+            }
+        }
         Ok(self)
     }
 
+    fn write_block(&mut self) -> std::io::Result<()> {
+        let block = match &mut self.block {
+            Some(b) if !b.is_empty() => b,
+            _ => return Ok(()),
+        };
+        // lz4 is the only one we have for now
+        let compressed = lz4_flex::block::compress(block);
+        self.io.write_all(&(compressed.len() as u32).to_le_bytes())?;
+        self.io.write_all(&(block.len() as u32).to_le_bytes())?;
+        self.io.write_all(&compressed)?;
+        block.clear();
+        Ok(())
+    }
+
+    // an entry of the data section, which is written as an item since the node ids follow the object
     pub fn write_entry(&mut self, key: &[u8], value: &Object) -> Result<(), CstError> {
+        self.write_legacy_entry(key, value)?;
+        value.save_nodes(self)
+    }
+
+    // an entry without the node ids, which is how the legacy format writes it
+    pub fn write_legacy_entry(&mut self, key: &[u8], value: &Object) -> Result<(), CstError> {
         self.write_integer(key.len() as i64)?;
         self.write_bytes(key)?;
         value.save_snapshot(self)
@@ -55,6 +155,43 @@ impl<W: Write> SnapshotWriter<W> {
         self.write_bytes([d; 1].as_ref())
     }
 
+    // the magic and the version of the format we write, and how the rest is compressed
+    pub fn write_head(&mut self) -> std::io::Result<&mut Self> {
+        if self.compression == Compression::None {
+            return self.write_bytes(b"CONSTDB")?.write_bytes(SNAPSHOT_VERSION.as_ref());
+        }
+        let code = self.compression.code();
+        let _ = self.write_bytes(b"CONSTDB")?
+            .write_bytes(SNAPSHOT_VERSION_COMPRESSED.as_ref())?
+            .write_byte(code)?;
+        self.block = Some(Vec::with_capacity(COMPRESSION_BLOCK_SIZE));
+        Ok(self)
+    }
+
+    // a section is made of a flag, the number of items and the items
+    pub fn write_section(&mut self, flag: u8, count: usize) -> std::io::Result<&mut Self> {
+        self.write_byte(flag)?.write_integer(count as i64)
+    }
+
+    // an item is prefixed with its length, so that the loader is able to skip the fields it doesn't know
+    pub fn write_item<F>(&mut self, f: F) -> Result<(), CstError>
+    where
+        F: FnOnce(&mut SnapshotWriter<Vec<u8>>) -> Result<(), CstError>,
+    {
+        let mut item = SnapshotWriter::new(0, Vec::new());
+        f(&mut item)?;
+        item.flush()?;
+        let payload = std::mem::take(item.get_mut());
+        self.write_integer(payload.len() as i64)?.write_bytes(&payload)?;
+        Ok(())
+    }
+
+    pub fn write_checksum(&mut self) -> std::io::Result<&mut Self> {
+        self.write_byte(SNAPSHOT_FLAG_CHECKSUM)?;
+        let checksum = self.checksum();
+        self.write_bytes(checksum.to_le_bytes().as_ref())
+    }
+
     pub fn total_wrote(&self) -> usize {
         self.wrote_size
     }
@@ -64,8 +201,68 @@ impl<W: Write> SnapshotWriter<W> {
     }
 
     pub fn flush(&mut self) -> Result<(), CstError> {
+        self.write_block()?;
         self.io.flush().map_err(|x| x.into())
     }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.io.get_mut()
+    }
+}
+
+// the chunks sent but not written yet, the main thread waits for the writer when there're more,
+// rather than holding a snapshot in memory when the disk is slow.
+const SNAPSHOT_CHUNKS_IN_FLIGHT: usize = 16;
+
+// sends the bytes of a snapshot serialized by the main thread to the thread writing them to the file.
+pub struct ChunkSender {
+    tx: std::sync::mpsc::SyncSender<Vec<u8>>,
+}
+
+impl Write for ChunkSender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        self.tx.send(buf.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the snapshot writer has exited"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ChunkSender {
+    // the snapshot is complete, the writer can make it visible now.
+    // if the sender is dropped without committing, the snapshot is discarded.
+    pub fn commit(&mut self) -> io::Result<()> {
+        self.tx.send(vec![]).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the snapshot writer has exited"))
+    }
+}
+
+// spawn a thread writing the chunks into a temporary file, which is renamed to file_name when committed.
+pub fn spawn_snapshot_writer(file_name: String) -> (ChunkSender, std::thread::JoinHandle<Result<(), CstError>>) {
+    let (tx, rx) = std::sync::mpsc::sync_channel::<Vec<u8>>(SNAPSHOT_CHUNKS_IN_FLIGHT);
+    let handle = std::thread::spawn(move || {
+        let tmp_name = format!("{}.tmp", file_name);
+        let mut f = std::fs::OpenOptions::new().create(true).write(true).truncate(true).open(&tmp_name)?;
+        loop {
+            match rx.recv() {
+                Ok(chunk) if chunk.is_empty() => break,
+                Ok(chunk) => f.write_all(&chunk)?,
+                Err(_) => {
+                    drop(f);
+                    let _ = std::fs::remove_file(&tmp_name);
+                    return Err(CstError::SystemError);
+                }
+            }
+        }
+        f.sync_all()?;
+        std::fs::rename(&tmp_name, &file_name)?;
+        Ok(())
+    });
+    (ChunkSender{tx}, handle)
 }
 
 pub type FileSnapshotLoader = SnapshotLoader<tokio::fs::File>;
@@ -102,6 +299,15 @@ pub struct SnapshotLoader<T> {
     io: T,
     read_size: usize,
     checksum_writter: DebugCrc64,
+    format: u8,
+    compression: Compression,
+    // the decompressed block being read and the position in it
+    block: Option<(Vec<u8>, usize)>,
+    // the flag and the number of items of the section being read, and how many sections have been read
+    section: (u8, usize),
+    sections: usize,
+    // the end of the item being read, no length in it could go beyond
+    item_end: Option<usize>,
 }
 
 impl<T> SnapshotLoader<T>
@@ -114,6 +320,12 @@ where
             io: f,
             read_size: 0,
             checksum_writter: DebugCrc64::new(),
+            format: 0,
+            compression: Compression::None,
+            block: None,
+            section: (0, 0),
+            sections: 0,
+            item_end: None,
         }
     }
 
@@ -121,66 +333,105 @@ where
         loop {
             match &mut self.stat {
                 SnapshotLoadProgress::Begin => {
-                    self.read_bytes(7).await?;
+                    if self.read_bytes(7).await? != b"CONSTDB" {
+                        return Err(CstError::InvalidSnapshot(0));
+                    }
                     self.stat = SnapshotLoadProgress::Version;
                 }
                 SnapshotLoadProgress::Version => {
                     let version = self.read_bytes(4).await?;
-                    if version.len() != 4 {
-                        return Err(CstError::InvalidSnapshot(7));
-                    }
-                    let v = format!(
+                    let v: Bytes = format!(
                         "{}.{}.{}.{}",
                         version[0], version[1], version[2], version[3]
                     )
                     .into();
-                    self.stat = SnapshotLoadProgress::Node;
+                    self.format = version[1];
+                    match self.format {
+                        SNAPSHOT_FORMAT_LEGACY => {
+                            self.enter_section(SNAPSHOT_FLAG_NODE, 1);
+                            self.stat = SnapshotLoadProgress::Node;
+                        }
+                        SNAPSHOT_FORMAT => self.next_section().await?,
+                        SNAPSHOT_FORMAT_COMPRESSED => {
+                            let code = self.read_byte().await?;
+                            self.compression = Compression::from_code(code)
+                                .ok_or_else(|| CstError::IncompatibleSnapshot(format!("{} with compression {}", v.to_string(), code)))?;
+                            self.block = Some((vec![], 0));
+                            self.next_section().await?;
+                        }
+                        _ => return Err(CstError::IncompatibleSnapshot(v.to_string())),
+                    }
+                    if version[2] > SNAPSHOT_VERSION[2] {
+                        info!("The snapshot of version {} is newer than ours, the parts we don't know will be skipped", v.to_string());
+                    }
                     return Ok(Some(SnapshotEntry::Version(v)));
                 }
+                SnapshotLoadProgress::Section(flag, count, current) => {
+                    if *current < *count {
+                        *current += 1;
+                        let flag = *flag;
+                        let size = self.read_len().await?;
+                        let end = self.read_size.checked_add(size).ok_or(CstError::InvalidSnapshot(self.read_size))?;
+                        self.item_end = Some(end);
+                        let entry = match flag {
+                            SNAPSHOT_FLAG_NODE => Some(self.read_node().await?),
+                            SNAPSHOT_FLAG_REPLICA_ADD => Some(self.read_replica_add().await?),
+                            SNAPSHOT_FLAG_REPLICA_REM => Some(self.read_replica_del().await?),
+                            SNAPSHOT_FLAG_DATAS => {
+                                let (key, value) = self.read_entry(Some(end)).await?;
+                                Some(SnapshotEntry::Data(key, value))
+                            }
+                            SNAPSHOT_FLAG_SHADOWS => {
+                                let (key, value) = self.read_entry(Some(end)).await?;
+                                Some(SnapshotEntry::Shadow(key, value))
+                            }
+                            SNAPSHOT_FLAG_EXPIRES => {
+                                let (key, ttl) = self.read_key_int().await?;
+                                Some(SnapshotEntry::Expires(key, ttl))
+                            }
+                            SNAPSHOT_FLAG_DELETES => {
+                                let (key, del_time) = self.read_key_int().await?;
+                                Some(SnapshotEntry::Deletes(key, del_time))
+                            }
+                            SNAPSHOT_FLAG_VERSIONS => {
+                                let node_id = self.read_integer().await? as u64;
+                                Some(SnapshotEntry::Versions(node_id, self.read_integer().await? as u64))
+                            }
+                            _ => None,
+                        };
+                        // the fields appended by newer versions
+                        if self.read_size > end {
+                            return Err(CstError::InvalidSnapshot(end));
+                        }
+                        self.read_bytes(end - self.read_size).await?;
+                        self.item_end = None;
+                        if entry.is_some() {
+                            return Ok(entry);
+                        }
+                    } else {
+                        self.next_section().await?;
+                    }
+                }
                 SnapshotLoadProgress::Node => {
-                    let nodid = self.read_integer().await? as u64;
-                    let alias_len = self.read_integer().await? as usize;
-                    let alias = String::from_utf8(self.read_bytes(alias_len).await?)
-                        .map_err(|_| CstError::InvalidSnapshot(self.read_size))
-                        .unwrap();
-                    let addr_len = self.read_integer().await? as usize;
-                    let addr = String::from_utf8(self.read_bytes(addr_len).await?)
-                        .map_err(|_| CstError::InvalidSnapshot(self.read_size))
-                        .unwrap();
-                    let uuid = self.read_integer().await? as u64;
-                    self.convert_stat().await?;
-                    return Ok(Some(SnapshotEntry::Node(nodid, alias, addr, uuid)));
+                    let entry = self.read_node().await?;
+                    self.stat = SnapshotLoadProgress::LegacySection;
+                    return Ok(Some(entry));
                 }
                 SnapshotLoadProgress::Replicas(true) => {
-                    let add_time = self.read_integer().await? as u64;
-                    let nodid = self.read_integer().await? as u64;
-                    let alias_len = self.read_integer().await? as usize;
-                    let alias = String::from_utf8(self.read_bytes(alias_len).await?)
-                        .map_err(|_| CstError::InvalidSnapshot(self.read_size))
-                        .unwrap();
-                    let addr_len = self.read_integer().await? as usize;
-                    let addr = String::from_utf8(self.read_bytes(addr_len).await?)
-                        .map_err(|_| CstError::InvalidSnapshot(self.read_size))
-                        .unwrap();
-                    let uuid = self.read_integer().await? as u64;
-                    self.convert_stat().await?;
-                    return Ok(Some(SnapshotEntry::ReplicaAdd(
-                        add_time, nodid, alias, addr, uuid,
-                    )));
+                    let entry = self.read_replica_add().await?;
+                    self.stat = SnapshotLoadProgress::LegacySection;
+                    return Ok(Some(entry));
                 }
                 SnapshotLoadProgress::Replicas(false) => {
-                    let addr_len = self.read_integer().await? as usize;
-                    let addr = String::from_utf8(self.read_bytes(addr_len).await?)
-                        .map_err(|_| CstError::InvalidSnapshot(self.read_size))
-                        .unwrap();
-                    let t = self.read_integer().await? as u64;
-                    self.convert_stat().await?;
-                    return Ok(Some(SnapshotEntry::ReplicaDel(addr, t)));
+                    let entry = self.read_replica_del().await?;
+                    self.stat = SnapshotLoadProgress::LegacySection;
+                    return Ok(Some(entry));
                 }
+                SnapshotLoadProgress::LegacySection => self.convert_stat().await?,
                 SnapshotLoadProgress::Datas(size, current) => {
                     if *current < *size {
                         *current += 1;
-                        let (key, value) = self.read_entry().await?;
+                        let (key, value) = self.read_entry(None).await?;
                         return Ok(Some(SnapshotEntry::Data(key, value)));
                     } else {
                         self.convert_stat().await?;
@@ -205,9 +456,13 @@ where
                     }
                 }
                 SnapshotLoadProgress::Checksum => {
-                    let _checksum = self.read_integer().await?;
+                    // the checksum covers everything before it, and is written as 8 bytes in little endian
                     let checksum = self.checksum_writter.get();
-                    if (_checksum as u64) != checksum {
+                    let bytes = self.read_bytes(8).await?;
+                    let expected = u64::from_le_bytes([
+                        bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7],
+                    ]);
+                    if expected != checksum {
                         return Err(CstError::InvalidSnapshotChecksum);
                     }
                     self.stat = SnapshotLoadProgress::Finish;
@@ -219,18 +474,71 @@ where
         }
     }
 
+    // the format of the snapshot, known after the version is read
+    pub fn format(&self) -> u8 {
+        self.format
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    // the flag and the number of items of the section the latest entry belongs to
+    pub fn section(&self) -> (u8, usize) {
+        self.section
+    }
+
+    // the number of sections read, which tells whether the latest entry begins a new section
+    pub fn sections_read(&self) -> usize {
+        self.sections
+    }
+
+    fn enter_section(&mut self, flag: u8, count: usize) {
+        self.section = (flag, count);
+        self.sections += 1;
+    }
+
+    // read the header of the next section in the current format, the unknown ones are skipped item by item.
+    async fn next_section(&mut self) -> Result<(), CstError> {
+        let flag = self.read_byte().await?;
+        if flag == SNAPSHOT_FLAG_CHECKSUM {
+            self.stat = SnapshotLoadProgress::Checksum;
+            return Ok(());
+        }
+        let count = self.read_len().await?;
+        if !(SNAPSHOT_FLAG_NODE..=SNAPSHOT_FLAG_VERSIONS).contains(&flag) {
+            info!("Skipping the unknown section {} with {} items in the snapshot", flag, count);
+        }
+        self.enter_section(flag, count);
+        self.stat = SnapshotLoadProgress::Section(flag, count, 0);
+        Ok(())
+    }
+
+    // read the header of the next section in the legacy format, which are not skippable.
     async fn convert_stat(&mut self) -> Result<(), CstError> {
         self.stat = match self.read_byte().await? {
-            SNAPSHOT_FLAG_REPLICA_ADD => SnapshotLoadProgress::Replicas(true),
-            SNAPSHOT_FLAG_REPLICA_REM => SnapshotLoadProgress::Replicas(false),
+            SNAPSHOT_FLAG_REPLICA_ADD => {
+                self.enter_section(SNAPSHOT_FLAG_REPLICA_ADD, 1);
+                SnapshotLoadProgress::Replicas(true)
+            }
+            SNAPSHOT_FLAG_REPLICA_REM => {
+                self.enter_section(SNAPSHOT_FLAG_REPLICA_REM, 1);
+                SnapshotLoadProgress::Replicas(false)
+            }
             SNAPSHOT_FLAG_DATAS => {
-                SnapshotLoadProgress::Datas(self.read_integer().await? as usize, 0)
+                let size = self.read_len().await?;
+                self.enter_section(SNAPSHOT_FLAG_DATAS, size);
+                SnapshotLoadProgress::Datas(size, 0)
             }
             SNAPSHOT_FLAG_DELETES => {
-                SnapshotLoadProgress::Deletes(self.read_integer().await? as usize, 0)
+                let size = self.read_len().await?;
+                self.enter_section(SNAPSHOT_FLAG_DELETES, size);
+                SnapshotLoadProgress::Deletes(size, 0)
             }
             SNAPSHOT_FLAG_EXPIRES => {
-                SnapshotLoadProgress::Expires(self.read_integer().await? as usize, 0)
+                let size = self.read_len().await?;
+                self.enter_section(SNAPSHOT_FLAG_EXPIRES, size);
+                SnapshotLoadProgress::Expires(size, 0)
             }
             SNAPSHOT_FLAG_CHECKSUM => SnapshotLoadProgress::Checksum,
             _ => {
@@ -240,6 +548,34 @@ where
         Ok(())
     }
 
+    async fn read_string(&mut self) -> Result<String, CstError> {
+        let len = self.read_len().await?;
+        String::from_utf8(self.read_bytes(len).await?).map_err(|_| CstError::InvalidSnapshot(self.read_size))
+    }
+
+    async fn read_node(&mut self) -> Result<SnapshotEntry, CstError> {
+        let nodid = self.read_integer().await? as u64;
+        let alias = self.read_string().await?;
+        let addr = self.read_string().await?;
+        let uuid = self.read_integer().await? as u64;
+        Ok(SnapshotEntry::Node(nodid, alias, addr, uuid))
+    }
+
+    async fn read_replica_add(&mut self) -> Result<SnapshotEntry, CstError> {
+        let add_time = self.read_integer().await? as u64;
+        let nodid = self.read_integer().await? as u64;
+        let alias = self.read_string().await?;
+        let addr = self.read_string().await?;
+        let uuid = self.read_integer().await? as u64;
+        Ok(SnapshotEntry::ReplicaAdd(add_time, nodid, alias, addr, uuid))
+    }
+
+    async fn read_replica_del(&mut self) -> Result<SnapshotEntry, CstError> {
+        let addr = self.read_string().await?;
+        let t = self.read_integer().await? as u64;
+        Ok(SnapshotEntry::ReplicaDel(addr, t))
+    }
+
     #[inline]
     pub async fn read_integer(&mut self) -> Result<i64, CstError> {
         let flag = self.read_byte().await?;
@@ -263,32 +599,93 @@ where
         }
     }
 
+    // read a length or a count, which can't be negative, nor go beyond the item it's in
+    pub async fn read_len(&mut self) -> Result<usize, CstError> {
+        let len = self.read_integer().await?;
+        if len < 0 {
+            return Err(CstError::InvalidSnapshot(self.read_size));
+        }
+        let len = len as usize;
+        if let Some(end) = self.item_end {
+            if len > end.saturating_sub(self.read_size) {
+                return Err(CstError::InvalidSnapshot(self.read_size));
+            }
+        }
+        Ok(len)
+    }
+
     pub async fn read_bytes(&mut self, size: usize) -> Result<Vec<u8>, CstError> {
-        let mut datas = Vec::with_capacity(size);
-        unsafe {
-            datas.set_len(size);
+        if let Some(end) = self.item_end {
+            if size > end.saturating_sub(self.read_size) {
+                return Err(CstError::InvalidSnapshot(self.read_size));
+            }
+        }
+        // the size may be corrupted where it's not bounded by an item, like in the legacy format,
+        // so the buffer grows with the bytes really read instead of being allocated at once
+        let mut datas = Vec::with_capacity(std::cmp::min(size, SNAPSHOT_READ_CHUNK));
+        while datas.len() < size {
+            let filled = datas.len();
+            if self.block.is_none() {
+                datas.resize(filled + std::cmp::min(size - filled, SNAPSHOT_READ_CHUNK), 0);
+                self.io.read_exact(&mut datas[filled..]).await?;
+            } else {
+                let (block, pos) = self.block.as_mut().unwrap();
+                if *pos == block.len() {
+                    self.read_block().await?;
+                    continue;
+                }
+                let n = std::cmp::min(size - filled, block.len() - *pos);
+                datas.extend_from_slice(&block[*pos..*pos + n]);
+                *pos += n;
+            }
         }
-        self.io.read_exact(&mut datas).await?;
         self.checksum_writter.write(&datas)?;
+        self.read_size += size;
         Ok(datas)
     }
 
+    // read and decompress the next block
+    async fn read_block(&mut self) -> Result<(), CstError> {
+        let mut header = [0u8; COMPRESSION_BLOCK_HEADER_SIZE];
+        self.io.read_exact(&mut header).await?;
+        let compressed_size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let raw_size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        if raw_size == 0 || raw_size > COMPRESSION_BLOCK_SIZE || compressed_size > lz4_flex::block::get_maximum_output_size(raw_size) {
+            return Err(CstError::InvalidSnapshot(self.read_size));
+        }
+        let mut compressed = vec![0u8; compressed_size];
+        self.io.read_exact(&mut compressed).await?;
+        let raw = lz4_flex::block::decompress(&compressed, raw_size).map_err(|_| CstError::InvalidSnapshot(self.read_size))?;
+        if raw.len() != raw_size {
+            return Err(CstError::InvalidSnapshot(self.read_size));
+        }
+        self.block = Some((raw, 0));
+        Ok(())
+    }
+
     pub async fn read_byte(&mut self) -> Result<u8, CstError> {
         self.read_bytes(1).await.map(|x| x[0])
     }
 
-    pub async fn read_entry(&mut self) -> Result<(Bytes, Object), CstError> {
+    // the end of the item is given if the entry is one, the node ids may follow the object in it then.
+    // they are missing in the items written by the older versions, so the nodes are 0 in that case.
+    pub async fn read_entry(&mut self, end: Option<usize>) -> Result<(Bytes, Object), CstError> {
         let key = {
-            let s = self.read_integer().await? as usize;
+            let s = self.read_len().await?;
             self.read_bytes(s).await?.to_vec().into()
         };
-        let v = Object::load_snapshot(self).await?;
+        let mut v = Object::load_snapshot(self).await?;
+        if let Some(end) = end {
+            if self.read_size < end {
+                v.load_nodes(self).await?;
+            }
+        }
         Ok((key, v))
     }
 
     async fn read_key_int(&mut self) -> Result<(Bytes, u64), CstError> {
         let key = {
-            let s = self.read_integer().await? as usize;
+            let s = self.read_len().await?;
             self.read_bytes(s).await?.to_vec().into()
         };
         Ok((key, self.read_integer().await? as u64))
@@ -298,6 +695,14 @@ where
     pub fn total_read(&self) -> usize {
         self.read_size
     }
+
+    pub fn get_ref(&self) -> &T {
+        &self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -307,10 +712,27 @@ pub enum SnapshotEntry {
     ReplicaAdd(u64, u64, String, String, u64), // (add_time, node_id, node_alias, addr, uuid_he_sent)
     ReplicaDel(String, u64),                   // (addr, del_time)
     Data(Bytes, Object),
+    Shadow(Bytes, Object),
     Expires(Bytes, u64),
     Deletes(Bytes, u64),
+    Versions(u64, u64), // (node_id, uuid of his latest write we have)
 }
 
+// The version is made of 4 bytes, the second one is the format. Since format 2, every section is
+// a flag followed by the number of its items, and every item is prefixed with its length, so that
+// a loader can skip the sections and the trailing fields added later, in which case only the third
+// byte is increased. The format itself is increased only if the older loaders can't read it anymore.
+pub const SNAPSHOT_FORMAT_LEGACY: u8 = 1;
+pub const SNAPSHOT_FORMAT: u8 = 2;
+pub const SNAPSHOT_VERSION: [u8; 4] = [0, SNAPSHOT_FORMAT, 0, 0];
+// the same as format 2, except that everything following the header is compressed in blocks.
+// the header is followed by a byte telling how it's compressed.
+pub const SNAPSHOT_FORMAT_COMPRESSED: u8 = 3;
+const SNAPSHOT_VERSION_COMPRESSED: [u8; 4] = [0, SNAPSHOT_FORMAT_COMPRESSED, 0, 0];
+// the compressions we are able to decompress, which are told to our replicas in SYNC
+pub const SNAPSHOT_COMPRESSIONS: &str = "lz4";
+const SNAPSHOT_VERSION_LEGACY: [u8; 4] = [0, SNAPSHOT_FORMAT_LEGACY, 1, 1];
+
 pub const SNAPSHOT_FLAG_NODE: u8 = 2;
 pub const SNAPSHOT_FLAG_REPLICA_ADD: u8 = 3;
 pub const SNAPSHOT_FLAG_REPLICA_REM: u8 = 4;
@@ -318,6 +740,10 @@ pub const SNAPSHOT_FLAG_DATAS: u8 = 5;
 pub const SNAPSHOT_FLAG_EXPIRES: u8 = 6;
 pub const SNAPSHOT_FLAG_DELETES: u8 = 7;
 pub const SNAPSHOT_FLAG_CHECKSUM: u8 = 8;
+// the objects of the keys which are of other types than those in the data section
+pub const SNAPSHOT_FLAG_SHADOWS: u8 = 9;
+// where we are with the writes of every node, so that the relays still follow them after a restart
+pub const SNAPSHOT_FLAG_VERSIONS: u8 = 10;
 
 #[derive(Debug, Copy, Clone)]
 enum SnapshotLoadProgress {
@@ -325,56 +751,166 @@ enum SnapshotLoadProgress {
     Version,
     Node,
     Replicas(bool),
+    LegacySection,
     Datas(usize, usize),
     Expires(usize, usize),
     Deletes(usize, usize),
+    Section(u8, usize, usize),
     Checksum,
     Finish,
 }
 
+// rewrite the snapshot src into dst in the given format and compression, so that we can upgrade the
+// snapshots dumped by the older versions, and send the replicas running an older version snapshots
+// they can read. the sections unknown to us are dropped, and the legacy format is never compressed.
+pub async fn convert_snapshot(src: &str, dst: &str, format: u8, compression: Compression) -> Result<(), CstError> {
+    if format != SNAPSHOT_FORMAT && format != SNAPSHOT_FORMAT_LEGACY {
+        return Err(CstError::IncompatibleSnapshot(format.to_string()));
+    }
+    let f = tokio::fs::File::open(src).await?;
+    let mut loader = SnapshotLoader::new(tokio::io::BufReader::new(f));
+    // the entries are serialized into memory, and written into the file by chunks without blocking the thread
+    let mut out = tokio::fs::OpenOptions::new().create(true).write(true).truncate(true).open(dst).await?;
+    let compression = if format == SNAPSHOT_FORMAT_LEGACY { Compression::None } else { compression };
+    let mut w = SnapshotWriter::new(64 * 1024, Vec::with_capacity(128 * 1024)).with_compression(compression);
+    if format == SNAPSHOT_FORMAT {
+        w.write_head()?;
+    } else {
+        w.write_bytes(b"CONSTDB")?.write_bytes(SNAPSHOT_VERSION_LEGACY.as_ref())?;
+    }
+    let mut sections = 0;
+    while let Some(entry) = loader.next().await? {
+        if w.get_mut().len() >= 64 * 1024 {
+            out.write_all(&std::mem::take(w.get_mut())).await?;
+        }
+        if let SnapshotEntry::Version(_) = entry {
+            continue;
+        }
+        let (flag, count) = loader.section();
+        let new_section = loader.sections_read() != sections;
+        sections = loader.sections_read();
+        if format == SNAPSHOT_FORMAT {
+            if new_section {
+                w.write_section(flag, count)?;
+            }
+            w.write_item(|w| write_snapshot_entry(w, &entry))?;
+            continue;
+        }
+        // the legacy format writes the node without a flag, and every replica with a flag of its own.
+        // it has no shadows or versions, the older versions don't know them.
+        match entry {
+            SnapshotEntry::Shadow(..) | SnapshotEntry::Versions(..) => continue,
+            SnapshotEntry::Node(..) => {}
+            SnapshotEntry::ReplicaAdd(..) | SnapshotEntry::ReplicaDel(..) => {
+                w.write_byte(flag)?;
+            }
+            _ => {
+                if new_section {
+                    w.write_byte(flag)?.write_integer(count as i64)?;
+                }
+            }
+        }
+        match &entry {
+            // the legacy format has no items, in which the node ids can be skipped
+            SnapshotEntry::Data(k, v) => w.write_legacy_entry(k.as_bytes(), v)?,
+            _ => write_snapshot_entry(&mut w, &entry)?,
+        }
+    }
+    w.write_checksum()?;
+    w.flush()?;
+    out.write_all(w.get_mut()).await?;
+    out.sync_all().await?;
+    Ok(())
+}
+
+// the format and the compression of a snapshot file
+pub async fn snapshot_encoding(file_name: &str) -> Result<(u8, Compression), CstError> {
+    let f = tokio::fs::File::open(file_name).await?;
+    let mut loader = SnapshotLoader::new(f);
+    loader.next().await?;
+    Ok((loader.format(), loader.compression()))
+}
+
+// the fields of an entry, which are the same in all the formats
+fn write_snapshot_entry<W: Write>(w: &mut SnapshotWriter<W>, entry: &SnapshotEntry) -> Result<(), CstError> {
+    match entry {
+        SnapshotEntry::Version(_) => {}
+        SnapshotEntry::Node(node_id, alias, addr, uuid) => {
+            let _ = w.write_integer(*node_id as i64)?
+                .write_integer(alias.len() as i64)?
+                .write_bytes(alias.as_bytes())?
+                .write_integer(addr.len() as i64)?
+                .write_bytes(addr.as_bytes())?
+                .write_integer(*uuid as i64)?;
+        }
+        SnapshotEntry::ReplicaAdd(add_time, node_id, alias, addr, uuid) => {
+            let _ = w.write_integer(*add_time as i64)?
+                .write_integer(*node_id as i64)?
+                .write_integer(alias.len() as i64)?
+                .write_bytes(alias.as_bytes())?
+                .write_integer(addr.len() as i64)?
+                .write_bytes(addr.as_bytes())?
+                .write_integer(*uuid as i64)?;
+        }
+        SnapshotEntry::ReplicaDel(addr, t) => {
+            let _ = w.write_integer(addr.len() as i64)?
+                .write_bytes(addr.as_bytes())?
+                .write_integer(*t as i64)?;
+        }
+        SnapshotEntry::Data(k, v) | SnapshotEntry::Shadow(k, v) => w.write_entry(k.as_bytes(), v)?,
+        SnapshotEntry::Expires(k, t) | SnapshotEntry::Deletes(k, t) => {
+            let _ = w.write_integer(k.len() as i64)?
+                .write_bytes(k.as_bytes())?
+                .write_integer(*t as i64)?;
+        }
+        SnapshotEntry::Versions(node_id, uuid) => {
+            let _ = w.write_integer(*node_id as i64)?.write_integer(*uuid as i64)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
-    use bytes::buf::Writer;
-    use bytes::BufMut;
-    use tokio::fs::OpenOptions;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::macros::support::thread_rng_n;
-
-    use crate::snapshot::{SnapshotLoader, SnapshotWriter};
+    use crate::{Bytes, CstError};
+    use crate::crdt::timestamp::Timestamp;
+    use crate::object::{Encoding, Object};
+    use crate::snapshot::{convert_snapshot, Compression, SnapshotEntry, SnapshotLoader, SnapshotWriter, SNAPSHOT_FLAG_DATAS, SNAPSHOT_FLAG_NODE, SNAPSHOT_FLAG_REPLICA_ADD, SNAPSHOT_FORMAT, SNAPSHOT_FORMAT_COMPRESSED, SNAPSHOT_FORMAT_LEGACY};
 
     #[test]
     fn test_snapshot() {
-        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
             test_snapshot_bytes().await;
         });
     }
 
     async fn test_snapshot_bytes() {
+        let file_name = std::env::temp_dir().join(format!("constdb_snapshot_bytes_test_{}", std::process::id()));
         {
             let f = std::fs::OpenOptions::new()
                 .create(true)
                 .read(true)
                 .write(true)
                 .truncate(true)
-                .open("test_spapshot_bytes")
+                .open(&file_name)
                 .unwrap();
             let mut w = SnapshotWriter::new(2048, f);
-            w.write_bytes(b"CONST");
-            w.write_bytes(b"DB");
-            w.write_integer(1);
-            w.write_integer(2);
-            w.write_integer(1 << 13);
-            w.write_integer(1 << 20);
-            w.write_integer(1 << 26);
-            w.write_integer(1 << 30);
-            w.write_integer(1 << 31);
+            w.write_bytes(b"CONST").unwrap();
+            w.write_bytes(b"DB").unwrap();
+            w.write_integer(1).unwrap();
+            w.write_integer(2).unwrap();
+            w.write_integer(1 << 13).unwrap();
+            w.write_integer(1 << 20).unwrap();
+            w.write_integer(1 << 26).unwrap();
+            w.write_integer(1 << 30).unwrap();
+            w.write_integer(1 << 31).unwrap();
             assert_eq!(9519382692141102896, w.checksum());
         }
         {
             let f = tokio::fs::OpenOptions::new()
                 .read(true)
-                .open("test_spapshot_bytes")
+                .open(&file_name)
                 .await
                 .unwrap();
             let mut r = SnapshotLoader::new(f);
@@ -388,5 +924,137 @@ mod test {
             assert_eq!(r.read_integer().await.unwrap(), 1 << 30);
             assert_eq!(r.read_integer().await.unwrap(), 1 << 31);
         }
+        let _ = std::fs::remove_file(&file_name);
+    }
+
+    async fn load_entries(file_name: &str) -> (u8, Vec<String>) {
+        let f = tokio::fs::File::open(file_name).await.unwrap();
+        let mut r = SnapshotLoader::new(f);
+        let mut entries = vec![];
+        while let Some(e) = r.next().await.unwrap() {
+            if let SnapshotEntry::Version(_) = e {
+                continue;
+            }
+            entries.push(format!("{:?}", e));
+        }
+        (r.format(), entries)
+    }
+
+    #[test]
+    fn test_snapshot_format() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            {
+                let f = std::fs::File::create("test_snapshot_format").unwrap();
+                let mut w = SnapshotWriter::new(1024, f);
+                w.write_head().unwrap().write_section(SNAPSHOT_FLAG_NODE, 1).unwrap();
+                w.write_item(|w| {
+                    let _ = w.write_integer(1)?.write_integer(1)?.write_bytes(b"a")?.write_integer(3)?.write_bytes(b"a:1")?.write_integer(100)?;
+                    // a field appended by a newer version
+                    let _ = w.write_integer(12345)?;
+                    Ok(())
+                }).unwrap();
+                // a section added by a newer version
+                w.write_section(100, 2).unwrap();
+                w.write_item(|w| { let _ = w.write_bytes(b"unknown")?; Ok(()) }).unwrap();
+                w.write_item(|w| { let _ = w.write_integer(7)?; Ok(()) }).unwrap();
+                w.write_section(SNAPSHOT_FLAG_REPLICA_ADD, 1).unwrap();
+                w.write_item(|w| {
+                    let _ = w.write_integer(5)?.write_integer(2)?.write_integer(1)?.write_bytes(b"b")?.write_integer(3)?.write_bytes(b"b:2")?.write_integer(200)?;
+                    Ok(())
+                }).unwrap();
+                w.write_section(SNAPSHOT_FLAG_DATAS, 2).unwrap();
+                for (k, v) in [("k1", "v1"), ("k2", "v2")].iter() {
+                    // no node ids, which are lost in the legacy format
+                    let o = Object::new(Encoding::Bytes(Bytes::from(*v)), Timestamp::new(10, 0), Timestamp::default());
+                    w.write_item(|w| w.write_entry(k.as_bytes(), &o)).unwrap();
+                }
+                w.write_checksum().unwrap();
+                w.flush().unwrap();
+            }
+            let (format, entries) = load_entries("test_snapshot_format").await;
+            assert_eq!(format, SNAPSHOT_FORMAT);
+            assert_eq!(entries.len(), 4);
+            assert!(entries[0].starts_with("Node(1, \"a\", \"a:1\", 100)"));
+            assert!(entries[1].starts_with("ReplicaAdd(5, 2, \"b\", \"b:2\", 200)"));
+
+            // downgraded for the older versions, and upgraded back without losing anything we know
+            convert_snapshot("test_snapshot_format", "test_snapshot_format_legacy", SNAPSHOT_FORMAT_LEGACY, Compression::None).await.unwrap();
+            assert_eq!(load_entries("test_snapshot_format_legacy").await, (SNAPSHOT_FORMAT_LEGACY, entries.clone()));
+            convert_snapshot("test_snapshot_format_legacy", "test_snapshot_format", SNAPSHOT_FORMAT, Compression::Lz4).await.unwrap();
+            assert_eq!(load_entries("test_snapshot_format").await, (SNAPSHOT_FORMAT_COMPRESSED, entries));
+
+            // a format we don't know
+            std::fs::write("test_snapshot_format", b"CONSTDB\x00\x04\x00\x00").unwrap();
+            let f = tokio::fs::File::open("test_snapshot_format").await.unwrap();
+            assert!(SnapshotLoader::new(f).next().await.is_err());
+            let _ = std::fs::remove_file("test_snapshot_format");
+            let _ = std::fs::remove_file("test_snapshot_format_legacy");
+        });
+    }
+
+    #[test]
+    fn test_snapshot_compression() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let big = "x".repeat(600 * 1024);
+            {
+                let f = std::fs::File::create("test_snapshot_compression").unwrap();
+                let mut w = SnapshotWriter::new(1024, f).with_compression(Compression::Lz4);
+                w.write_head().unwrap().write_section(SNAPSHOT_FLAG_DATAS, 1001).unwrap();
+                for i in 0..1000 {
+                    let o = Object::new(Encoding::Bytes(Bytes::from(format!("value_{}", i).as_str())), Timestamp::new(i, 1), Timestamp::default());
+                    w.write_item(|w| w.write_entry(format!("key_{}", i).as_bytes(), &o)).unwrap();
+                }
+                // a value spanning several blocks
+                let o = Object::new(Encoding::Bytes(Bytes::from(big.as_str())), Timestamp::new(1, 1), Timestamp::default());
+                w.write_item(|w| w.write_entry(b"big", &o)).unwrap();
+                w.write_checksum().unwrap();
+                w.flush().unwrap();
+            }
+            assert!(std::fs::metadata("test_snapshot_compression").unwrap().len() < 100 * 1024);
+            let f = tokio::fs::File::open("test_snapshot_compression").await.unwrap();
+            let mut r = SnapshotLoader::new(f);
+            let mut keys = 0;
+            while let Some(e) = r.next().await.unwrap() {
+                if let SnapshotEntry::Data(k, v) = e {
+                    keys += 1;
+                    if k.as_bytes() == b"big" {
+                        assert_eq!(format!("{:?}", v.enc), format!("{:?}", Encoding::Bytes(Bytes::from(big.as_str()))));
+                    }
+                }
+            }
+            assert_eq!(keys, 1001);
+            assert_eq!(r.compression(), Compression::Lz4);
+
+            // decompressed for the replicas unable to load it
+            convert_snapshot("test_snapshot_compression", "test_snapshot_decompressed", SNAPSHOT_FORMAT, Compression::None).await.unwrap();
+            let (format, entries) = load_entries("test_snapshot_decompressed").await;
+            assert_eq!(format, SNAPSHOT_FORMAT);
+            assert_eq!(entries.len(), 1001);
+            let _ = std::fs::remove_file("test_snapshot_compression");
+            let _ = std::fs::remove_file("test_snapshot_decompressed");
+        });
+    }
+
+    #[test]
+    fn test_snapshot_corrupted() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            for len in [-1i64, 1 << 40, 100].iter() {
+                let mut w = SnapshotWriter::new(1024, vec![]);
+                w.write_head().unwrap().write_section(SNAPSHOT_FLAG_DATAS, 1).unwrap();
+                w.write_item(|w| { let _ = w.write_integer(*len)?.write_bytes(b"key")?; Ok(()) }).unwrap();
+                w.write_checksum().unwrap();
+                w.flush().unwrap();
+                let bytes = w.get_mut().clone();
+                let mut r = SnapshotLoader::new(bytes.as_slice());
+                assert!(matches!(r.next().await, Ok(Some(SnapshotEntry::Version(_)))));
+                assert!(matches!(r.next().await, Err(CstError::InvalidSnapshot(_))));
+            }
+            // the lengths not bounded by an item only fail at the end of the file
+            let mut r = SnapshotLoader::new(b"CONSTDB".as_ref());
+            assert!(r.read_bytes(1 << 40).await.is_err());
+        });
     }
 }
@@ -5,13 +5,14 @@ use crate::CstError;
 use crate::cmd::NextArg;
 use failure::_core::fmt::{Display, Formatter};
 use crate::server::Server;
+use crate::replica::replica::{SnapshotLoading, capabilities_names};
 use std::sync::atomic::{AtomicU64};
 use failure::_core::sync::atomic::{Ordering, AtomicUsize};
 use crate::conf::{GLOBAL_CONF, CONF_PATH};
 use sysinfo::{SystemExt, ProcessExt};
 
 lazy_static!{
-    static ref GLOBAL_METRICS: RwLock<Metrics> = RwLock::new(Metrics::default());
+    static ref GLOBAL_METRICS: RwLock<Metrics> = RwLock::new(Metrics{server: ServerBasic::load(), ..Default::default()});
     pub static ref CURRENT_MEMORY: AtomicUsize = AtomicUsize::new(0);
     pub static ref CURRENT_CLIENTS: AtomicU64 = AtomicU64::new(0);
     pub static ref TOTAL_NETWORK_INPUT_BYTES: AtomicU64 = AtomicU64::new(0);
@@ -24,6 +25,7 @@ pub struct Metrics {
     server: ServerBasic,
     clients: Clients,
     memory: Memory,
+    persistence: Persistence,
     stats: Stats,
     replication: Replication,
     cpu: CPU,
@@ -35,6 +37,26 @@ impl Metrics {
         self.stats.total_commands_processed += 1;
     }
 
+    pub fn incr_sync_full(&mut self) {
+        self.stats.sync_full += 1;
+    }
+
+    pub fn incr_sync_delta(&mut self) {
+        self.stats.sync_delta += 1;
+    }
+
+    pub fn incr_relayed_commands(&mut self) {
+        self.stats.relayed_commands += 1;
+    }
+
+    pub fn incr_repaired_keys(&mut self) {
+        self.stats.repaired_keys += 1;
+    }
+
+    pub fn incr_skipped_commands(&mut self) {
+        self.stats.skipped_commands += 1;
+    }
+
     pub fn add_connections_received(&mut self) {
         self.stats.total_connections_received += 1;
     }
@@ -56,6 +78,8 @@ impl Display for Metrics {
         f.write_str("\n")?;
         self.memory.fmt(f)?;
         f.write_str("\n")?;
+        self.persistence.fmt(f)?;
+        f.write_str("\n")?;
         self.stats.fmt(f)?;
         f.write_str("\n")?;
         self.replication.fmt(f)?;
@@ -82,9 +106,22 @@ fn refresh_metrics(server: &mut Server) {
     g.stats.total_commands_processed += server.metrics.stats.total_commands_processed;
     server.metrics.stats.total_commands_processed = 0;
     g.stats.total_connections_received = conns_rcvd;
+    g.stats.type_conflicts = server.db.type_conflicts;
+    g.stats.sync_full += server.metrics.stats.sync_full;
+    server.metrics.stats.sync_full = 0;
+    g.stats.sync_delta += server.metrics.stats.sync_delta;
+    server.metrics.stats.sync_delta = 0;
+    g.stats.relayed_commands += server.metrics.stats.relayed_commands;
+    server.metrics.stats.relayed_commands = 0;
+    g.stats.repaired_keys += server.metrics.stats.repaired_keys;
+    server.metrics.stats.repaired_keys = 0;
+    g.stats.skipped_commands += server.metrics.stats.skipped_commands;
+    server.metrics.stats.skipped_commands = 0;
+    g.persistence.refresh(server);
+    g.replication.refresh(server);
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 struct ServerBasic {
     version: String,
     executable: String,
@@ -94,8 +131,10 @@ struct ServerBasic {
     os: String,
 }
 
-impl Default for ServerBasic {
-    fn default() -> Self {
+impl ServerBasic {
+    // only the global metrics hold the basic infos, so the per-server metrics
+    // don't need the config to be loaded.
+    fn load() -> Self {
         let conf = &GLOBAL_CONF;
         let s = sysinfo::System::new();
         let mut server = ServerBasic{
@@ -176,6 +215,70 @@ impl Display for Memory {
     }
 }
 
+#[derive(Default, Debug, Clone)]
+struct Persistence {
+    changes_since_last_save: u64,
+    bgsave_in_progress: bool,
+    last_save_time: u64,
+    last_bgsave_status: bool,
+    last_bgsave_time_sec: i64,
+    current_bgsave_time_sec: i64,
+    aof_enabled: bool,
+    aof_current_size: u64,
+    rdb_import_in_progress: bool,
+    rdb_import_keys: u64,
+    rdb_import_skipped: u64,
+    rdb_import_failed: u64,
+    last_rdb_import_status: &'static str,
+}
+
+impl Persistence {
+    fn refresh(&mut self, server: &Server) {
+        self.changes_since_last_save = server.dirty;
+        self.bgsave_in_progress = server.bgsave_in_progress();
+        self.last_save_time = server.latest_dump_time;
+        self.last_bgsave_status = server.latest_bgsave_ok;
+        self.last_bgsave_time_sec = server.latest_bgsave_time_sec;
+        self.aof_enabled = server.aof.is_some();
+        self.aof_current_size = server.aof.as_ref().map(|x| x.size()).unwrap_or_default();
+        self.rdb_import_in_progress = server.rdb_import.is_some();
+        let (st, status) = match (&server.rdb_import, &server.latest_rdb_import) {
+            (Some(i), _) => (i.stats, "in_progress"),
+            (None, Some((st, true))) => (*st, "ok"),
+            (None, Some((st, false))) => (*st, "err"),
+            (None, None) => (Default::default(), "none"),
+        };
+        self.rdb_import_keys = st.imported;
+        self.rdb_import_skipped = st.skipped + st.expired;
+        self.rdb_import_failed = st.failed;
+        self.last_rdb_import_status = status;
+        self.current_bgsave_time_sec = if self.bgsave_in_progress {
+            (chrono::Local::now().timestamp() as u64).saturating_sub(server.bgsave_start_time) as i64
+        } else {
+            -1
+        };
+    }
+}
+
+impl Display for Persistence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("# Persistence\n")?;
+        f.write_fmt(format_args!("changes_since_last_save:{}\n", self.changes_since_last_save))?;
+        f.write_fmt(format_args!("bgsave_in_progress:{}\n", self.bgsave_in_progress as u8))?;
+        f.write_fmt(format_args!("last_save_time:{}\n", self.last_save_time))?;
+        f.write_fmt(format_args!("last_bgsave_status:{}\n", if self.last_bgsave_status { "ok" } else { "err" }))?;
+        f.write_fmt(format_args!("last_bgsave_time_sec:{}\n", self.last_bgsave_time_sec))?;
+        f.write_fmt(format_args!("current_bgsave_time_sec:{}\n", self.current_bgsave_time_sec))?;
+        f.write_fmt(format_args!("aof_enabled:{}\n", self.aof_enabled as u8))?;
+        f.write_fmt(format_args!("aof_current_size:{}\n", self.aof_current_size))?;
+        f.write_fmt(format_args!("rdb_import_in_progress:{}\n", self.rdb_import_in_progress as u8))?;
+        f.write_fmt(format_args!("rdb_import_keys:{}\n", self.rdb_import_keys))?;
+        f.write_fmt(format_args!("rdb_import_skipped:{}\n", self.rdb_import_skipped))?;
+        f.write_fmt(format_args!("rdb_import_failed:{}\n", self.rdb_import_failed))?;
+        f.write_fmt(format_args!("last_rdb_import_status:{}\n", self.last_rdb_import_status))
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct Stats {
     total_connections_received: u64,
@@ -184,6 +287,17 @@ pub struct Stats {
     total_net_input_bytes: u64,
     total_net_output_bytes: u64,
     expired_keys: u64,
+    // the writes and merges of keys of other types somewhere else
+    type_conflicts: u64,
+    // how many times the replicas are sent a full snapshot, or only what's written since their positions
+    sync_full: u64,
+    sync_delta: u64,
+    // the writes of other nodes relayed to us by our replicas
+    relayed_commands: u64,
+    // the keys changed by the anti-entropy with our replicas, which shows they have diverged
+    repaired_keys: u64,
+    // the commands of our replicas we don't know, which are likely of a newer version and skipped
+    skipped_commands: u64,
 }
 
 impl Display for Stats {
@@ -194,7 +308,13 @@ impl Display for Stats {
         f.write_fmt(format_args!("instantaneous_ops_per_sec:{}\n", self.instantaneous_ops_per_sec))?;
         f.write_fmt(format_args!("total_net_input_bytes:{}\n", self.total_net_input_bytes))?;
         f.write_fmt(format_args!("total_net_output_bytes:{}\n", self.total_net_output_bytes))?;
-        f.write_fmt(format_args!("expired_keys:{}\n", self.expired_keys))
+        f.write_fmt(format_args!("expired_keys:{}\n", self.expired_keys))?;
+        f.write_fmt(format_args!("type_conflicts:{}\n", self.type_conflicts))?;
+        f.write_fmt(format_args!("sync_full:{}\n", self.sync_full))?;
+        f.write_fmt(format_args!("sync_delta:{}\n", self.sync_delta))?;
+        f.write_fmt(format_args!("relayed_commands:{}\n", self.relayed_commands))?;
+        f.write_fmt(format_args!("repaired_keys:{}\n", self.repaired_keys))?;
+        f.write_fmt(format_args!("skipped_commands:{}\n", self.skipped_commands))
     }
 }
 
@@ -205,6 +325,39 @@ pub struct Replication {
     repl_backlog_size: usize,
     repl_backlog_first_uuid: u64,
     repl_backlog_hislen: usize,
+    // whether we're loading the snapshot of any replica
+    loading: bool,
+    replicas: Vec<ReplicaInfo>,
+}
+
+#[derive(Debug, Clone)]
+struct ReplicaInfo {
+    addr: String,
+    id: u64,
+    alias: String,
+    uuid_he_sent: u64,
+    protocol_version: u64,
+    capabilities: u64,
+    clock_skew_ms: Option<i64>,
+    loading: Option<SnapshotLoading>,
+}
+
+impl Replication {
+    fn refresh(&mut self, server: &Server) {
+        let metas = server.replicas.metas();
+        self.connected_replicas = metas.len() as u32;
+        self.loading = metas.iter().any(|m| m.loading.is_some());
+        self.replicas = metas.into_iter().map(|m| ReplicaInfo{
+            addr: m.he.addr.clone(),
+            id: m.he.id,
+            alias: m.he.alias.clone(),
+            uuid_he_sent: m.uuid_he_sent,
+            protocol_version: m.protocol_version,
+            capabilities: m.capabilities,
+            clock_skew_ms: m.clock_skew_ms,
+            loading: m.loading.clone(),
+        }).collect();
+    }
 }
 
 impl Display for Replication {
@@ -214,7 +367,20 @@ impl Display for Replication {
         f.write_fmt(format_args!("repl_backlog_active:{}\n", self.repl_backlog_active))?;
         f.write_fmt(format_args!("repl_backlog_size:{}\n", self.repl_backlog_size))?;
         f.write_fmt(format_args!("repl_backlog_first_uuid:{}\n", self.repl_backlog_first_uuid))?;
-        f.write_fmt(format_args!("repl_backlog_hislen:{}\n", self.repl_backlog_hislen))
+        f.write_fmt(format_args!("repl_backlog_hislen:{}\n", self.repl_backlog_hislen))?;
+        f.write_fmt(format_args!("loading:{}\n", self.loading as u8))?;
+        for (i, r) in self.replicas.iter().enumerate() {
+            f.write_fmt(format_args!("replica{}:addr={},id={},alias={},uuid_he_sent={}", i, r.addr, r.id, r.alias, r.uuid_he_sent))?;
+            f.write_fmt(format_args!(",protocol_version={},capabilities={}", r.protocol_version, capabilities_names(r.capabilities)))?;
+            if let Some(skew) = r.clock_skew_ms {
+                f.write_fmt(format_args!(",clock_skew_ms={}", skew))?;
+            }
+            if let Some(l) = &r.loading {
+                f.write_fmt(format_args!(",loading_size={},loading_received={},loading_merged={},loading_pending={}", l.size, l.received, l.merged, l.pending))?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
     }
 }
 
@@ -292,6 +458,7 @@ pub fn info_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
         Ok(part) => match part.to_ascii_lowercase().as_str() {
             "server" => format!("{}", m.server),
             "memory" => format!("{}", m.memory),
+            "persistence" => format!("{}", m.persistence),
             "clients" => format!("{}", m.clients),
             "stats" => format!("{}", m.stats),
             "replication" => format!("{}", m.replication),
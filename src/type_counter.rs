@@ -7,7 +7,8 @@ use bitflags::_core::cmp::max;
 use crate::CstError;
 use crate::cmd::NextArg;
 use crate::link::Client;
-use crate::object::{Encoding, Object};
+use crate::object::Encoding;
+use crate::crdt::timestamp::Timestamp;
 use crate::resp::Message;
 use crate::server::Server;
 use crate::snapshot::{SnapshotLoader, SnapshotWriter};
@@ -43,6 +44,7 @@ impl Counter {
             Some((v, t)) => {
                 if *t < uuid {
                     *v += value;
+                    *t = uuid;
                     self.sum += value;
                 }
             }
@@ -56,36 +58,34 @@ impl Counter {
         }
     }
 
+    // the value of a node is of its latest change in either one, or the greater one if they're of the same time
     pub fn merge(&mut self, other: Counter) {
-        for (nodeid, (v, t)) in self.data.iter_mut() {
-            match other.data.get(nodeid) {
-                Some((vv, tt)) => {
-                    if *tt > *t {
-                        *v = *vv;
-                    } else if *tt == *t {
-                        *v = max(*v, *vv);
-                    }
-                },
-                None => {}
-            }
-        }
-        for (nodeid, (vv, tt)) in other.data.iter() {
-            match self.data.get_mut(nodeid) {
+        for (nodeid, (vv, tt)) in other.data {
+            match self.data.get_mut(&nodeid) {
                 Some((v, t)) => {
-                    if *tt > *t {
-                        *v = *vv;
-                    } else if *tt == *t {
-                        *v = max(*v, *vv);
+                    if (tt, vv) > (*t, *v) {
+                        *v = vv;
+                        *t = tt;
                     }
                 },
                 None => {
-                    self.data.insert(*nodeid, (*vv, *tt));
+                    self.data.insert(nodeid, (vv, tt));
                 },
             }
         }
         self.cal_sum();
     }
 
+    // the values of the nodes changed after the uuid
+    pub fn delta_since(&self, uuid: u64) -> Counter {
+        let mut c = Counter{
+            sum: 0,
+            data: self.data.iter().filter(|(_, (_, t))| *t > uuid).map(|(n, v)| (*n, *v)).collect(),
+        };
+        c.cal_sum();
+        c
+    }
+
     fn cal_sum(&mut self) {
         self.sum = self.data.iter().map(|(_, (v, _))| *v).sum();
     }
@@ -98,6 +98,16 @@ impl Counter {
         Message::Array(data)
     }
 
+    // the sum and the values of every node, sorted by node id
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut nodes: Vec<(&u64, &(i64, u64))> = self.data.iter().collect();
+        nodes.sort();
+        let nodes: Vec<serde_json::Value> = nodes.into_iter()
+            .map(|(n, (v, t))| serde_json::json!({"node": n, "value": v, "uuid": t}))
+            .collect();
+        serde_json::json!({"sum": self.sum, "nodes": nodes})
+    }
+
     pub fn save_snapshot<W: Write>(&self, dst: &mut SnapshotWriter<W>) -> Result<(), CstError> {
         dst.write_integer(self.data.len() as i64)?;
         for (nodeid, (v, t)) in self.data.iter() {
@@ -109,8 +119,9 @@ impl Counter {
     }
 
     pub async fn load_snapshot<T: AsyncRead + Unpin>(src: &mut SnapshotLoader<T>) -> Result<Self, CstError> {
-        let cnt = src.read_integer().await? as usize;
-        let mut data = VClock::with_capacity(cnt);
+        let cnt = src.read_len().await?;
+        // the count is not trusted before the entries are really read
+        let mut data = VClock::with_capacity(std::cmp::min(cnt, 64));
         let mut total = 0;
         for _ in 0..cnt {
             let n = src.read_integer().await? as u64;
@@ -139,67 +150,83 @@ impl<'a> Iterator for CounterIter<'a> {
 }
 
 
-pub fn delcnt_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+// the deletion of the older versions, which take the values back in the slots of their nodes.
+// it's still applied so when received from them, or replayed from what they logged.
+pub fn delcnt_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Counter::default()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Counter::default()), client.is_none())?;
     match &mut o.enc {
         Encoding::Counter(c) => {
-            // let cnt = args.next_i64()?;
-            // *i += cnt;
-            o.update_time = max(o.update_time, uuid);
-            o.delete_time = max(o.delete_time, uuid);
-            while let Ok(nodeid) = args.next_u64() {
+            o.update_time = max(o.update_time, t);
+            o.delete_time = max(o.delete_time, t);
+            while let Ok(n) = args.next_u64() {
                 let v = args.next_i64()?;
-                c.change(nodeid, v, uuid);
+                c.change(n, v, uuid);
             }
             Ok(Message::None)
         }
-        _ => return Err(CstError::InvalidType),
+        _ => Err(CstError::InvalidType),
     }
 }
 
-pub fn incr_command(server: &mut Server, _client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn delcounter_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
-    //let o = server.db.query_or_insert(key_name, uuid)
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Counter::default()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Counter::default()), client.is_none())?;
+    match &mut o.enc {
+        Encoding::Counter(c) => {
+            o.update_time = max(o.update_time, t);
+            o.delete_time = max(o.delete_time, t);
+            // the values of the nodes seen by the deleting one are taken back by itself, rather than changed in
+            // their places, where its uuid may hide their later changes it hasn't seen.
+            let mut total = 0;
+            while args.next_u64().is_ok() {
+                total += args.next_i64()?;
+            }
+            c.change(nodeid, total, uuid);
+            Ok(Message::None)
         }
-        Some(o) => o,
-    };
+        _ => Err(CstError::InvalidType),
+    }
+}
+
+pub fn incr_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
+    let mut args = args.into_iter();
+    let key_name = args.next_bytes()?;
+    //let o = server.db.query_or_insert(key_name, uuid)
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Counter::default()), client.is_none())?;
     //let mut db = HashMap::new();
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::from(Counter::default()), uuid, 0).into());
     let c = o.enc.as_mut_counter()?;
     let v = c.change(nodeid, 1, uuid);
-    o.updated_at(uuid);
+    o.updated_at(t);
     Ok(Message::Integer(v))
 }
 
-pub fn decr_command(server: &mut Server, _client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn decr_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::from(Counter::default()), uuid, 0).into());
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Counter::default()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Counter::default()), client.is_none())?;
     let c = o.enc.as_mut_counter()?;
     let v = c.change(nodeid, -1, uuid);
-    o.updated_at(uuid);
+    o.updated_at(t);
     Ok(Message::Integer(v))
-}
\ No newline at end of file
+}
+
+pub fn incrby_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
+    let mut args = args.into_iter();
+    let key_name = args.next_bytes()?;
+    let delta = args.next_i64()?;
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Counter::default()), client.is_none())?;
+    let c = o.enc.as_mut_counter()?;
+    let v = c.change(nodeid, delta, uuid);
+    o.updated_at(t);
+    Ok(Message::Integer(v))
+}
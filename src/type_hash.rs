@@ -1,14 +1,16 @@
 use std::cmp::max;
 
-use crate::{Bytes, CstError};
+use crate::CstError;
 use crate::cmd::NextArg;
 use crate::link::Client;
-use crate::object::{Encoding, Object};
+use crate::object::Encoding;
 use crate::resp::Message;
 use crate::server::Server;
 use crate::crdt::lwwhash::Dict;
+use crate::crdt::timestamp::Timestamp;
 
-pub fn hset_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn hset_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     let kvs = {
@@ -18,34 +20,28 @@ pub fn hset_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
         }
         kvs
     };
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Dict::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Dict::empty()), client.is_none())?;
 
     let mut cnt = 0;
     let d = o.enc.as_mut_dict()?;
     for (k, v) in kvs.iter() {
-        if d.set_field(k.clone(), v.clone(), uuid) {
+        if d.set_field(k.clone(), v.clone(), t) {
             cnt += 1;
         }
     }
 
-    if uuid < o.delete_time {
+    if t < o.delete_time {
         for (k, _) in kvs.iter() {
             d.del_field(k, o.delete_time);
             cnt = 0;
         }
     }
-    o.updated_at(uuid);
+    o.updated_at(t);
     Ok(Message::Integer(cnt as i64))
 }
 
-pub fn hdel_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn hdel_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     let fields = {
@@ -56,17 +52,12 @@ pub fn hdel_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
         fields
     };
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::from(Dict::empty()), uuid, 0));
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Dict::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Dict::empty()), client.is_none())?;
+    // the fields are deleted with the whole dict if it's deleted later
+    let dt = max(t, o.delete_time);
     let s = o.enc.as_mut_dict()?;
-    let cnt = s.del_fields(fields.as_slice(), uuid);
-    o.updated_at(uuid);
+    let cnt = s.del_fields(fields.as_slice(), dt);
+    o.updated_at(t);
     Ok(Message::Integer(cnt as i64))
 }
 
@@ -99,22 +90,15 @@ pub fn hgetall_command(server: &mut Server, _client: Option<&mut Client>, _nodei
 }
 
 // deldict command can only be sent by our replicas
-pub fn deldict_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn deldict_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::from(Dict::empty()), uuid, 0).into());
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Dict::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Dict::empty()), client.is_none())?;
     let d = o.enc.as_mut_dict()?;
-    let members: Vec<Bytes> = d.iter_all().map(|(x, _, _)| x.clone()).collect();
-    let _ = d.del_fields(members.as_slice(), uuid);
-    o.delete_time = max(o.delete_time, uuid);
-    o.update_time = max(o.update_time, uuid);
+    d.remove_before(t);
+    o.delete_time = max(o.delete_time, t);
+    o.update_time = max(o.update_time, t);
     Ok(Message::None)
 }
\ No newline at end of file
@@ -6,11 +6,13 @@ use crate::{Bytes, CstError};
 use crate::cmd::NextArg;
 use crate::link::Client;
 use crate::crdt::lwwhash::Set;
-use crate::object::{Encoding, Object};
+use crate::crdt::timestamp::Timestamp;
+use crate::object::Encoding;
 use crate::resp::Message;
 use crate::server::Server;
 
-pub fn sadd_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn sadd_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     let members = {
@@ -21,27 +23,21 @@ pub fn sadd_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
         members
     };
 
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Set::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Set::empty()), client.is_none())?;
     let s = o.enc.as_mut_set()?;
-    let mut cnt = s.add_members(members.as_slice(), uuid);
+    let mut cnt = s.add_members(members.as_slice(), t);
 
     // current replica add these members, and another replica delete the whole set later.
-    if uuid < o.delete_time {
+    if t < o.delete_time {
         s.remove_members(members.as_slice(), o.delete_time);
         cnt = 0;
     }
-    o.updated_at(uuid);
+    o.updated_at(t);
     Ok(Message::Integer(cnt as i64))
 }
 
-pub fn srem_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn srem_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     let members = {
@@ -52,17 +48,12 @@ pub fn srem_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
         members
     };
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::from(Set::empty()), uuid, 0));
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Set::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Set::empty()), client.is_none())?;
+    // the members are removed with the whole set if it's deleted later
+    let dt = max(t, o.delete_time);
     let s = o.enc.as_mut_set()?;
-    let cnt = s.remove_members(&members, uuid);
-    o.updated_at(uuid);
+    let cnt = s.remove_members(&members, dt);
+    o.updated_at(t);
     Ok(Message::Integer(cnt as i64))
 }
 
@@ -81,18 +72,12 @@ pub fn smembers_command(server: &mut Server, _client: Option<&mut Client>, _node
 }
 
 // TODO
-pub fn spop_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn spop_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
 
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Set::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Set::empty()), client.is_none())?;
     let s = o.enc.as_mut_set()?;
     let mut c = thread_rng_n(s.size());
     let mut m: Option<Bytes> = None;
@@ -105,8 +90,8 @@ pub fn spop_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
     }
     match m {
         Some(member) => {
-            s.remove_member(&member, uuid);
-            o.updated_at(uuid);
+            s.remove_member(&member, t);
+            o.updated_at(t);
             Ok(Message::BulkString(member))
         }
         None => Ok(Message::Nil)
@@ -114,22 +99,15 @@ pub fn spop_command(server: &mut Server, _client: Option<&mut Client>, _nodeid:
 }
 
 // delset command can only be sent by our replicas
-pub fn delset_command(server: &mut Server, _client: Option<&mut Client>, _nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+pub fn delset_command(server: &mut Server, client: Option<&mut Client>, nodeid: u64, uuid: u64, args: Vec<Message>) -> Result<Message, CstError> {
+    let t = Timestamp::new(uuid, nodeid);
     let mut args = args.into_iter();
     let key_name = args.next_bytes()?;
     //let o = server.db.entry(key_name).or_insert(Object::new(Encoding::from(Set::empty()), uuid, 0).into());
-    let o = match server.db.query(&key_name, uuid) {
-        None => {
-            let o = Object::new(Encoding::from(Set::empty()), uuid, 0).into();
-            server.db.add(key_name.clone(), o);
-            server.db.query(&key_name, uuid).unwrap()
-        }
-        Some(o) => o,
-    };
+    let o = server.db.query_or_create(&key_name, t, Encoding::from(Set::empty()), client.is_none())?;
     let s = o.enc.as_mut_set()?;
-    let members: Vec<Bytes> = s.iter_all().map(|(x, _)| x.clone()).collect();
-    let _ = s.remove_members(members.as_slice(), uuid);
-    o.delete_time = max(o.delete_time, uuid);
-    o.update_time = max(o.update_time, uuid);
+    s.remove_before(t);
+    o.delete_time = max(o.delete_time, t);
+    o.update_time = max(o.update_time, t);
     Ok(Message::None)
 }
\ No newline at end of file